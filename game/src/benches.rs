@@ -0,0 +1,104 @@
+//! Benchmarks of chunk access patterns. Compare the voxel layouts with
+//! `cargo bench` and `cargo bench --features morton`.
+
+use cgmath::Vector3;
+use test::{black_box, Bencher};
+
+use material::MaterialId;
+use mesher;
+use voxel_grid::{Chunk, QuantizedFloat, Voxel, VoxelGrid, WorldPos};
+
+const DIM: u16 = 32;
+
+/// Rolling hills, so chunks hold a mix of air, surface and solid voxels.
+fn terrain() -> VoxelGrid {
+    let mut vg = VoxelGrid::with_dimension(DIM);
+    vg.fill_region(
+        &Vector3::new(-1, -1, -1),
+        &Vector3::new(1, 0, 1),
+        &|pos: &WorldPos| {
+            let (x, z) = (pos.x as f32, pos.z as f32);
+            let h = 6.0 * (x * 0.11).sin() + 4.0 * (z * 0.17).cos() - 4.0;
+            let o = (h - pos.y as f32 + 0.5).max(0.0).min(1.0);
+            if o > 0.0 {
+                Voxel::new_with_args(MaterialId::SNOW, QuantizedFloat::from_f32(o))
+            } else {
+                Voxel::new()
+            }
+        },
+    );
+    vg
+}
+
+#[bench]
+fn bench_mesh_chunk(b: &mut Bencher) {
+    let vg = terrain();
+    let idx = Vector3::new(0, -1, 0);
+    let chunk = vg.get_chunk(&idx).unwrap();
+    let neighbors = vg.neighbors(&idx);
+    b.iter(|| mesher::mesh_chunk(chunk, &neighbors, 1.0));
+}
+
+#[bench]
+fn bench_scan_chunk(b: &mut Bencher) {
+    let vg = terrain();
+    let chunk = vg.get_chunk(&Vector3::new(0, -1, 0)).unwrap();
+    b.iter(|| {
+        let mut sum = 0.0;
+        for z in 0..DIM {
+            for y in 0..DIM {
+                for x in 0..DIM {
+                    sum += chunk
+                        .get_voxel_at(Vector3::new(x, y, z))
+                        .get_occupancy_as_f32();
+                }
+            }
+        }
+        black_box(sum)
+    });
+}
+
+/// Average of the six face neighbours of every inner voxel, the access
+/// pattern of diffusion and smoothing passes.
+#[bench]
+fn bench_face_neighbours(b: &mut Bencher) {
+    let vg = terrain();
+    let chunk: &Chunk = vg.get_chunk(&Vector3::new(0, -1, 0)).unwrap();
+    let at = |x: u16, y: u16, z: u16| {
+        chunk
+            .get_voxel_at(Vector3::new(x, y, z))
+            .get_occupancy_as_f32()
+    };
+    b.iter(|| {
+        let mut sum = 0.0;
+        for z in 1..DIM - 1 {
+            for y in 1..DIM - 1 {
+                for x in 1..DIM - 1 {
+                    sum += (at(x + 1, y, z)
+                        + at(x - 1, y, z)
+                        + at(x, y + 1, z)
+                        + at(x, y - 1, z)
+                        + at(x, y, z + 1)
+                        + at(x, y, z - 1))
+                        / 6.0;
+                }
+            }
+        }
+        black_box(sum)
+    });
+}
+
+#[bench]
+fn bench_sample_gradient(b: &mut Bencher) {
+    let vg = terrain();
+    b.iter(|| {
+        let mut sum = Vector3::new(0.0, 0.0, 0.0);
+        for z in 0..DIM {
+            for x in 0..DIM {
+                let p = Vector3::new(x as f32 + 0.3, -4.2, z as f32 + 0.7);
+                sum += vg.gradient(p);
+            }
+        }
+        black_box(sum)
+    });
+}
@@ -0,0 +1,459 @@
+use std::collections::HashSet;
+
+use cgmath::{InnerSpace, Vector2, Vector3};
+
+use material::{MaterialId, MaterialRegistry};
+use voxel_grid::{ChunkIndex, QuantizedFloat, Voxel, VoxelGrid, WorldPos, FACE_DIRECTIONS};
+
+/// Volume affected by a brush, in voxel units.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BrushShape {
+    Sphere {
+        center: Vector3<f32>,
+        radius: f32,
+    },
+    Box {
+        center: Vector3<f32>,
+        half_extents: Vector3<f32>,
+    },
+    /// Upright cylinder standing on `base`.
+    Cylinder {
+        base: Vector3<f32>,
+        radius: f32,
+        height: f32,
+    },
+    Capsule {
+        start: Vector3<f32>,
+        end: Vector3<f32>,
+        radius: f32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BrushOp {
+    /// Fill the brush volume with a material.
+    Add(MaterialId),
+    /// Carve the brush volume out.
+    Subtract,
+    /// Blend occupancy towards the average of the six neighbouring voxels.
+    Smooth,
+    /// Change the material of whatever is inside the brush, keeping its
+    /// occupancy.
+    Paint(MaterialId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Brush {
+    pub shape: BrushShape,
+    pub op: BrushOp,
+    /// Width in voxels over which the brush fades out at its surface.
+    pub falloff: f32,
+    /// Scales the effect of the brush, from 0 to 1.
+    pub strength: f32,
+}
+
+impl Brush {
+    pub fn new(shape: BrushShape, op: BrushOp) -> Self {
+        Brush {
+            shape,
+            op,
+            falloff: 1.0,
+            strength: 1.0,
+        }
+    }
+
+    pub fn with_falloff(mut self, falloff: f32) -> Self {
+        self.falloff = falloff;
+        self
+    }
+
+    pub fn with_strength(mut self, strength: f32) -> Self {
+        self.strength = strength;
+        self
+    }
+
+    /// Signed distance from `p` to the surface of the brush shape, negative
+    /// inside.
+    pub fn distance(&self, p: Vector3<f32>) -> f32 {
+        match self.shape {
+            BrushShape::Sphere { center, radius } => (p - center).magnitude() - radius,
+            BrushShape::Box {
+                center,
+                half_extents,
+            } => {
+                let d = p - center;
+                let q = Vector3::new(d.x.abs(), d.y.abs(), d.z.abs()) - half_extents;
+                let outside = Vector3::new(q.x.max(0.0), q.y.max(0.0), q.z.max(0.0));
+                outside.magnitude() + q.x.max(q.y).max(q.z).min(0.0)
+            }
+            BrushShape::Cylinder {
+                base,
+                radius,
+                height,
+            } => {
+                let d = p - base;
+                let q = Vector2::new(
+                    Vector2::new(d.x, d.z).magnitude() - radius,
+                    (d.y - height * 0.5).abs() - height * 0.5,
+                );
+                let outside = Vector2::new(q.x.max(0.0), q.y.max(0.0));
+                outside.magnitude() + q.x.max(q.y).min(0.0)
+            }
+            BrushShape::Capsule { start, end, radius } => {
+                let axis = end - start;
+                let t = if axis.magnitude2() > 0.0 {
+                    ((p - start).dot(axis) / axis.magnitude2())
+                        .max(0.0)
+                        .min(1.0)
+                } else {
+                    0.0
+                };
+                (p - (start + axis * t)).magnitude() - radius
+            }
+        }
+    }
+
+    /// How strongly the brush affects a voxel centred at `p`, from 0 to 1.
+    pub fn weight(&self, p: Vector3<f32>) -> f32 {
+        let d = self.distance(p);
+        let coverage = if self.falloff > 0.0 {
+            (0.5 - d / self.falloff).max(0.0).min(1.0)
+        } else if d <= 0.0 {
+            1.0
+        } else {
+            0.0
+        };
+        coverage * self.strength
+    }
+
+    /// Inclusive range of voxels the brush can affect.
+    pub fn bounds(&self) -> (WorldPos, WorldPos) {
+        let (min, max) = match self.shape {
+            BrushShape::Sphere { center, radius } => {
+                let r = Vector3::new(radius, radius, radius);
+                (center - r, center + r)
+            }
+            BrushShape::Box {
+                center,
+                half_extents,
+            } => (center - half_extents, center + half_extents),
+            BrushShape::Cylinder {
+                base,
+                radius,
+                height,
+            } => (
+                base - Vector3::new(radius, 0.0, radius),
+                base + Vector3::new(radius, height, radius),
+            ),
+            BrushShape::Capsule { start, end, radius } => (
+                Vector3::new(start.x.min(end.x), start.y.min(end.y), start.z.min(end.z))
+                    - Vector3::new(radius, radius, radius),
+                Vector3::new(start.x.max(end.x), start.y.max(end.y), start.z.max(end.z))
+                    + Vector3::new(radius, radius, radius),
+            ),
+        };
+        let pad = self.falloff.max(0.0) * 0.5 + 0.5;
+        (
+            Vector3::new(
+                (min.x - pad).floor() as i32,
+                (min.y - pad).floor() as i32,
+                (min.z - pad).floor() as i32,
+            ),
+            Vector3::new(
+                (max.x + pad).ceil() as i32,
+                (max.y + pad).ceil() as i32,
+                (max.z + pad).ceil() as i32,
+            ),
+        )
+    }
+}
+
+impl VoxelGrid {
+    /// Apply a brush to the grid, leaving indestructible materials
+    /// untouched. Returns the chunks that were changed.
+    pub fn apply_brush(
+        &mut self,
+        brush: &Brush,
+        materials: &MaterialRegistry,
+    ) -> HashSet<ChunkIndex> {
+        let mut edits = self.brush_edits(brush);
+        edits.retain(|&(_, old, _)| !materials.is_indestructible(old.get_material()));
+        self.write_edits(edits)
+    }
+
+    /// Effort of terraforming with a brush: the occupancy it removes and
+    /// adds weighted by the hardness of the materials exposed to it. None
+    /// if terraforming with it would change nothing.
+    pub fn terraform_cost(&self, brush: &Brush, materials: &MaterialRegistry) -> Option<f32> {
+        let hardness = |m| materials.get(m).map_or(1.0, |d| d.hardness);
+        let mut cost = None;
+        for (_, old, new) in self.brush_edits(brush) {
+            if materials.is_indestructible(old.get_material()) {
+                continue;
+            }
+            let (from, to) = (occupancy(&old), occupancy(&new));
+            let c = if old.get_material() == new.get_material() {
+                (from - to).abs() * hardness(old.get_material())
+            } else {
+                from * hardness(old.get_material()) + to * hardness(new.get_material())
+            };
+            cost = Some(cost.unwrap_or(0.0) + c);
+        }
+        cost
+    }
+
+    /// Voxels changed by a brush, as position, old and new voxel.
+    fn brush_edits(&self, brush: &Brush) -> Vec<(WorldPos, Voxel, Voxel)> {
+        let (min, max) = brush.bounds();
+        let mut edits = Vec::new();
+
+        for z in min.z..max.z + 1 {
+            for y in min.y..max.y + 1 {
+                for x in min.x..max.x + 1 {
+                    let pos = Vector3::new(x, y, z);
+                    let centre = Vector3::new(x as f32 + 0.5, y as f32 + 0.5, z as f32 + 0.5);
+                    let w = brush.weight(centre);
+                    if w <= 0.0 {
+                        continue;
+                    }
+
+                    let old = self.voxel_at(&pos);
+                    let new = self.brushed_voxel(&brush.op, &pos, old, w);
+                    if new != old {
+                        edits.push((pos, old, new));
+                    }
+                }
+            }
+        }
+        edits
+    }
+
+    /// Written after all reads so smoothing only sees the original grid.
+    fn write_edits(&mut self, edits: Vec<(WorldPos, Voxel, Voxel)>) -> HashSet<ChunkIndex> {
+        let mut touched = HashSet::new();
+        self.begin_transaction();
+        for (pos, _, v) in edits {
+            touched.insert(self.locate(&pos).0);
+            self.set_voxel(&pos, v.get_material(), v.get_occupancy());
+        }
+        self.commit_transaction();
+        touched
+    }
+
+    fn brushed_voxel(&self, op: &BrushOp, pos: &WorldPos, old: Voxel, w: f32) -> Voxel {
+        let occ = occupancy(&old);
+        match *op {
+            BrushOp::Add(m) => {
+                if w > occ {
+                    Voxel::new_with_args(m, QuantizedFloat::from_f32(w))
+                } else {
+                    old
+                }
+            }
+            BrushOp::Subtract => {
+                let remaining = occ.min(1.0 - w);
+                if remaining <= 0.0 {
+                    Voxel::new()
+                } else if remaining < occ {
+                    Voxel::new_with_args(old.get_material(), QuantizedFloat::from_f32(remaining))
+                } else {
+                    old
+                }
+            }
+            BrushOp::Smooth => {
+                let sum = FACE_DIRECTIONS
+                    .iter()
+                    .map(|d| occupancy(&self.voxel_at(&(pos + Vector3::from(*d)))))
+                    .sum::<f32>();
+                let smoothed = occ + (sum / 6.0 - occ) * w;
+                if smoothed <= 0.0 {
+                    Voxel::new()
+                } else if old.get_material() == MaterialId::AIR {
+                    // Material grows in from the most solid neighbour
+                    Voxel::new_with_args(
+                        self.dominant_neighbour(pos),
+                        QuantizedFloat::from_f32(smoothed),
+                    )
+                } else {
+                    Voxel::new_with_args(old.get_material(), QuantizedFloat::from_f32(smoothed))
+                }
+            }
+            BrushOp::Paint(m) => {
+                if old.get_material() != MaterialId::AIR && w >= 0.5 {
+                    Voxel::new_with_args(m, old.get_occupancy())
+                } else {
+                    old
+                }
+            }
+        }
+    }
+
+    fn dominant_neighbour(&self, pos: &WorldPos) -> MaterialId {
+        let mut best = (MaterialId::AIR, 0.0);
+        for d in &FACE_DIRECTIONS {
+            let v = self.voxel_at(&(pos + Vector3::from(*d)));
+            if v.get_material() != MaterialId::AIR && occupancy(&v) > best.1 {
+                best = (v.get_material(), occupancy(&v));
+            }
+        }
+        best.0
+    }
+}
+
+/// Occupancy of a voxel, with air always empty.
+#[inline]
+fn occupancy(v: &Voxel) -> f32 {
+    if v.get_material() == MaterialId::AIR {
+        0.0
+    } else {
+        v.get_occupancy_as_f32()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sphere(op: BrushOp) -> Brush {
+        Brush::new(
+            BrushShape::Sphere {
+                center: Vector3::new(0.0, 0.0, 0.0),
+                radius: 3.0,
+            },
+            op,
+        )
+    }
+
+    #[test]
+    fn brush_add_subtract() {
+        let mut vg = VoxelGrid::with_dimension(8);
+        let materials = MaterialRegistry::builtin();
+        let touched = vg.apply_brush(&sphere(BrushOp::Add(MaterialId::SNOW)), &materials);
+        assert_eq!(touched.len(), 8);
+        assert!(touched.contains(&Vector3::new(-1, -1, -1)));
+
+        let centre = vg.voxel_at(&Vector3::new(0, 0, 0));
+        assert_eq!(centre.get_material(), MaterialId::SNOW);
+        assert_eq!(centre.get_occupancy(), QuantizedFloat::FULL);
+        let edge = vg.voxel_at(&Vector3::new(2, 0, 0)).get_occupancy_as_f32();
+        assert!(edge > 0.0 && edge < 1.0);
+        assert_eq!(
+            vg.voxel_at(&Vector3::new(4, 0, 0)).get_material(),
+            MaterialId::AIR
+        );
+
+        vg.apply_brush(&sphere(BrushOp::Subtract).with_falloff(0.0), &materials);
+        assert_eq!(
+            vg.voxel_at(&Vector3::new(0, 0, 0)).get_material(),
+            MaterialId::AIR
+        );
+    }
+
+    #[test]
+    fn brush_paint_keeps_occupancy() {
+        let mut vg = VoxelGrid::with_dimension(8);
+        let materials = MaterialRegistry::builtin();
+        vg.apply_brush(&sphere(BrushOp::Add(MaterialId::ROCK)), &materials);
+        let before = vg.voxel_at(&Vector3::new(2, 0, 0));
+        vg.apply_brush(
+            &sphere(BrushOp::Paint(MaterialId::GRASS)).with_falloff(0.0),
+            &materials,
+        );
+        let after = vg.voxel_at(&Vector3::new(2, 0, 0));
+        assert_eq!(after.get_material(), MaterialId::GRASS);
+        assert_eq!(after.get_occupancy(), before.get_occupancy());
+        assert!(vg
+            .apply_brush(
+                &Brush::new(
+                    BrushShape::Box {
+                        center: Vector3::new(20.0, 0.0, 0.0),
+                        half_extents: Vector3::new(1.0, 1.0, 1.0),
+                    },
+                    BrushOp::Paint(MaterialId::SNOW),
+                ),
+                &materials,
+            )
+            .is_empty());
+    }
+
+    #[test]
+    fn brush_smooth_flattens_spike() {
+        let mut vg = VoxelGrid::with_dimension(8);
+        let materials = MaterialRegistry::builtin();
+        vg.set_voxel(
+            &Vector3::new(4, 4, 4),
+            MaterialId::ROCK,
+            QuantizedFloat::FULL,
+        );
+        vg.apply_brush(
+            &Brush::new(
+                BrushShape::Capsule {
+                    start: Vector3::new(4.5, 3.0, 4.5),
+                    end: Vector3::new(4.5, 6.0, 4.5),
+                    radius: 2.0,
+                },
+                BrushOp::Smooth,
+            ),
+            &materials,
+        );
+        let spike = vg.voxel_at(&Vector3::new(4, 4, 4)).get_occupancy().value;
+        assert!(spike < QuantizedFloat::FULL.value);
+        let side = vg.voxel_at(&Vector3::new(5, 4, 4));
+        assert_eq!(side.get_material(), MaterialId::ROCK);
+    }
+
+    #[test]
+    fn brush_undo_is_one_step() {
+        let mut vg = VoxelGrid::with_dimension(8);
+        let materials = MaterialRegistry::builtin();
+        vg.apply_brush(&sphere(BrushOp::Add(MaterialId::SNOW)), &materials);
+        assert!(vg.undo());
+        assert!(!vg.journal().can_undo());
+        assert_eq!(
+            vg.voxel_at(&Vector3::new(0, 0, 0)).get_material(),
+            MaterialId::AIR
+        );
+    }
+
+    #[test]
+    fn brush_spares_bedrock() {
+        let mut vg = VoxelGrid::with_dimension(8);
+        let materials = MaterialRegistry::builtin();
+        let dig = sphere(BrushOp::Subtract).with_falloff(0.0);
+        vg.apply_brush(&sphere(BrushOp::Add(MaterialId::GRASS)), &materials);
+        let soil = vg.terraform_cost(&dig, &materials).unwrap();
+        vg.apply_brush(
+            &sphere(BrushOp::Paint(MaterialId::ROCK)).with_falloff(0.0),
+            &materials,
+        );
+        let rock = vg.terraform_cost(&dig, &materials).unwrap();
+        assert!(rock > soil);
+
+        vg.apply_brush(
+            &sphere(BrushOp::Paint(MaterialId::BEDROCK)).with_falloff(0.0),
+            &materials,
+        );
+        assert_eq!(vg.terraform_cost(&dig, &materials), None);
+        assert!(vg.apply_brush(&dig, &materials).is_empty());
+        assert_eq!(
+            vg.voxel_at(&Vector3::new(0, 0, 0)).get_material(),
+            MaterialId::BEDROCK
+        );
+    }
+
+    #[test]
+    fn brush_cylinder_bounds() {
+        let b = Brush::new(
+            BrushShape::Cylinder {
+                base: Vector3::new(0.0, 0.0, 0.0),
+                radius: 2.0,
+                height: 4.0,
+            },
+            BrushOp::Subtract,
+        );
+        assert!(b.weight(Vector3::new(0.5, 2.0, 0.5)) == 1.0);
+        assert!(b.weight(Vector3::new(0.5, 5.0, 0.5)) == 0.0);
+        let (min, max) = b.bounds();
+        assert!(min.y < 0 && max.y > 4);
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::{hash_map, hash_set, HashMap, HashSet};
+use std::iter::Chain;
+
+use cgmath::Vector3;
+
+use voxel_grid::{ChunkIndex, VoxelIndex};
+
+/// Box of voxels inside one chunk, both corners inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyRegion {
+    pub min: VoxelIndex,
+    pub max: VoxelIndex,
+}
+
+impl DirtyRegion {
+    #[inline]
+    pub fn voxel(idx: VoxelIndex) -> Self {
+        DirtyRegion { min: idx, max: idx }
+    }
+
+    /// Every voxel of a chunk with `dim` voxels per side.
+    #[inline]
+    pub fn whole(dim: u16) -> Self {
+        let last = dim.saturating_sub(1);
+        DirtyRegion {
+            min: Vector3::new(0, 0, 0),
+            max: Vector3::new(last, last, last),
+        }
+    }
+
+    /// Grow the region to also cover `other`.
+    #[inline]
+    pub fn merge(&mut self, other: &DirtyRegion) {
+        self.min = Vector3::new(
+            self.min.x.min(other.min.x),
+            self.min.y.min(other.min.y),
+            self.min.z.min(other.min.z),
+        );
+        self.max = Vector3::new(
+            self.max.x.max(other.max.x),
+            self.max.y.max(other.max.y),
+            self.max.z.max(other.max.z),
+        );
+    }
+
+    #[inline]
+    pub fn contains(&self, idx: VoxelIndex) -> bool {
+        idx.x >= self.min.x
+            && idx.y >= self.min.y
+            && idx.z >= self.min.z
+            && idx.x <= self.max.x
+            && idx.y <= self.max.y
+            && idx.z <= self.max.z
+    }
+
+    /// True if the region reaches the faces of a chunk with `dim` voxels per
+    /// side, in `VoxelGrid::neighbors` order. Neighbouring chunks sample
+    /// those voxels, e.g. when meshing.
+    pub fn touches_faces(&self, dim: u16) -> [bool; 6] {
+        let last = dim.saturating_sub(1);
+        [
+            self.max.x == last,
+            self.min.x == 0,
+            self.max.y == last,
+            self.min.y == 0,
+            self.max.z == last,
+            self.min.z == 0,
+        ]
+    }
+}
+
+/// What changed the voxels of a chunk. When a chunk changes in several ways
+/// the greatest source is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChangeSource {
+    /// Undoing or redoing edits.
+    Replay,
+    /// Inserting whole chunks, such as loaded or generated ones.
+    Load,
+    /// Editing voxels, including the chunks created by edits.
+    Edit,
+}
+
+impl Default for ChangeSource {
+    fn default() -> Self {
+        ChangeSource::Edit
+    }
+}
+
+/// Published when chunks of a `VoxelGrid` change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoxelChangeEvent {
+    /// Voxels inside `region` of `chunk` changed, or the chunk was created.
+    Modified {
+        chunk: ChunkIndex,
+        region: DirtyRegion,
+        source: ChangeSource,
+    },
+    Removed(ChunkIndex),
+}
+
+pub type DirtyChunks<'a> =
+    Chain<hash_map::Keys<'a, ChunkIndex, DirtyRegion>, hash_set::Iter<'a, ChunkIndex>>;
+
+/// Records which parts of a grid changed since the changes were last taken.
+#[derive(Debug, Clone, Default)]
+pub struct ChangeTracker {
+    dirty: HashMap<ChunkIndex, DirtyRegion>,
+    removed: HashSet<ChunkIndex>,
+    sources: HashMap<ChunkIndex, ChangeSource>,
+    /// Source of the changes marked from now on.
+    source: ChangeSource,
+}
+
+impl ChangeTracker {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn mark(&mut self, chunk: &ChunkIndex, region: DirtyRegion) {
+        let source = self.source;
+        self.mark_from(chunk, region, source);
+    }
+
+    pub fn mark_from(&mut self, chunk: &ChunkIndex, region: DirtyRegion, source: ChangeSource) {
+        self.removed.remove(chunk);
+        let s = self.sources.entry(*chunk).or_insert(source);
+        *s = (*s).max(source);
+        if let Some(r) = self.dirty.get_mut(chunk) {
+            r.merge(&region);
+            return;
+        }
+        self.dirty.insert(*chunk, region);
+    }
+
+    pub fn mark_removed(&mut self, chunk: &ChunkIndex) {
+        self.dirty.remove(chunk);
+        self.sources.remove(chunk);
+        self.removed.insert(*chunk);
+    }
+
+    /// Changes marked with `mark` come from `source` until it is set again.
+    pub fn set_source(&mut self, source: ChangeSource) {
+        self.source = source;
+    }
+
+    #[inline]
+    pub fn is_dirty(&self, chunk: &ChunkIndex) -> bool {
+        self.dirty.contains_key(chunk) || self.removed.contains(chunk)
+    }
+
+    #[inline]
+    pub fn dirty_region(&self, chunk: &ChunkIndex) -> Option<&DirtyRegion> {
+        self.dirty.get(chunk)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.dirty.is_empty() && self.removed.is_empty()
+    }
+
+    /// Chunks that were modified or removed.
+    pub fn dirty_chunks<'a>(&'a self) -> DirtyChunks<'a> {
+        self.dirty.keys().chain(self.removed.iter())
+    }
+
+    /// Turn everything recorded so far into events and start over.
+    pub fn take(&mut self) -> Vec<VoxelChangeEvent> {
+        let sources = &mut self.sources;
+        let mut events = self
+            .dirty
+            .drain()
+            .map(|(chunk, region)| VoxelChangeEvent::Modified {
+                chunk,
+                region,
+                source: sources.remove(&chunk).unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+        events.extend(self.removed.drain().map(VoxelChangeEvent::Removed));
+        events
+    }
+
+    pub fn clear(&mut self) {
+        self.dirty.clear();
+        self.removed.clear();
+        self.sources.clear();
+    }
+}
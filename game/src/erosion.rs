@@ -0,0 +1,477 @@
+use cgmath::Vector3;
+
+use material::{MaterialId, MaterialRegistry};
+use voxel_grid::{QuantizedFloat, VoxelGrid, WorldPos};
+
+/// Height changes smaller than this leave a column untouched.
+const EPSILON: f32 = 1e-3;
+
+/// Surface height of every column in a box of the grid. Column `(x, z)` is
+/// at world position `min + (x, _, z)`, heights are world y coordinates of
+/// the surface.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeightField {
+    pub min: WorldPos,
+    pub max: WorldPos,
+    pub width: usize,
+    pub depth: usize,
+    pub heights: Vec<f32>,
+    /// Height below which erosion does not wear a column down, e.g. the top
+    /// of its bedrock.
+    pub floors: Vec<f32>,
+}
+
+impl HeightField {
+    #[inline]
+    pub fn get(&self, x: usize, z: usize) -> f32 {
+        self.heights[x + self.width * z]
+    }
+
+    /// Height and gradient at a point between columns, bilinearly
+    /// interpolated. The point must be at least one column inside the far
+    /// edges.
+    fn slope(&self, x: f32, z: f32) -> (f32, f32, f32) {
+        let (ix, iz) = (x as usize, z as usize);
+        let (u, v) = (x - ix as f32, z - iz as f32);
+        let h00 = self.get(ix, iz);
+        let h10 = self.get(ix + 1, iz);
+        let h01 = self.get(ix, iz + 1);
+        let h11 = self.get(ix + 1, iz + 1);
+
+        let gx = (h10 - h00) * (1.0 - v) + (h11 - h01) * v;
+        let gz = (h01 - h00) * (1.0 - u) + (h11 - h10) * u;
+        let h =
+            h00 * (1.0 - u) * (1.0 - v) + h10 * u * (1.0 - v) + h01 * (1.0 - u) * v + h11 * u * v;
+        (h, gx, gz)
+    }
+
+    /// Simulate water droplets running down the surface. Droplets pick up
+    /// sediment where they speed up, carving gullies, and drop it where they
+    /// slow down or pool, filling valleys. The same parameters and seed
+    /// always give the same result.
+    pub fn erode(&mut self, params: &ErosionParams) {
+        if self.width < 3 || self.depth < 3 {
+            return;
+        }
+        let brush = brush(params.radius);
+        let mut rng = Rng::new(params.seed);
+        let (w, d) = (self.width as f32 - 1.0, self.depth as f32 - 1.0);
+
+        for _ in 0..params.droplets {
+            let (mut x, mut z) = (rng.next_f32() * w, rng.next_f32() * d);
+            let (mut dx, mut dz) = (0.0f32, 0.0f32);
+            let (mut speed, mut water, mut sediment) = (1.0f32, 1.0f32, 0.0f32);
+
+            let mut inside = true;
+            for _ in 0..params.lifetime {
+                let (h, gx, gz) = self.slope(x, z);
+
+                // Turn downhill, keeping some of the old direction
+                dx = dx * params.inertia - gx * (1.0 - params.inertia);
+                dz = dz * params.inertia - gz * (1.0 - params.inertia);
+                let len = (dx * dx + dz * dz).sqrt();
+                if len < EPSILON {
+                    break;
+                }
+                dx /= len;
+                dz /= len;
+
+                let (old_x, old_z) = (x, z);
+                x += dx;
+                z += dz;
+                if x < 0.0 || z < 0.0 || x >= w || z >= d {
+                    inside = false;
+                    break;
+                }
+
+                let dh = self.slope(x, z).0 - h;
+                let capacity = (-dh).max(params.min_slope) * speed * water * params.capacity;
+                if dh > 0.0 || sediment > capacity {
+                    // Fill the pit behind the droplet, or drop what it can
+                    // no longer carry
+                    let amount = if dh > 0.0 {
+                        dh.min(sediment)
+                    } else {
+                        (sediment - capacity) * params.deposition
+                    };
+                    sediment -= amount;
+                    self.deposit(old_x, old_z, amount);
+                } else {
+                    let amount = ((capacity - sediment) * params.erosion).min(-dh);
+                    sediment += self.wear(old_x, old_z, amount, &brush);
+                }
+
+                speed = (speed * speed - dh * params.gravity).max(0.0).sqrt();
+                water *= 1.0 - params.evaporation;
+            }
+            // Droplets leaving the field take their sediment with them,
+            // others drop it where they dry up
+            if inside {
+                self.deposit(x, z, sediment);
+            }
+        }
+    }
+
+    /// Spread sediment over the four columns around a point.
+    fn deposit(&mut self, x: f32, z: f32, amount: f32) {
+        let (ix, iz) = (x as usize, z as usize);
+        let (u, v) = (x - ix as f32, z - iz as f32);
+        let w = self.width;
+        self.heights[ix + w * iz] += amount * (1.0 - u) * (1.0 - v);
+        self.heights[ix + 1 + w * iz] += amount * u * (1.0 - v);
+        self.heights[ix + w * (iz + 1)] += amount * (1.0 - u) * v;
+        self.heights[ix + 1 + w * (iz + 1)] += amount * u * v;
+    }
+
+    /// Lower the columns around a point by `amount` in total, returns how
+    /// much was removed. Columns outside the field are skipped.
+    fn wear(&mut self, x: f32, z: f32, amount: f32, brush: &[(i32, i32, f32)]) -> f32 {
+        let (cx, cz) = (x.round() as i32, z.round() as i32);
+        let mut removed = 0.0;
+        for &(ox, oz, weight) in brush {
+            let (nx, nz) = (cx + ox, cz + oz);
+            if nx < 0 || nz < 0 || nx >= self.width as i32 || nz >= self.depth as i32 {
+                continue;
+            }
+            let i = nx as usize + self.width * nz as usize;
+            let h = self.heights[i];
+            let delta = (amount * weight).min(h - self.floors[i]).max(0.0);
+            self.heights[i] = h - delta;
+            removed += delta;
+        }
+        removed
+    }
+}
+
+/// Settings of the hydraulic erosion simulation, see `HeightField::erode`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ErosionParams {
+    pub seed: u32,
+    /// Number of droplets simulated.
+    pub droplets: u32,
+    /// Most steps a droplet takes before evaporating.
+    pub lifetime: u32,
+    /// Fraction of its direction a droplet keeps at every step.
+    pub inertia: f32,
+    /// Sediment a droplet can carry per unit of speed, water and slope.
+    pub capacity: f32,
+    /// Slope assumed on flat ground, so droplets still carry sediment there.
+    pub min_slope: f32,
+    /// Fraction of the excess sediment dropped per step.
+    pub deposition: f32,
+    /// Fraction of the free capacity picked up per step.
+    pub erosion: f32,
+    /// Fraction of the water lost per step.
+    pub evaporation: f32,
+    pub gravity: f32,
+    /// Columns around a droplet that it wears down, spreading gullies.
+    pub radius: u32,
+    /// Material of voxels filled with sediment.
+    pub sediment: MaterialId,
+}
+
+impl Default for ErosionParams {
+    fn default() -> Self {
+        ErosionParams {
+            seed: 0,
+            droplets: 50_000,
+            lifetime: 30,
+            inertia: 0.05,
+            capacity: 4.0,
+            min_slope: 0.01,
+            deposition: 0.3,
+            erosion: 0.3,
+            evaporation: 0.01,
+            gravity: 4.0,
+            radius: 3,
+            sediment: MaterialId::ROCK,
+        }
+    }
+}
+
+impl VoxelGrid {
+    /// Surface heights of the columns from `min` to `max` (inclusive). The
+    /// surface of a column is on top of its highest occupied voxel, raised
+    /// by that voxel's occupancy. Columns without occupied voxels are at
+    /// `min.y`, which is also the floor of every column.
+    pub fn height_field(&self, min: &WorldPos, max: &WorldPos) -> HeightField {
+        let width = (max.x - min.x + 1).max(0) as usize;
+        let depth = (max.z - min.z + 1).max(0) as usize;
+        let mut heights = Vec::with_capacity(width * depth);
+        for z in min.z..max.z + 1 {
+            for x in min.x..max.x + 1 {
+                let mut h = min.y as f32;
+                for y in (min.y..max.y + 1).rev() {
+                    let o = self.voxel_at(&Vector3::new(x, y, z)).get_occupancy_as_f32();
+                    if o > 0.0 {
+                        h = y as f32 + o;
+                        break;
+                    }
+                }
+                heights.push(h);
+            }
+        }
+        HeightField {
+            min: *min,
+            max: *max,
+            width,
+            depth,
+            floors: vec![min.y as f32; heights.len()],
+            heights,
+        }
+    }
+
+    /// Raise the floor of every column of `field` to the top of its highest
+    /// indestructible voxel.
+    fn protect_floors(&self, field: &mut HeightField, materials: &MaterialRegistry) {
+        for z in 0..field.depth {
+            for x in 0..field.width {
+                for y in (field.min.y..field.max.y + 1).rev() {
+                    let pos = Vector3::new(field.min.x + x as i32, y, field.min.z + z as i32);
+                    let v = self.voxel_at(&pos);
+                    if materials.is_indestructible(v.get_material()) {
+                        field.floors[x + field.width * z] = y as f32 + v.get_occupancy_as_f32();
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Move the surface of every column to its height in `field`, as one
+    /// step of the undo history. Only voxels between the old and the new
+    /// surface change, new solid voxels are `sediment`. Indestructible
+    /// voxels are left as they are.
+    pub fn apply_height_field(
+        &mut self,
+        field: &HeightField,
+        sediment: MaterialId,
+        materials: &MaterialRegistry,
+    ) {
+        self.apply_height_field_with(field, materials, |_, _| sediment);
+    }
+
+    /// Like `apply_height_field`, with the material of new solid voxels in
+    /// column `(x, z)` of the field given by `fill`.
+    pub fn apply_height_field_with<F>(
+        &mut self,
+        field: &HeightField,
+        materials: &MaterialRegistry,
+        fill: F,
+    ) where
+        F: Fn(usize, usize) -> MaterialId,
+    {
+        let old = self.height_field(&field.min, &field.max);
+        self.begin_transaction();
+        for z in 0..field.depth {
+            for x in 0..field.width {
+                let (from, to) = (old.get(x, z), field.get(x, z));
+                if (from - to).abs() < EPSILON {
+                    continue;
+                }
+                let low = (from.min(to).floor() as i32).max(field.min.y);
+                let high = (from.max(to).ceil() as i32).min(field.max.y + 1);
+                for y in low..high {
+                    let pos = field.min + Vector3::new(x as i32, 0, z as i32);
+                    let pos = Vector3::new(pos.x, y, pos.z);
+                    let v = self.voxel_at(&pos);
+                    if materials.is_indestructible(v.get_material()) {
+                        continue;
+                    }
+                    let o = (to - y as f32).max(0.0).min(1.0);
+                    let m = if v.get_material() == MaterialId::AIR
+                        || v.get_occupancy() == QuantizedFloat::EMPTY
+                    {
+                        fill(x, z)
+                    } else {
+                        v.get_material()
+                    };
+                    let o = QuantizedFloat::from_f32(o);
+                    if o != v.get_occupancy() {
+                        self.set_voxel(&pos, m, o);
+                    }
+                }
+            }
+        }
+        self.commit_transaction();
+    }
+
+    /// Run hydraulic erosion on the surface of the columns from `min` to
+    /// `max`, as one step of the undo history. Indestructible voxels are not
+    /// worn away.
+    pub fn erode(
+        &mut self,
+        min: &WorldPos,
+        max: &WorldPos,
+        params: &ErosionParams,
+        materials: &MaterialRegistry,
+    ) {
+        let mut field = self.height_field(min, max);
+        self.protect_floors(&mut field, materials);
+        field.erode(params);
+        self.apply_height_field(&field, params.sediment, materials);
+    }
+}
+
+/// Columns within `radius` of a droplet with their share of the erosion.
+fn brush(radius: u32) -> Vec<(i32, i32, f32)> {
+    let r = radius.max(1) as i32;
+    let mut brush = Vec::new();
+    for z in -r..r + 1 {
+        for x in -r..r + 1 {
+            let weight = r as f32 - ((x * x + z * z) as f32).sqrt();
+            if weight > 0.0 {
+                brush.push((x, z, weight));
+            }
+        }
+    }
+    let total = brush.iter().map(|b| b.2).sum::<f32>();
+    for b in &mut brush {
+        b.2 /= total;
+    }
+    brush
+}
+
+/// Small deterministic random number generator (SplitMix64).
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u32) -> Self {
+        Rng(u64::from(seed))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use voxel_grid::Voxel;
+
+    /// A valley along z, sloping down towards a flat basin at +z.
+    fn valley() -> VoxelGrid {
+        let mut vg = VoxelGrid::with_dimension(16);
+        vg.fill_region(
+            &Vector3::new(0, 0, 0),
+            &Vector3::new(1, 1, 1),
+            &|pos: &WorldPos| {
+                let (x, z) = (pos.x as f32 + 0.5, pos.z as f32 + 0.5);
+                let h = 4.0 + (x - 16.0).abs() * 0.5 + (20.0 - z).max(0.0) * 0.6;
+                let o = (h - pos.y as f32).max(0.0).min(1.0);
+                if o > 0.0 {
+                    Voxel::new_with_args(MaterialId::GRASS, QuantizedFloat::from_f32(o))
+                } else {
+                    Voxel::new()
+                }
+            },
+        );
+        vg.clear_changes();
+        vg
+    }
+
+    fn params(seed: u32) -> ErosionParams {
+        ErosionParams {
+            seed,
+            droplets: 2000,
+            ..ErosionParams::default()
+        }
+    }
+
+    #[test]
+    fn height_field_roundtrip() {
+        let mut vg = valley();
+        let (min, max) = (Vector3::new(0, 0, 0), Vector3::new(31, 31, 31));
+        let field = vg.height_field(&min, &max);
+        assert_eq!((field.width, field.depth), (32, 32));
+        assert!((field.get(16, 31) - 4.25).abs() < 0.01);
+        assert!((field.get(0, 0) - 23.45).abs() < 0.01);
+
+        // Writing back the same heights changes nothing
+        let materials = MaterialRegistry::builtin();
+        vg.apply_height_field(&field, MaterialId::ROCK, &materials);
+        assert!(vg.take_changes().is_empty());
+
+        let mut raised = field.clone();
+        raised.heights[16 + 32 * 31] = 6.5;
+        vg.apply_height_field(&raised, MaterialId::ROCK, &materials);
+        let at = |vg: &VoxelGrid, y| vg.voxel_at(&Vector3::new(16, y, 31));
+        assert_eq!(at(&vg, 4).get_material(), MaterialId::GRASS);
+        assert_eq!(at(&vg, 4).get_occupancy(), QuantizedFloat::FULL);
+        assert_eq!(at(&vg, 5).get_material(), MaterialId::ROCK);
+        assert_eq!(at(&vg, 6).get_occupancy(), QuantizedFloat::from_f32(0.5));
+        assert_eq!(at(&vg, 7).get_occupancy(), QuantizedFloat::EMPTY);
+    }
+
+    #[test]
+    fn erosion_carves_and_fills() {
+        let field = valley().height_field(&Vector3::new(0, 0, 0), &Vector3::new(31, 31, 31));
+        let mut a = field.clone();
+        a.erode(&params(1));
+        let mut b = field.clone();
+        b.erode(&params(1));
+        let mut c = field.clone();
+        c.erode(&params(2));
+        assert_eq!(a, b);
+        assert!(a != c);
+
+        // The slopes are worn down and the foot of the valley is filled in
+        let change = |x: usize, z: usize| a.get(x, z) - field.get(x, z);
+        let slopes = (4..16).map(|z| change(4, z) + change(28, z)).sum::<f32>();
+        let floor = (14..24).map(|z| change(16, z)).sum::<f32>();
+        assert!(slopes < 0.0);
+        assert!(floor > 0.0);
+        let before = field.heights.iter().sum::<f32>();
+        let after = a.heights.iter().sum::<f32>();
+        assert!(after <= before + 0.01);
+    }
+
+    #[test]
+    fn erosion_spares_bedrock() {
+        let mut vg = valley();
+        let (min, max) = (Vector3::new(0, 0, 0), Vector3::new(31, 31, 31));
+        // One side of the valley is bedrock
+        let mut bedrock = Vec::new();
+        for z in 0..32 {
+            for y in 0..32 {
+                for x in 0..16 {
+                    let pos = Vector3::new(x, y, z);
+                    let v = vg.voxel_at(&pos);
+                    if v.get_material() != MaterialId::AIR {
+                        vg.set_voxel(&pos, MaterialId::BEDROCK, v.get_occupancy());
+                        bedrock.push((pos, vg.voxel_at(&pos)));
+                    }
+                }
+            }
+        }
+        let before = vg.height_field(&min, &max);
+        vg.erode(&min, &max, &params(3), &MaterialRegistry::builtin());
+        assert!(vg.height_field(&min, &max) != before);
+        for (pos, v) in bedrock {
+            assert!(vg.voxel_at(&pos) == v);
+        }
+    }
+
+    #[test]
+    fn erode_undo() {
+        let mut vg = valley();
+        let (min, max) = (Vector3::new(0, 0, 0), Vector3::new(31, 31, 31));
+        let before = vg.height_field(&min, &max);
+        vg.erode(&min, &max, &params(3), &MaterialRegistry::builtin());
+        let after = vg.height_field(&min, &max);
+        assert!(after != before);
+        assert!(vg.undo());
+        assert_eq!(vg.height_field(&min, &max), before);
+    }
+}
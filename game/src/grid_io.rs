@@ -0,0 +1,448 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use cgmath::Vector3;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use material::MaterialId;
+use quantize::{self, Bits16, Bits8, OccupancyPrecision};
+use voxel_grid::{
+    check_dimension, linear_index, Chunk, ChunkIndex, QuantizedFloat, Voxel, VoxelGrid,
+};
+
+const MAGIC: [u8; 4] = *b"VXLG";
+
+/// Version of the chunk record layout, bumped whenever `Voxel` or the record
+/// encoding changes. Version 1 stored occupancy in 8 bits, since version 2 it
+/// is stored in 16 bits whatever `OccupancyPrecision` is.
+pub const FORMAT_VERSION: u16 = 2;
+
+/// Version of the mapping from stored material ids to `MaterialId`. Version
+/// 1 stored the index into the old hard-coded material enum, since version 2
+/// the `MaterialRegistry` id is stored as is.
+pub const MATERIAL_TABLE_VERSION: u16 = 2;
+
+/// Materials of the old material enum, by index.
+const LEGACY_MATERIALS: [MaterialId; 6] = [
+    MaterialId::AIR,
+    MaterialId::GRASS,
+    MaterialId::SNOW,
+    MaterialId::WATER,
+    MaterialId::ICE,
+    MaterialId::ROCK,
+];
+
+#[derive(Debug)]
+pub enum GridIoError {
+    Io(io::Error),
+    /// The data does not start with the grid file magic.
+    NotAGrid,
+    UnsupportedVersion(u16),
+    UnsupportedMaterialTable(u16),
+    UnknownMaterial(u8),
+    /// The data is well formed but describes an impossible grid.
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for GridIoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GridIoError::Io(ref e) => write!(f, "i/o error: {}", e),
+            GridIoError::NotAGrid => write!(f, "not a voxel grid file"),
+            GridIoError::UnsupportedVersion(v) => write!(f, "unsupported format version {}", v),
+            GridIoError::UnsupportedMaterialTable(v) => {
+                write!(f, "unsupported material table version {}", v)
+            }
+            GridIoError::UnknownMaterial(id) => write!(f, "unknown material id {}", id),
+            GridIoError::Corrupt(why) => write!(f, "corrupt voxel grid: {}", why),
+        }
+    }
+}
+
+impl Error for GridIoError {
+    fn description(&self) -> &str {
+        match *self {
+            GridIoError::Io(_) => "i/o error",
+            GridIoError::NotAGrid => "not a voxel grid file",
+            GridIoError::UnsupportedVersion(_) => "unsupported format version",
+            GridIoError::UnsupportedMaterialTable(_) => "unsupported material table version",
+            GridIoError::UnknownMaterial(_) => "unknown material id",
+            GridIoError::Corrupt(why) => why,
+        }
+    }
+}
+
+impl From<io::Error> for GridIoError {
+    fn from(e: io::Error) -> Self {
+        GridIoError::Io(e)
+    }
+}
+
+/// Uncompressed start of a grid file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GridHeader {
+    pub version: u16,
+    pub chunk_dimension: u16,
+    pub material_table_version: u16,
+    pub chunk_count: u32,
+}
+
+impl GridHeader {
+    /// Header for `chunk_count` chunks written with the current format.
+    pub fn current(chunk_dimension: u16, chunk_count: u32) -> GridHeader {
+        GridHeader {
+            version: FORMAT_VERSION,
+            chunk_dimension,
+            material_table_version: MATERIAL_TABLE_VERSION,
+            chunk_count,
+        }
+    }
+
+    pub fn read<R: Read>(r: &mut R) -> Result<GridHeader, GridIoError> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(GridIoError::NotAGrid);
+        }
+        let header = GridHeader {
+            version: r.read_u16::<LittleEndian>()?,
+            chunk_dimension: r.read_u16::<LittleEndian>()?,
+            material_table_version: r.read_u16::<LittleEndian>()?,
+            chunk_count: r.read_u32::<LittleEndian>()?,
+        };
+        if header.version == 0 || header.version > FORMAT_VERSION {
+            return Err(GridIoError::UnsupportedVersion(header.version));
+        }
+        if check_dimension(header.chunk_dimension).is_err() {
+            return Err(GridIoError::Corrupt("invalid chunk dimension"));
+        }
+        Ok(header)
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&MAGIC)?;
+        w.write_u16::<LittleEndian>(self.version)?;
+        w.write_u16::<LittleEndian>(self.chunk_dimension)?;
+        w.write_u16::<LittleEndian>(self.material_table_version)?;
+        w.write_u32::<LittleEndian>(self.chunk_count)
+    }
+}
+
+/// Maps material ids of an older material table to the current `MaterialId`.
+struct MaterialTable {
+    /// `None` if stored ids are current ids.
+    materials: Option<&'static [MaterialId]>,
+}
+
+impl MaterialTable {
+    fn for_version(version: u16) -> Result<MaterialTable, GridIoError> {
+        match version {
+            1 => Ok(MaterialTable {
+                materials: Some(&LEGACY_MATERIALS),
+            }),
+            2 => Ok(MaterialTable { materials: None }),
+            v => Err(GridIoError::UnsupportedMaterialTable(v)),
+        }
+    }
+
+    fn material(&self, id: u8) -> Result<MaterialId, GridIoError> {
+        match self.materials {
+            Some(table) => match table.get(id as usize) {
+                Some(m) => Ok(*m),
+                None => Err(GridIoError::UnknownMaterial(id)),
+            },
+            None => Ok(MaterialId(id)),
+        }
+    }
+}
+
+impl VoxelGrid {
+    /// Write every chunk of the grid. Chunks are written in a fixed order so
+    /// equal grids produce equal files.
+    pub fn save<W: Write>(&self, mut w: W) -> Result<(), GridIoError> {
+        let mut chunks = self.chunks().collect::<Vec<_>>();
+        chunks.sort_by_key(|&(idx, _)| (idx.x, idx.y, idx.z));
+
+        GridHeader::current(self.dimension(), chunks.len() as u32).write(&mut w)?;
+
+        let mut body = ZlibEncoder::new(w, Compression::default());
+        for (idx, chunk) in chunks {
+            write_chunk(&mut body, idx, chunk)?;
+        }
+        body.finish()?.flush()?;
+        Ok(())
+    }
+
+    /// Read a grid written by `save`, migrating older versions. The loaded
+    /// chunks are marked as changed.
+    pub fn load<R: Read>(mut r: R) -> Result<VoxelGrid, GridIoError> {
+        let header = GridHeader::read(&mut r)?;
+        let materials = MaterialTable::for_version(header.material_table_version)?;
+
+        let mut grid = VoxelGrid::with_dimension(header.chunk_dimension);
+        let mut body = ZlibDecoder::new(r);
+        for _ in 0..header.chunk_count {
+            let (idx, chunk) = read_chunk(&mut body, &header, &materials)?;
+            if grid.get_chunk(&idx).is_some() {
+                return Err(GridIoError::Corrupt("chunk stored twice"));
+            }
+            grid.try_insert_chunk(&idx, chunk)
+                .map_err(|_| GridIoError::Corrupt("chunk has a different dimension"))?;
+        }
+        Ok(grid)
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), GridIoError> {
+        self.save(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<VoxelGrid, GridIoError> {
+        VoxelGrid::load(BufReader::new(File::open(path)?))
+    }
+}
+
+/// A chunk is stored as its index followed by runs of equal voxels in x-y-z
+/// order.
+pub fn write_chunk<W: Write>(w: &mut W, idx: &ChunkIndex, chunk: &Chunk) -> io::Result<()> {
+    let mut runs: Vec<(u32, Voxel)> = Vec::new();
+    for (_, &v) in chunk.voxels() {
+        match runs.last_mut() {
+            Some(&mut (ref mut len, last)) if last == v => {
+                *len += 1;
+                continue;
+            }
+            _ => {}
+        }
+        runs.push((1, v));
+    }
+
+    w.write_i32::<LittleEndian>(idx.x)?;
+    w.write_i32::<LittleEndian>(idx.y)?;
+    w.write_i32::<LittleEndian>(idx.z)?;
+    w.write_u32::<LittleEndian>(runs.len() as u32)?;
+    for (len, v) in runs {
+        w.write_u32::<LittleEndian>(len)?;
+        write_voxel(w, &v)?;
+    }
+    Ok(())
+}
+
+/// Read a chunk written by `write_chunk` as part of a grid described by
+/// `header`, migrating older versions.
+pub fn read_chunk_record<R: Read>(
+    r: &mut R,
+    header: &GridHeader,
+) -> Result<(ChunkIndex, Chunk), GridIoError> {
+    let materials = MaterialTable::for_version(header.material_table_version)?;
+    read_chunk(r, header, &materials)
+}
+
+fn read_chunk<R: Read>(
+    r: &mut R,
+    header: &GridHeader,
+    materials: &MaterialTable,
+) -> Result<(ChunkIndex, Chunk), GridIoError> {
+    let idx = Vector3::new(
+        r.read_i32::<LittleEndian>()?,
+        r.read_i32::<LittleEndian>()?,
+        r.read_i32::<LittleEndian>()?,
+    );
+
+    let dim = header.chunk_dimension;
+    let total = {
+        let d = dim as usize;
+        d * d * d
+    };
+    let run_count = r.read_u32::<LittleEndian>()?;
+    let mut voxels = Vec::with_capacity(total);
+    for _ in 0..run_count {
+        let len = r.read_u32::<LittleEndian>()? as usize;
+        let v = read_voxel(r, header.version, materials)?;
+        if len > total - voxels.len() {
+            return Err(GridIoError::Corrupt("chunk has too many voxels"));
+        }
+        voxels.extend((0..len).map(|_| v));
+    }
+    if voxels.len() != total {
+        return Err(GridIoError::Corrupt("chunk has too few voxels"));
+    }
+
+    let chunk = Chunk::from_fn(dim, |i| voxels[linear_index(dim, i)]);
+    Ok((idx, chunk))
+}
+
+fn write_voxel<W: Write>(w: &mut W, v: &Voxel) -> io::Result<()> {
+    w.write_u8(v.get_material().0)?;
+    let occupancy = quantize::convert::<OccupancyPrecision, Bits16>(v.get_occupancy().value);
+    w.write_u16::<LittleEndian>(occupancy)
+}
+
+/// Read a voxel stored with format `version`, converting it to the current
+/// layout.
+fn read_voxel<R: Read>(
+    r: &mut R,
+    version: u16,
+    materials: &MaterialTable,
+) -> Result<Voxel, GridIoError> {
+    let material = materials.material(r.read_u8()?)?;
+    let occupancy = match version {
+        1 => quantize::convert::<Bits8, OccupancyPrecision>(r.read_u8()?),
+        2 => quantize::convert::<Bits16, OccupancyPrecision>(r.read_u16::<LittleEndian>()?),
+        v => return Err(GridIoError::UnsupportedVersion(v)),
+    };
+    Ok(Voxel::new_with_args(
+        material,
+        QuantizedFloat::new(occupancy),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn same_voxels(a: &VoxelGrid, b: &VoxelGrid) -> bool {
+        let dim = a.dimension();
+        a.dimension() == b.dimension()
+            && a.chunks().count() == b.chunks().count()
+            && a.chunks().all(|(idx, ca)| match b.get_chunk(idx) {
+                Some(cb) => (0..dim).all(|z| {
+                    (0..dim).all(|y| {
+                        (0..dim).all(|x| {
+                            let i = Vector3::new(x, y, z);
+                            ca.get_voxel_at(i) == cb.get_voxel_at(i)
+                        })
+                    })
+                }),
+                None => false,
+            })
+    }
+
+    fn saved(grid: &VoxelGrid) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        grid.save(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn io_header() {
+        let mut grid = VoxelGrid::with_dimension(8);
+        grid.set_voxel(
+            &Vector3::new(-3, 9, 0),
+            MaterialId::ICE,
+            QuantizedFloat::new(7),
+        );
+        let bytes = saved(&grid);
+        let header = GridHeader::read(&mut &bytes[..]).unwrap();
+        assert_eq!(
+            header,
+            GridHeader {
+                version: FORMAT_VERSION,
+                chunk_dimension: 8,
+                material_table_version: MATERIAL_TABLE_VERSION,
+                chunk_count: 1,
+            }
+        );
+
+        let loaded = VoxelGrid::load(&bytes[..]).unwrap();
+        assert_eq!(
+            loaded.voxel_at(&Vector3::new(-3, 9, 0)).get_material(),
+            MaterialId::ICE
+        );
+        assert!(loaded.is_dirty(&Vector3::new(-1, 1, 0)));
+        assert_eq!(saved(&loaded), bytes);
+    }
+
+    #[test]
+    fn io_rejects_bad_input() {
+        let bytes = saved(&VoxelGrid::with_dimension(4));
+        match VoxelGrid::load(&b"nope"[..]) {
+            Err(GridIoError::NotAGrid) => {}
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+
+        let mut newer = bytes.clone();
+        newer[4] = 0xff;
+        match VoxelGrid::load(&newer[..]) {
+            Err(GridIoError::UnsupportedVersion(_)) => {}
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+
+        let mut grid = VoxelGrid::with_dimension(4);
+        grid.set_voxel(
+            &Vector3::new(0, 0, 0),
+            MaterialId::ROCK,
+            QuantizedFloat::new(1),
+        );
+        let bytes = saved(&grid);
+        match VoxelGrid::load(&bytes[..16]) {
+            Err(GridIoError::Io(_)) => {}
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+    }
+
+    /// A one voxel grid in the version 1 layout, with the given material
+    /// table and stored material.
+    fn single_voxel(material_table_version: u16, material: u8) -> Vec<u8> {
+        let mut body = Vec::new();
+        {
+            let mut enc = ZlibEncoder::new(&mut body, Compression::default());
+            for v in &[0i32, 0, 0] {
+                enc.write_i32::<LittleEndian>(*v).unwrap();
+            }
+            enc.write_u32::<LittleEndian>(1).unwrap();
+            enc.write_u32::<LittleEndian>(1).unwrap();
+            enc.write_all(&[material, 255]).unwrap();
+            enc.finish().unwrap();
+        }
+        let mut bytes = Vec::new();
+        GridHeader {
+            version: 1,
+            chunk_dimension: 1,
+            material_table_version,
+            chunk_count: 1,
+        }
+        .write(&mut bytes)
+        .unwrap();
+        bytes.extend(body);
+        bytes
+    }
+
+    #[test]
+    fn io_material_tables() {
+        let origin = Vector3::new(0, 0, 0);
+        let legacy = VoxelGrid::load(&single_voxel(1, 5)[..]).unwrap();
+        assert_eq!(legacy.voxel_at(&origin).get_material(), MaterialId::ROCK);
+        assert_eq!(
+            legacy.voxel_at(&origin).get_occupancy(),
+            QuantizedFloat::FULL
+        );
+        match VoxelGrid::load(&single_voxel(1, 200)[..]) {
+            Err(GridIoError::UnknownMaterial(200)) => {}
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+
+        let current = VoxelGrid::load(&single_voxel(2, 200)[..]).unwrap();
+        assert_eq!(current.voxel_at(&origin).get_material(), MaterialId(200));
+        match VoxelGrid::load(&single_voxel(3, 0)[..]) {
+            Err(GridIoError::UnsupportedMaterialTable(3)) => {}
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[quickcheck]
+    fn prop_save_load_roundtrip(dim: u8, voxels: Vec<(i8, i8, i8, Voxel)>) -> bool {
+        let mut grid = VoxelGrid::with_dimension(u16::from(dim % 8 + 1));
+        for (x, y, z, v) in voxels {
+            let pos = Vector3::new(i32::from(x), i32::from(y), i32::from(z));
+            grid.set_voxel(&pos, v.get_material(), v.get_occupancy());
+        }
+        let loaded = VoxelGrid::load(&saved(&grid)[..]).unwrap();
+        same_voxels(&grid, &loaded)
+    }
+}
@@ -0,0 +1,629 @@
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+use cgmath::Vector3;
+use flate2::read::ZlibDecoder;
+use flate2::Crc;
+
+use strata::{Strata, StrataRules};
+use terrain::Terrain;
+use voxel_grid::VoxelGrid;
+
+const PNG_SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+/// Most samples per side of a heightmap.
+pub const MAX_HEIGHTMAP_SIZE: usize = 16384;
+
+#[derive(Debug)]
+pub enum HeightmapError {
+    Io(io::Error),
+    /// A valid file using a feature that is not supported, e.g. a colour
+    /// PNG.
+    Unsupported(&'static str),
+    Malformed(&'static str),
+}
+
+impl fmt::Display for HeightmapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HeightmapError::Io(ref e) => write!(f, "i/o error: {}", e),
+            HeightmapError::Unsupported(what) => write!(f, "unsupported heightmap: {}", what),
+            HeightmapError::Malformed(why) => write!(f, "malformed heightmap: {}", why),
+        }
+    }
+}
+
+impl Error for HeightmapError {
+    fn description(&self) -> &str {
+        match *self {
+            HeightmapError::Io(_) => "i/o error",
+            HeightmapError::Unsupported(what) => what,
+            HeightmapError::Malformed(why) => why,
+        }
+    }
+}
+
+impl From<io::Error> for HeightmapError {
+    fn from(e: io::Error) -> Self {
+        HeightmapError::Io(e)
+    }
+}
+
+/// Grid of elevation samples. Sample `(x, z)` covers `[x, x + 1)` by
+/// `[z, z + 1)` pixels, rows run along +z.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heightmap {
+    pub width: usize,
+    pub depth: usize,
+    /// Samples in row order, NaN where the file has no data.
+    pub heights: Vec<f32>,
+    /// Metres between samples, if the file says.
+    pub cell_size: Option<f32>,
+}
+
+impl Heightmap {
+    /// Greyscale PNG with 8 or 16 bits per sample.
+    pub fn read_png<R: Read>(mut r: R) -> Result<Heightmap, HeightmapError> {
+        let mut signature = [0u8; 8];
+        r.read_exact(&mut signature)?;
+        if signature != PNG_SIGNATURE {
+            return Err(HeightmapError::Malformed("not a PNG file"));
+        }
+
+        let mut header = None;
+        let mut data = Vec::new();
+        loop {
+            let len = r.read_u32::<BigEndian>()? as usize;
+            let mut kind = [0u8; 4];
+            r.read_exact(&mut kind)?;
+            let mut content = Vec::new();
+            r.by_ref().take(len as u64).read_to_end(&mut content)?;
+            if content.len() != len {
+                return Err(HeightmapError::Malformed("truncated PNG chunk"));
+            }
+            let mut crc = Crc::new();
+            crc.update(&kind);
+            crc.update(&content);
+            if r.read_u32::<BigEndian>()? != crc.sum() {
+                return Err(HeightmapError::Malformed("PNG chunk checksum mismatch"));
+            }
+
+            match &kind {
+                b"IHDR" => header = Some(png_header(&content)?),
+                b"IDAT" => data.extend_from_slice(&content),
+                b"IEND" => break,
+                _ if kind[0] & 0x20 == 0 => {
+                    return Err(HeightmapError::Unsupported("critical PNG chunk"));
+                }
+                _ => {}
+            }
+        }
+
+        let (width, depth, bytes) = match header {
+            Some(h) => h,
+            None => return Err(HeightmapError::Malformed("PNG without IHDR")),
+        };
+        let stride = width * bytes;
+        let mut pixels = Vec::with_capacity((stride + 1) * depth);
+        ZlibDecoder::new(&data[..])
+            .take(((stride + 1) * depth) as u64)
+            .read_to_end(&mut pixels)?;
+        if pixels.len() < (stride + 1) * depth {
+            return Err(HeightmapError::Malformed("PNG image data is too short"));
+        }
+
+        let mut heights = Vec::with_capacity(width * depth);
+        let mut previous = vec![0u8; stride];
+        for row in pixels.chunks(stride + 1).take(depth) {
+            let mut line = row[1..].to_vec();
+            unfilter(row[0], bytes, &previous, &mut line)?;
+            if bytes == 2 {
+                let mut r = &line[..];
+                for _ in 0..width {
+                    heights.push(f32::from(r.read_u16::<BigEndian>()?));
+                }
+            } else {
+                heights.extend(line.iter().map(|&b| f32::from(b)));
+            }
+            previous = line;
+        }
+
+        Ok(Heightmap {
+            width,
+            depth,
+            heights,
+            cell_size: None,
+        })
+    }
+
+    /// Little-endian u16 samples without a header.
+    pub fn read_raw<R: Read>(
+        mut r: R,
+        width: usize,
+        depth: usize,
+    ) -> Result<Heightmap, HeightmapError> {
+        check_size(width, depth)?;
+        let mut heights = Vec::with_capacity(width * depth);
+        for _ in 0..width * depth {
+            heights.push(f32::from(r.read_u16::<LittleEndian>()?));
+        }
+        Ok(Heightmap {
+            width,
+            depth,
+            heights,
+            cell_size: None,
+        })
+    }
+
+    /// ESRI ASCII grid. Rows are stored from north to south, the first row
+    /// becomes `z = 0`.
+    pub fn read_esri_ascii<R: Read>(mut r: R) -> Result<Heightmap, HeightmapError> {
+        let mut text = String::new();
+        r.read_to_string(&mut text)?;
+        let mut tokens = text.split_whitespace().peekable();
+
+        let (mut width, mut depth) = (None, None);
+        let (mut cell_size, mut no_data) = (None, None);
+        loop {
+            let key = match tokens.peek() {
+                Some(t) if t.starts_with(|c: char| c.is_ascii_alphabetic()) => t.to_lowercase(),
+                _ => break,
+            };
+            tokens.next();
+            let value = match tokens.next().map(str::parse::<f64>) {
+                Some(Ok(v)) => v,
+                _ => return Err(HeightmapError::Malformed("bad ESRI grid header value")),
+            };
+            match key.as_str() {
+                "ncols" => width = Some(value as usize),
+                "nrows" => depth = Some(value as usize),
+                "cellsize" => cell_size = Some(value as f32),
+                "nodata_value" => no_data = Some(value as f32),
+                _ => {}
+            }
+        }
+
+        let (width, depth) = match (width, depth) {
+            (Some(w), Some(d)) => (w, d),
+            _ => {
+                return Err(HeightmapError::Malformed(
+                    "ESRI grid without ncols or nrows",
+                ))
+            }
+        };
+        check_size(width, depth)?;
+        let mut heights = Vec::with_capacity(width * depth);
+        for _ in 0..width * depth {
+            let h = match tokens.next().map(str::parse::<f32>) {
+                Some(Ok(h)) => h,
+                Some(Err(_)) => return Err(HeightmapError::Malformed("bad ESRI grid value")),
+                None => return Err(HeightmapError::Malformed("ESRI grid is too short")),
+            };
+            heights.push(if Some(h) == no_data {
+                ::std::f32::NAN
+            } else {
+                h
+            });
+        }
+
+        Ok(Heightmap {
+            width,
+            depth,
+            heights,
+            cell_size,
+        })
+    }
+
+    /// Read a heightmap, picking the format from the file extension: `.png`,
+    /// `.asc` or `.raw`/`.r16`. Raw files must be square.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Heightmap, HeightmapError> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(OsStr::to_str)
+            .map(str::to_lowercase)
+            .unwrap_or_default();
+        let r = BufReader::new(File::open(path)?);
+        match extension.as_str() {
+            "png" => Heightmap::read_png(r),
+            "asc" => Heightmap::read_esri_ascii(r),
+            "raw" | "r16" => {
+                let samples = path.metadata()?.len() as usize / 2;
+                let side = (samples as f64).sqrt().round() as usize;
+                if side * side != samples {
+                    return Err(HeightmapError::Malformed("raw heightmap is not square"));
+                }
+                Heightmap::read_raw(r, side, side)
+            }
+            _ => Err(HeightmapError::Unsupported(
+                "unknown heightmap file extension",
+            )),
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, x: usize, z: usize) -> f32 {
+        self.heights[x + self.width * z]
+    }
+
+    /// Height at a point in pixels, bilinearly interpolated between sample
+    /// centres. Points outside the map take the height of the nearest edge.
+    pub fn sample(&self, x: f32, z: f32) -> f32 {
+        let clamp = |v: f32, n: usize| (v - 0.5).max(0.0).min((n - 1) as f32);
+        let (x, z) = (clamp(x, self.width), clamp(z, self.depth));
+        let (x0, z0) = (x.floor() as usize, z.floor() as usize);
+        let (tx, tz) = (x - x0 as f32, z - z0 as f32);
+        // Only read the next sample if it has a say, so missing data does
+        // not spread beyond its neighbours
+        let x1 = if tx > 0.0 { x0 + 1 } else { x0 };
+        let z1 = if tz > 0.0 { z0 + 1 } else { z0 };
+
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        lerp(
+            lerp(self.get(x0, z0), self.get(x1, z0), tx),
+            lerp(self.get(x0, z1), self.get(x1, z1), tx),
+            tz,
+        )
+    }
+
+    /// Lowest and highest sample, ignoring missing data.
+    pub fn range(&self) -> Option<(f32, f32)> {
+        self.heights
+            .iter()
+            .filter(|h| !h.is_nan())
+            .fold(None, |range, &h| match range {
+                Some((lo, hi)) => Some((h.min(lo), h.max(hi))),
+                None => Some((h, h)),
+            })
+    }
+}
+
+/// Real world size of a heightmap's pixels and samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeightmapScale {
+    /// Metres between neighbouring samples, the cell size of the map if
+    /// `None`, or 1 if the map has none either.
+    pub metres_per_pixel: Option<f32>,
+    /// Metres per unit of sample value, e.g. 0.1 for decimetre samples.
+    pub vertical_scale: f32,
+    /// Metres per voxel.
+    pub voxel_size: f32,
+}
+
+impl Default for HeightmapScale {
+    fn default() -> Self {
+        HeightmapScale {
+            metres_per_pixel: None,
+            vertical_scale: 1.0,
+            voxel_size: 1.0,
+        }
+    }
+}
+
+impl HeightmapScale {
+    /// Metres between neighbouring samples of `map`.
+    pub fn metres_per_pixel(&self, map: &Heightmap) -> f32 {
+        self.metres_per_pixel.or(map.cell_size).unwrap_or(1.0)
+    }
+
+    fn check(&self, map: &Heightmap) -> Result<(), HeightmapError> {
+        let valid = |v: f32| v.is_finite() && v > 0.0;
+        if valid(self.metres_per_pixel(map)) && valid(self.vertical_scale) && valid(self.voxel_size)
+        {
+            Ok(())
+        } else {
+            Err(HeightmapError::Unsupported("heightmap scale"))
+        }
+    }
+}
+
+impl Terrain {
+    /// Terrain of columns following a heightmap, filled with the layers of
+    /// `strata` down to bedrock at `y = 0`. The map
+    /// starts at the world origin and extends along +x and +z. The lowest
+    /// point of the map lies above the layers and the bedrock of `strata`,
+    /// so every column has all of them. The voxel at the surface
+    /// of every column is occupied by the fraction of it below the height.
+    /// Columns next to samples without data are left empty. Fails if any
+    /// part of `scale`, or the cell size it defaults to, is not a positive,
+    /// finite number.
+    pub fn from_heightmap(
+        map: &Heightmap,
+        scale: &HeightmapScale,
+        strata: &StrataRules,
+        dimension: u16,
+    ) -> Result<Terrain, HeightmapError> {
+        scale.check(map)?;
+        let mut grid = VoxelGrid::with_dimension(dimension);
+        let min = match map.range() {
+            Some((min, _)) if map.width > 0 && map.depth > 0 => min,
+            _ => return Ok(Terrain::new(grid)),
+        };
+
+        let pixels_per_voxel = scale.voxel_size / scale.metres_per_pixel(map);
+        let columns = |n: usize| ((n as f32 / pixels_per_voxel).round() as usize).max(1);
+        let (nx, nz) = (columns(map.width), columns(map.depth));
+        let base = (strata.soil_depth() + strata.bedrock_depth).ceil().max(1.0);
+        let mut surface = Vec::with_capacity(nx * nz);
+        for z in 0..nz {
+            for x in 0..nx {
+                let h = map.sample(
+                    (x as f32 + 0.5) * pixels_per_voxel,
+                    (z as f32 + 0.5) * pixels_per_voxel,
+                );
+                surface.push(base + (h - min) * scale.vertical_scale / scale.voxel_size);
+            }
+        }
+        let top = surface
+            .iter()
+            .filter(|s| !s.is_nan())
+            .fold(base, |top, &s| top.max(s));
+
+        let dim = i32::from(dimension);
+        let last = |n: i32| (n - 1) / dim;
+        let max = Vector3::new(last(nx as i32), last(top.ceil() as i32), last(nz as i32));
+        let height = |x: f32, z: f32| {
+            if x < 0.0 || z < 0.0 || x as usize >= nx || z as usize >= nz {
+                return ::std::f32::NAN;
+            }
+            surface[x as usize + nx * z as usize]
+        };
+        grid.fill_region(
+            &Vector3::new(0, 0, 0),
+            &max,
+            &Strata::new(strata, 0.0, height),
+        );
+        Ok(Terrain::new(grid))
+    }
+}
+
+fn check_size(width: usize, depth: usize) -> Result<(), HeightmapError> {
+    if width == 0 || depth == 0 || width > MAX_HEIGHTMAP_SIZE || depth > MAX_HEIGHTMAP_SIZE {
+        return Err(HeightmapError::Unsupported("heightmap size"));
+    }
+    Ok(())
+}
+
+/// Width, height and bytes per sample of a PNG header.
+fn png_header(content: &[u8]) -> Result<(usize, usize, usize), HeightmapError> {
+    if content.len() != 13 {
+        return Err(HeightmapError::Malformed("IHDR has the wrong length"));
+    }
+    let mut r = content;
+    let width = r.read_u32::<BigEndian>()? as usize;
+    let depth = r.read_u32::<BigEndian>()? as usize;
+    let (bit_depth, colour, interlace) = (content[8], content[9], content[12]);
+    check_size(width, depth)?;
+    if colour != 0 {
+        return Err(HeightmapError::Unsupported("PNG is not greyscale"));
+    }
+    if interlace != 0 {
+        return Err(HeightmapError::Unsupported("interlaced PNG"));
+    }
+    match bit_depth {
+        8 => Ok((width, depth, 1)),
+        16 => Ok((width, depth, 2)),
+        _ => Err(HeightmapError::Unsupported("PNG bit depth")),
+    }
+}
+
+/// Undo the PNG filter of a row in place, given the unfiltered row above.
+fn unfilter(
+    filter: u8,
+    bytes: usize,
+    previous: &[u8],
+    line: &mut [u8],
+) -> Result<(), HeightmapError> {
+    for i in 0..line.len() {
+        let left = if i >= bytes { line[i - bytes] } else { 0 };
+        let up = previous[i];
+        let up_left = if i >= bytes { previous[i - bytes] } else { 0 };
+        let predicted = match filter {
+            0 => 0,
+            1 => left,
+            2 => up,
+            3 => ((u16::from(left) + u16::from(up)) / 2) as u8,
+            4 => paeth(left, up, up_left),
+            _ => return Err(HeightmapError::Malformed("unknown PNG filter")),
+        };
+        line[i] = line[i].wrapping_add(predicted);
+    }
+    Ok(())
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = i16::from(a) + i16::from(b) - i16::from(c);
+    let (pa, pb, pc) = (
+        (p - i16::from(a)).abs(),
+        (p - i16::from(b)).abs(),
+        (p - i16::from(c)).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use material::MaterialId;
+    use std::io::Write;
+    use voxel_grid::QuantizedFloat;
+
+    fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], content: &[u8]) {
+        let mut crc = Crc::new();
+        crc.update(kind);
+        crc.update(content);
+        out.write_u32::<BigEndian>(content.len() as u32).unwrap();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(content);
+        out.write_u32::<BigEndian>(crc.sum()).unwrap();
+    }
+
+    /// 16-bit greyscale PNG, row `z` filtered with filter type `z % 5`.
+    fn png(width: usize, samples: &[u16]) -> Vec<u8> {
+        let stride = width * 2;
+        let mut raw = Vec::new();
+        let mut previous = vec![0u8; stride];
+        for (z, row) in samples.chunks(width).enumerate() {
+            let mut line = Vec::new();
+            for &s in row {
+                line.write_u16::<BigEndian>(s).unwrap();
+            }
+            let filter = (z % 5) as u8;
+            let mut filtered = line.clone();
+            for i in (0..stride).rev() {
+                let left = if i >= 2 { line[i - 2] } else { 0 };
+                let up_left = if i >= 2 { previous[i - 2] } else { 0 };
+                let predicted = match filter {
+                    0 => 0,
+                    1 => left,
+                    2 => previous[i],
+                    3 => ((u16::from(left) + u16::from(previous[i])) / 2) as u8,
+                    _ => paeth(left, previous[i], up_left),
+                };
+                filtered[i] = line[i].wrapping_sub(predicted);
+            }
+            raw.push(filter);
+            raw.extend_from_slice(&filtered);
+            previous = line;
+        }
+
+        let mut ihdr = Vec::new();
+        ihdr.write_u32::<BigEndian>(width as u32).unwrap();
+        ihdr.write_u32::<BigEndian>((samples.len() / width) as u32)
+            .unwrap();
+        ihdr.extend_from_slice(&[16, 0, 0, 0, 0]);
+        let mut z = ZlibEncoder::new(Vec::new(), Compression::default());
+        z.write_all(&raw).unwrap();
+        let data = z.finish().unwrap();
+
+        let mut out = PNG_SIGNATURE.to_vec();
+        write_chunk(&mut out, b"IHDR", &ihdr);
+        write_chunk(&mut out, b"tEXt", b"Comment\0ignored");
+        // Split the image data over two chunks
+        write_chunk(&mut out, b"IDAT", &data[..data.len() / 2]);
+        write_chunk(&mut out, b"IDAT", &data[data.len() / 2..]);
+        write_chunk(&mut out, b"IEND", &[]);
+        out
+    }
+
+    #[test]
+    fn heightmap_png() {
+        let samples = (0..30u16)
+            .map(|i| i.wrapping_mul(40_503) ^ 0x5a5a)
+            .collect::<Vec<_>>();
+        let map = Heightmap::read_png(&png(5, &samples)[..]).unwrap();
+        assert_eq!((map.width, map.depth), (5, 6));
+        let expected = samples.iter().map(|&s| f32::from(s)).collect::<Vec<_>>();
+        assert_eq!(map.heights, expected);
+
+        let mut corrupt = png(5, &samples);
+        corrupt[20] ^= 1;
+        assert!(Heightmap::read_png(&corrupt[..]).is_err());
+        assert!(Heightmap::read_png(&b"GIF89a.."[..]).is_err());
+    }
+
+    #[test]
+    fn heightmap_raw_and_esri() {
+        let raw = [1u8, 0, 0, 1, 255, 255, 3, 0, 4, 0, 5, 0];
+        let map = Heightmap::read_raw(&raw[..], 3, 2).unwrap();
+        assert_eq!(map.heights, vec![1.0, 256.0, 65535.0, 3.0, 4.0, 5.0]);
+        assert!(Heightmap::read_raw(&raw[..], 4, 2).is_err());
+
+        let asc = "ncols 3\nNROWS 2\nxllcorner 10.5\nyllcorner -3\ncellsize 30\n\
+                   NODATA_value -9999\n1 2.5 -9999\n  -4 5 6\n";
+        let map = Heightmap::read_esri_ascii(asc.as_bytes()).unwrap();
+        assert_eq!((map.width, map.depth, map.cell_size), (3, 2, Some(30.0)));
+        assert!(map.get(2, 0).is_nan());
+        assert_eq!(map.get(0, 1), -4.0);
+        assert_eq!(map.range(), Some((-4.0, 6.0)));
+        assert!(Heightmap::read_esri_ascii("ncols 3\nnrows 2\n1 2 3".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn terrain_from_heightmap() {
+        // A slope rising 2 units per pixel along x, with a hole
+        let mut map = Heightmap {
+            width: 4,
+            depth: 2,
+            heights: vec![10.0, 12.0, 14.0, 16.0, 10.0, 12.0, 14.0, 16.0],
+            cell_size: None,
+        };
+        map.heights[7] = ::std::f32::NAN;
+        let scale = HeightmapScale {
+            metres_per_pixel: Some(2.0),
+            vertical_scale: 0.5,
+            voxel_size: 1.0,
+        };
+        let terrain = Terrain::from_heightmap(&map, &scale, &StrataRules::default(), 4).unwrap();
+        let grid = terrain.grid();
+        let at = |x, y, z| grid.voxel_at(&Vector3::new(x, y, z));
+
+        // The lowest point is 4 voxels up, on 2 of soil and 2 of bedrock.
+        // Each voxel is half a pixel, the slope rises half a voxel per voxel
+        assert_eq!(at(0, 3, 0).get_material(), MaterialId::GRASS);
+        assert_eq!(at(0, 1, 0).get_material(), MaterialId::BEDROCK);
+        assert_eq!(at(7, 5, 0).get_material(), MaterialId::ROCK);
+        assert_eq!(at(7, 6, 0).get_material(), MaterialId::GRASS);
+        assert_eq!(at(0, 3, 0).get_occupancy(), QuantizedFloat::FULL);
+        assert_eq!(at(0, 4, 0).get_occupancy(), QuantizedFloat::EMPTY);
+        assert_eq!(at(3, 4, 0).get_occupancy(), QuantizedFloat::FULL);
+        assert_eq!(at(3, 5, 0).get_occupancy(), QuantizedFloat::from_f32(0.25));
+        assert_eq!(at(7, 6, 0).get_occupancy(), QuantizedFloat::FULL);
+        assert_eq!(at(7, 7, 0).get_occupancy(), QuantizedFloat::EMPTY);
+        // The hole spreads to the columns interpolating it
+        assert_eq!(at(7, 0, 3).get_occupancy(), QuantizedFloat::EMPTY);
+        assert_eq!(at(0, 0, 3).get_occupancy(), QuantizedFloat::FULL);
+        assert_eq!(
+            grid.loaded_bounds(),
+            Some((Vector3::new(0, 0, 0), Vector3::new(7, 7, 3)))
+        );
+
+        // The cell size of the map is used unless the scale sets one
+        let rules = StrataRules::default();
+        let sized = Heightmap {
+            cell_size: Some(2.0),
+            ..map.clone()
+        };
+        let unsized_scale = HeightmapScale {
+            metres_per_pixel: None,
+            ..scale
+        };
+        let same = Terrain::from_heightmap(&sized, &unsized_scale, &rules, 4).unwrap();
+        assert_eq!(same.grid().loaded_bounds(), grid.loaded_bounds());
+        assert_eq!(same.grid().voxel_at(&Vector3::new(3, 5, 0)), at(3, 5, 0));
+
+        for bad in &[0.0, -1.0, ::std::f32::INFINITY, ::std::f32::NAN] {
+            let scales = [
+                HeightmapScale {
+                    metres_per_pixel: Some(*bad),
+                    ..scale
+                },
+                HeightmapScale {
+                    vertical_scale: *bad,
+                    ..scale
+                },
+                HeightmapScale {
+                    voxel_size: *bad,
+                    ..scale
+                },
+            ];
+            for s in &scales {
+                assert!(Terrain::from_heightmap(&map, s, &rules, 4).is_err());
+            }
+        }
+    }
+}
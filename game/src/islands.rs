@@ -0,0 +1,313 @@
+use std::collections::HashSet;
+
+use cgmath::Vector3;
+
+use material::MaterialRegistry;
+use voxel_grid::{Voxel, VoxelGrid, WorldPos, FACE_DIRECTIONS, ISO_LEVEL};
+
+/// Solid voxels connected through their faces that do not reach bedrock or
+/// indestructible voxels, i.e. float in mid-air.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Island {
+    /// Every voxel of the island, in x-y-z order.
+    pub voxels: Vec<WorldPos>,
+    pub min: WorldPos,
+    pub max: WorldPos,
+}
+
+impl VoxelGrid {
+    /// Islands among the voxels from `min` to `max` (inclusive). Voxels at
+    /// or below `bedrock_level` and indestructible voxels hold up what they
+    /// touch. Voxels on the border of the box may be connected to something
+    /// outside it, so islands touching the border are never reported.
+    pub fn find_islands(
+        &self,
+        min: &WorldPos,
+        max: &WorldPos,
+        bedrock_level: i32,
+        materials: &MaterialRegistry,
+    ) -> Vec<Island> {
+        let mut islands = Vec::new();
+        if min.x > max.x || min.y > max.y || min.z > max.z {
+            return islands;
+        }
+        let size = max - min + Vector3::new(1, 1, 1);
+        let (sx, sy) = (size.x as usize, size.y as usize);
+
+        // Solid voxels not yet visited, in x-y-z order
+        let region = self.region(min, max).map(|(_, v)| v).collect::<Vec<_>>();
+        let mut solid = region
+            .iter()
+            .map(|v| v.get_occupancy_as_f32() >= ISO_LEVEL)
+            .collect::<Vec<_>>();
+        let mut stack = Vec::new();
+        for start in 0..solid.len() {
+            if !solid[start] {
+                continue;
+            }
+            solid[start] = false;
+            stack.push(start);
+
+            let mut voxels = Vec::new();
+            let mut anchored = false;
+            while let Some(i) = stack.pop() {
+                let local = Vector3::new(
+                    (i % sx) as i32,
+                    (i / sx % sy) as i32,
+                    (i / (sx * sy)) as i32,
+                );
+                let pos = min + local;
+                let border = local.x == 0
+                    || local.y == 0
+                    || local.z == 0
+                    || local.x == size.x - 1
+                    || local.y == size.y - 1
+                    || local.z == size.z - 1;
+                if border || anchors(&pos, region[i], bedrock_level, materials) {
+                    anchored = true;
+                    voxels.clear();
+                } else if !anchored {
+                    voxels.push(pos);
+                }
+
+                for d in &FACE_DIRECTIONS {
+                    let n = local + Vector3::new(d[0], d[1], d[2]);
+                    if n.x < 0
+                        || n.y < 0
+                        || n.z < 0
+                        || n.x >= size.x
+                        || n.y >= size.y
+                        || n.z >= size.z
+                    {
+                        continue;
+                    }
+                    let j = n.x as usize + sx * (n.y as usize + sy * n.z as usize);
+                    if solid[j] {
+                        solid[j] = false;
+                        stack.push(j);
+                    }
+                }
+            }
+
+            if !anchored {
+                islands.push(island(voxels));
+            }
+        }
+        islands
+    }
+
+    /// Islands anywhere in the grid. Chunks that do not exist are air, so
+    /// nothing outside the loaded chunks can hold an island up. Only the
+    /// loaded chunks are searched, however far apart they are.
+    pub fn find_all_islands(
+        &self,
+        bedrock_level: i32,
+        materials: &MaterialRegistry,
+    ) -> Vec<Island> {
+        let solid = |pos: &WorldPos| self.voxel_at(pos).get_occupancy_as_f32() >= ISO_LEVEL;
+        let dim = self.dimension();
+        let mut visited = HashSet::new();
+        let mut islands = Vec::new();
+        let mut stack = Vec::new();
+        for (idx, chunk) in self.chunks() {
+            let first = chunk.get_voxel_at(Vector3::new(0, 0, 0));
+            if chunk.is_uniform() && first.get_occupancy_as_f32() < ISO_LEVEL {
+                continue;
+            }
+            for z in 0..dim {
+                for y in 0..dim {
+                    for x in 0..dim {
+                        let start = self.world_pos(idx, &Vector3::new(x, y, z));
+                        if !solid(&start) || !visited.insert(start) {
+                            continue;
+                        }
+                        stack.push(start);
+
+                        let mut voxels = Vec::new();
+                        let mut anchored = false;
+                        while let Some(pos) = stack.pop() {
+                            if anchors(&pos, &self.voxel_at(&pos), bedrock_level, materials) {
+                                anchored = true;
+                                voxels.clear();
+                            } else if !anchored {
+                                voxels.push(pos);
+                            }
+                            for d in &FACE_DIRECTIONS {
+                                let n = pos + Vector3::new(d[0], d[1], d[2]);
+                                if solid(&n) && visited.insert(n) {
+                                    stack.push(n);
+                                }
+                            }
+                        }
+
+                        if !anchored {
+                            islands.push(island(voxels));
+                        }
+                    }
+                }
+            }
+        }
+        // In x-y-z order of their first voxel, as `find_islands` returns them
+        islands.sort_by_key(|i| {
+            let p = i.voxels[0];
+            (p.z, p.y, p.x)
+        });
+        islands
+    }
+
+    /// Replace every voxel of an island with air, as one step of the undo
+    /// history. Indestructible voxels are kept.
+    pub fn remove_island(&mut self, island: &Island, materials: &MaterialRegistry) {
+        let air = Voxel::new();
+        self.begin_transaction();
+        for pos in &island.voxels {
+            if !materials.is_indestructible(self.voxel_at(pos).get_material()) {
+                self.set_voxel(pos, air.get_material(), air.get_occupancy());
+            }
+        }
+        self.commit_transaction();
+    }
+}
+
+/// True if the solid voxel `v` at `pos` holds up what it touches.
+fn anchors(pos: &WorldPos, v: &Voxel, bedrock_level: i32, materials: &MaterialRegistry) -> bool {
+    pos.y <= bedrock_level || materials.is_indestructible(v.get_material())
+}
+
+fn island(mut voxels: Vec<WorldPos>) -> Island {
+    voxels.sort_by_key(|p| (p.z, p.y, p.x));
+    let first = voxels[0];
+    let (min, max) = voxels.iter().fold((first, first), |(lo, hi), p| {
+        (
+            Vector3::new(lo.x.min(p.x), lo.y.min(p.y), lo.z.min(p.z)),
+            Vector3::new(hi.x.max(p.x), hi.y.max(p.y), hi.z.max(p.z)),
+        )
+    });
+    Island { voxels, min, max }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use material::MaterialId;
+    use voxel_grid::QuantizedFloat;
+
+    fn ground() -> VoxelGrid {
+        let mut vg = VoxelGrid::with_dimension(8);
+        vg.fill_region(
+            &Vector3::new(-1, -1, -1),
+            &Vector3::new(0, 0, 0),
+            &|pos: &WorldPos| {
+                if pos.y < -4 {
+                    Voxel::new_with_args(MaterialId::ROCK, QuantizedFloat::FULL)
+                } else {
+                    Voxel::new()
+                }
+            },
+        );
+        vg
+    }
+
+    fn put(vg: &mut VoxelGrid, x: i32, y: i32, z: i32) {
+        vg.set_voxel(
+            &Vector3::new(x, y, z),
+            MaterialId::SNOW,
+            QuantizedFloat::FULL,
+        );
+    }
+
+    #[test]
+    fn islands_float() {
+        let materials = MaterialRegistry::builtin();
+        let mut vg = ground();
+        assert!(vg.find_all_islands(-8, &materials).is_empty());
+
+        // A pillar standing on the ground
+        for y in -4..3 {
+            put(&mut vg, 4, y, 4);
+        }
+        // A floating blob and a voxel only touching it along an edge
+        put(&mut vg, 0, 2, 0);
+        put(&mut vg, 1, 2, 0);
+        put(&mut vg, 1, 3, 1);
+
+        let islands = vg.find_all_islands(-8, &materials);
+        assert_eq!(islands.len(), 2);
+        assert_eq!(
+            islands[0].voxels,
+            vec![Vector3::new(0, 2, 0), Vector3::new(1, 2, 0)]
+        );
+        assert_eq!(islands[0].min, Vector3::new(0, 2, 0));
+        assert_eq!(islands[0].max, Vector3::new(1, 2, 0));
+        assert_eq!(islands[1].voxels, vec![Vector3::new(1, 3, 1)]);
+
+        // The ground itself is an island if bedrock is deeper than the map
+        assert_eq!(vg.find_all_islands(-20, &materials).len(), 3);
+    }
+
+    #[test]
+    fn islands_in_distant_chunks() {
+        let materials = MaterialRegistry::builtin();
+        let mut vg = ground();
+        put(&mut vg, 0, 2, 0);
+        // Far away from the ground, in chunks of its own
+        put(&mut vg, 100_000, 2, -100_000);
+        put(&mut vg, 100_000, -7, -100_000);
+        put(&mut vg, 100_000, -8, -100_000);
+
+        let islands = vg.find_all_islands(-8, &materials);
+        assert_eq!(islands.len(), 2);
+        assert_eq!(islands[0].voxels, vec![Vector3::new(100_000, 2, -100_000)]);
+        assert_eq!(islands[1].voxels, vec![Vector3::new(0, 2, 0)]);
+    }
+
+    #[test]
+    fn islands_touching_border() {
+        let materials = MaterialRegistry::builtin();
+        let mut vg = ground();
+        put(&mut vg, 0, 2, 0);
+        put(&mut vg, 1, 2, 0);
+
+        let (min, max) = (Vector3::new(-1, 1, -1), Vector3::new(2, 3, 1));
+        assert_eq!(vg.find_islands(&min, &max, -8, &materials).len(), 1);
+        let max = Vector3::new(1, 3, 1);
+        assert!(vg.find_islands(&min, &max, -8, &materials).is_empty());
+        assert!(vg.find_islands(&max, &min, -8, &materials).is_empty());
+    }
+
+    #[test]
+    fn remove_island_undo() {
+        let materials = MaterialRegistry::builtin();
+        let mut vg = ground();
+        put(&mut vg, 0, 2, 0);
+        put(&mut vg, 0, 3, 0);
+
+        let islands = vg.find_all_islands(-8, &materials);
+        assert_eq!(islands.len(), 1);
+        vg.remove_island(&islands[0], &materials);
+        assert!(vg.find_all_islands(-8, &materials).is_empty());
+        assert_eq!(vg.voxel_at(&Vector3::new(0, 3, 0)), Voxel::new());
+
+        assert!(vg.undo());
+        assert_eq!(vg.find_all_islands(-8, &materials), islands);
+    }
+
+    #[test]
+    fn islands_held_by_indestructible() {
+        let materials = MaterialRegistry::builtin();
+        let mut vg = ground();
+        put(&mut vg, 0, 2, 0);
+        put(&mut vg, 0, 3, 0);
+        let pillar = Vector3::new(0, 1, 0);
+        vg.set_voxel(&pillar, MaterialId::BEDROCK, QuantizedFloat::FULL);
+        assert!(vg.find_all_islands(-8, &materials).is_empty());
+        let (min, max) = (Vector3::new(-1, 0, -1), Vector3::new(1, 4, 1));
+        assert!(vg.find_islands(&min, &max, -8, &materials).is_empty());
+
+        // Indestructible voxels of a stale island are kept
+        let island = island(vec![pillar, Vector3::new(0, 2, 0)]);
+        vg.remove_island(&island, &materials);
+        assert_eq!(vg.voxel_at(&pillar).get_material(), MaterialId::BEDROCK);
+        assert_eq!(vg.voxel_at(&Vector3::new(0, 2, 0)), Voxel::new());
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::{hash_map, HashMap, VecDeque};
+use std::mem;
+
+use voxel_grid::{ChunkIndex, Voxel, VoxelIndex};
+
+pub const DEFAULT_JOURNAL_BUDGET: usize = 16 * 1024 * 1024;
+
+/// Rough cost of one journaled voxel edit, including map overhead.
+const EDIT_SIZE: usize = mem::size_of::<VoxelIndex>() + 2 * mem::size_of::<Voxel>() + 16;
+
+/// Voxels of one chunk changed by a transaction, with their values before
+/// and after it.
+#[derive(Debug, Clone, Default)]
+pub struct ChunkDelta {
+    edits: HashMap<VoxelIndex, (Voxel, Voxel)>,
+}
+
+impl ChunkDelta {
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.edits.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.edits.is_empty()
+    }
+
+    /// Edited voxels with their `(before, after)` values.
+    #[inline]
+    pub fn iter<'a>(&'a self) -> hash_map::Iter<'a, VoxelIndex, (Voxel, Voxel)> {
+        self.edits.iter()
+    }
+}
+
+/// Everything changed by one logical operation.
+#[derive(Debug, Clone, Default)]
+pub struct Transaction {
+    deltas: HashMap<ChunkIndex, ChunkDelta>,
+}
+
+impl Transaction {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    #[inline]
+    pub fn deltas(&self) -> &HashMap<ChunkIndex, ChunkDelta> {
+        &self.deltas
+    }
+
+    fn record(&mut self, chunk: &ChunkIndex, voxel: VoxelIndex, before: Voxel, after: Voxel) {
+        let delta = self.deltas.entry(*chunk).or_insert_with(Default::default);
+        let first = match delta.edits.get(&voxel) {
+            Some(&(b, _)) => b,
+            None => before,
+        };
+        if first == after {
+            delta.edits.remove(&voxel);
+        } else {
+            delta.edits.insert(voxel, (first, after));
+        }
+        if delta.is_empty() {
+            self.deltas.remove(chunk);
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.deltas.values().map(|d| d.len() * EDIT_SIZE).sum()
+    }
+}
+
+/// Undo and redo history of voxel edits. Edits made outside an explicit
+/// transaction are each their own transaction, nested transactions are
+/// merged into the outermost one. Once the history uses more than its
+/// budget the oldest transactions are forgotten.
+#[derive(Debug, Clone)]
+pub struct EditJournal {
+    undo: VecDeque<Transaction>,
+    redo: Vec<Transaction>,
+    open: Option<Transaction>,
+    depth: usize,
+    budget: usize,
+    used: usize,
+    recording: bool,
+}
+
+impl EditJournal {
+    pub fn new(budget: usize) -> Self {
+        EditJournal {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            open: None,
+            depth: 0,
+            budget,
+            used: 0,
+            recording: true,
+        }
+    }
+
+    #[inline]
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    /// Edits made while not recording can not be undone.
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording = recording;
+    }
+
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.enforce_budget();
+    }
+
+    #[inline]
+    pub fn memory_usage(&self) -> usize {
+        self.used
+    }
+
+    #[inline]
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    #[inline]
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    pub fn begin(&mut self) {
+        self.depth += 1;
+        if self.open.is_none() {
+            self.open = Some(Transaction::default());
+        }
+    }
+
+    pub fn commit(&mut self) {
+        if self.depth == 0 {
+            return;
+        }
+        self.depth -= 1;
+        if self.depth == 0 {
+            if let Some(t) = self.open.take() {
+                self.push(t);
+            }
+        }
+    }
+
+    pub fn record(&mut self, chunk: &ChunkIndex, voxel: VoxelIndex, before: Voxel, after: Voxel) {
+        if !self.recording {
+            return;
+        }
+        match self.open {
+            Some(ref mut t) => t.record(chunk, voxel, before, after),
+            None => {
+                let mut t = Transaction::default();
+                t.record(chunk, voxel, before, after);
+                self.push(t);
+            }
+        }
+    }
+
+    /// Take the most recent transaction to be undone, moving it to the redo
+    /// history.
+    pub fn pop_undo(&mut self) -> Option<&Transaction> {
+        let t = self.undo.pop_back()?;
+        self.redo.push(t);
+        self.redo.last()
+    }
+
+    /// Take the most recently undone transaction to be applied again.
+    pub fn pop_redo(&mut self) -> Option<&Transaction> {
+        let t = self.redo.pop()?;
+        self.undo.push_back(t);
+        self.undo.back()
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.open = None;
+        self.depth = 0;
+        self.used = 0;
+    }
+
+    fn push(&mut self, t: Transaction) {
+        if t.is_empty() {
+            return;
+        }
+        for r in self.redo.drain(..) {
+            self.used -= r.memory_usage();
+        }
+        self.used += t.memory_usage();
+        self.undo.push_back(t);
+        self.enforce_budget();
+    }
+
+    fn enforce_budget(&mut self) {
+        while self.used > self.budget {
+            match self.undo.pop_front() {
+                Some(t) => self.used -= t.memory_usage(),
+                None => break,
+            }
+        }
+    }
+}
+
+impl Default for EditJournal {
+    fn default() -> Self {
+        EditJournal::new(DEFAULT_JOURNAL_BUDGET)
+    }
+}
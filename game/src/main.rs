@@ -5,16 +5,27 @@
 // Use QuickCheck only when testing
 #![cfg_attr(test, feature(plugin))]
 #![cfg_attr(test, plugin(quickcheck_macros))]
+// Benchmarks use the unstable test crate
+#![cfg_attr(test, feature(test))]
 
 #[cfg(test)]
 extern crate quickcheck;
+#[cfg(test)]
+extern crate test;
 
 extern crate amethyst;
+extern crate byteorder;
 extern crate cgmath;
-extern crate genmesh;
+extern crate flate2;
+#[macro_use]
+extern crate log;
+extern crate rayon;
+extern crate ron;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 
 use amethyst::assets::Loader;
-use amethyst::core::cgmath::prelude::InnerSpace;
 use amethyst::core::cgmath::{Deg, Vector3};
 use amethyst::core::cgmath::{Quaternion, Rotation3};
 use amethyst::core::frame_limiter::FrameRateLimitStrategy;
@@ -23,18 +34,51 @@ use amethyst::ecs::World;
 use amethyst::input::InputBundle;
 use amethyst::prelude::*;
 use amethyst::renderer::{
-    AmbientColor, Camera, DisplayConfig, DrawShaded, Event, KeyboardInput, Light, Mesh, Pipeline,
+    AmbientColor, Camera, DisplayConfig, DrawShaded, Event, KeyboardInput, Light, Pipeline,
     PointLight, PosNormTex, Projection, RenderBundle, RenderSystem, Rgba, Stage, VirtualKeyCode,
     WindowEvent,
 };
 use amethyst::utils::fps_counter::FPSCounterBundle;
-use genmesh::{generators, MapToVertices, Triangulate, Vertices};
 
+#[cfg(test)]
+mod benches;
+mod brush;
 mod camera_bundle;
+mod changes;
+mod erosion;
 mod fly_cam;
+mod grid_io;
+mod heightmap;
+mod islands;
+mod journal;
+mod material;
+mod mesher;
+mod meshing;
+mod mipmap;
+mod mountains;
+mod palette;
+mod quantize;
+mod raycast;
+mod region;
+mod region_file;
+mod sampling;
+mod strata;
+mod streaming;
+mod terrain;
+mod terrain_bundle;
+mod thermal;
+mod vox;
+mod voxel_events;
 mod voxel_grid;
 
 use camera_bundle::CameraBundle;
+use material::MaterialRegistry;
+use meshing::TerrainMaterial;
+use mountains::{MountainGenerator, MountainParams};
+use strata::StrataRules;
+use streaming::TerrainStreamer;
+use terrain::Terrain;
+use terrain_bundle::TerrainBundle;
 
 const SPHERE_COLOUR: [f32; 4] = [0.0, 0.0, 1.0, 1.0]; // blue
 const AMBIENT_LIGHT_COLOUR: Rgba = Rgba(0.01, 0.01, 0.01, 1.0); // near-black
@@ -43,6 +87,8 @@ const BACKGROUND_COLOUR: [f32; 4] = [0.0, 0.0, 0.0, 0.0]; // black
 const LIGHT_POSITION: [f32; 3] = [2.0, 2.0, 2.0];
 const LIGHT_RADIUS: f32 = 5.0;
 const LIGHT_INTENSITY: f32 = 3.0;
+// A 32 voxel chunk is 15 units wide
+const VOXEL_SIZE: f32 = (60.0 / 32.0) / 4.0;
 
 struct VallenGameState;
 
@@ -54,6 +100,11 @@ impl State for VallenGameState {
         initialise_camera(world);
     }
 
+    fn on_stop(&mut self, world: &mut World) {
+        let mut terrain = world.write_resource::<Terrain>();
+        world.write_resource::<TerrainStreamer>().save(terrain.grid_mut());
+    }
+
     fn handle_event(&mut self, _: &mut World, event: Event) -> Trans {
         match event {
             Event::WindowEvent { event, .. } => match event {
@@ -75,6 +126,7 @@ impl State for VallenGameState {
 fn run() -> Result<(), amethyst::Error> {
     let display_config_path = format!("{}/resources/display.ron", env!("CARGO_MANIFEST_DIR"));
     let key_bindings_path = format!("{}/resources/controls.ron", env!("CARGO_MANIFEST_DIR"));
+    let materials_path = format!("{}/resources/materials.ron", env!("CARGO_MANIFEST_DIR"));
     let resources = format!("{}/resources/assets/", env!("CARGO_MANIFEST_DIR"));
 
     let pipe = Pipeline::build().with_stage(
@@ -84,6 +136,8 @@ fn run() -> Result<(), amethyst::Error> {
     );
 
     let config = DisplayConfig::load(&display_config_path);
+    let materials = MaterialRegistry::load(&materials_path)
+        .unwrap_or_else(|e| panic!("Failed to load {}: {}", materials_path, e));
 
     let mut game = Application::build(resources, VallenGameState)?
         .with_bundle(RenderBundle::new())?
@@ -94,67 +148,37 @@ fn run() -> Result<(), amethyst::Error> {
         .with_frame_limit(FrameRateLimitStrategy::Unlimited, 0)
         .with_bundle(FPSCounterBundle::default())?
         .with_bundle(CameraBundle)?
+        .with_bundle(TerrainBundle::new(materials).with_voxel_size(VOXEL_SIZE))?
         .with_bundle(TransformBundle::new().with_dep(&["fly_cam_system"]))?
         .build()?;
     Ok(game.run())
 }
 
 fn initialise_terrain(world: &mut World) {
-    use amethyst::assets::Handle;
     use amethyst::renderer::{Material, MaterialDefaults};
 
-    let mut vg = voxel_grid::VoxelGrid::new();
-
-    {
-        // Generate a plane of grass in the voxel grid
-        let mut first_chunk = voxel_grid::Chunk::new(32);
-
-        for outer in 0..32 {
-            for inner in 0..32 {
-                first_chunk.set_voxel_at(
-                    Vector3::new(outer, 16, inner),
-                    voxel_grid::Material::Grass,
-                    voxel_grid::QuantizedFloat::new(255),
-                );
-            }
-        }
-        vg.insert_chunk(&Vector3::new(0, 0, 0), first_chunk)
-    }
-
-    // Turn voxel grid into triangles
-    let chunk_size: f32 = 60.0;
-    let voxel_size = (chunk_size / 32.0) / 4.0;
-
-    let mut vertex_data: Vec<PosNormTex> = Vec::new();
-
-    for outer in 0..32 {
-        for inner in 0..32 {
-            vertex_data.extend(
-                generators::Cube::new()
-                    .vertex(|v| PosNormTex {
-                        position: [
-                            v.pos[0] + outer as f32 * voxel_size,
-                            v.pos[1],
-                            v.pos[2] + inner as f32 * voxel_size,
-                        ],
-                        normal: Vector3::from(v.normal).normalize().into(),
-                        tex_coord: [0.1, 0.1],
-                    })
-                    .triangulate()
-                    .vertices()
-                    .collect::<Vec<PosNormTex>>(),
-            )
-        }
-    }
+    let mountains_path = format!("{}/resources/mountains.ron", env!("CARGO_MANIFEST_DIR"));
+    let params = MountainParams::load(&mountains_path)
+        .unwrap_or_else(|e| panic!("Failed to load {}: {}", mountains_path, e));
+    let strata_path = format!("{}/resources/strata.ron", env!("CARGO_MANIFEST_DIR"));
+    let strata = StrataRules::load(&strata_path)
+        .unwrap_or_else(|e| panic!("Failed to load {}: {}", strata_path, e));
+    let mountains = MountainGenerator::new(params).with_strata(strata);
+
+    let mut terrain = Terrain::default();
+    let (low, high) = mountains.chunk_layers(terrain.grid().dimension());
+    terrain.generate(
+        &Vector3::new(-1, low, -1),
+        &Vector3::new(1, high, 1),
+        &mountains,
+    );
 
-    println!("vertices: {:?}", vertex_data.len());
+    // The generated chunks are meshed in the background by the terrain bundle
+    *world.write_resource::<Terrain>() = terrain;
 
-    let (mesh, material) = {
+    let material = {
         let loader = world.read_resource::<Loader>();
 
-        let mesh: Handle<Mesh> =
-            loader.load_from_data(vertex_data.into(), (), &world.read_resource());
-
         let albedo = SPHERE_COLOUR.into();
 
         let tex_storage = world.read_resource();
@@ -162,20 +186,13 @@ fn initialise_terrain(world: &mut World) {
 
         let albedo = loader.load_from_data(albedo, (), &tex_storage);
 
-        let mat = Material {
+        Material {
             albedo,
             ..mat_defaults.0.clone()
-        };
-
-        (mesh, mat)
+        }
     };
 
-    world
-        .create_entity()
-        .with(Transform::default())
-        .with(mesh)
-        .with(material)
-        .build();
+    *world.write_resource::<TerrainMaterial>() = TerrainMaterial(Some(material));
 }
 
 /// This function adds an ambient light and a point light to the world.
@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::slice;
+
+use ron;
+use serde::{Deserialize, Deserializer};
+
+/// Compact id of a material, as stored in every voxel. What an id means is
+/// defined by the `MaterialRegistry`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MaterialId(pub u8);
+
+impl MaterialId {
+    /// Empty space, the material of missing chunks.
+    pub const AIR: MaterialId = MaterialId(0);
+    // Ids of the materials in resources/materials.ron that code relies on
+    pub const GRASS: MaterialId = MaterialId(1);
+    pub const SNOW: MaterialId = MaterialId(2);
+    pub const WATER: MaterialId = MaterialId(3);
+    pub const ICE: MaterialId = MaterialId(4);
+    pub const ROCK: MaterialId = MaterialId(5);
+    pub const BEDROCK: MaterialId = MaterialId(6);
+}
+
+/// Materials the code refers to by id, with the name they must have.
+const RESERVED: [(MaterialId, &str); 6] = [
+    (MaterialId::GRASS, "grass"),
+    (MaterialId::SNOW, "snow"),
+    (MaterialId::WATER, "water"),
+    (MaterialId::ICE, "ice"),
+    (MaterialId::ROCK, "rock"),
+    (MaterialId::BEDROCK, "bedrock"),
+];
+
+impl<'de> Deserialize<'de> for MaterialId {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        u8::deserialize(d).map(MaterialId)
+    }
+}
+
+/// Properties of one material.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MaterialDef {
+    pub id: MaterialId,
+    pub name: String,
+    /// Linear RGB.
+    pub colour: [f32; 3],
+    /// In kg/m³.
+    pub density: f32,
+    /// Friction coefficient for skis sliding over the material.
+    pub friction: f32,
+    /// In W/(m·K).
+    pub thermal_conductivity: f32,
+    /// Fraction of sunlight reflected.
+    pub albedo: f32,
+    /// True if snow can settle on the material.
+    pub holds_snow: bool,
+    /// Steepest slope the material rests at, in degrees. Steeper slopes
+    /// slump until they reach it. Materials without one never slump.
+    #[serde(default = "default_talus_angle")]
+    pub talus_angle: f32,
+    /// Effort to dig out or fill in a voxel, relative to soil.
+    #[serde(default = "default_hardness")]
+    pub hardness: f32,
+    /// True if brushes and erosion cannot change the material.
+    #[serde(default)]
+    pub indestructible: bool,
+}
+
+fn default_talus_angle() -> f32 {
+    90.0
+}
+
+fn default_hardness() -> f32 {
+    1.0
+}
+
+#[derive(Debug)]
+pub enum MaterialError {
+    Io(io::Error),
+    Parse(ron::de::Error),
+    DuplicateId(MaterialId),
+    DuplicateName(String),
+    /// There is no material with id `MaterialId::AIR`.
+    MissingAir,
+    /// A reserved id is missing or has another name than the one given.
+    Reserved(MaterialId, &'static str),
+    /// `MaterialId::BEDROCK` is not indestructible.
+    DestructibleBedrock,
+}
+
+impl fmt::Display for MaterialError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MaterialError::Io(ref e) => write!(f, "i/o error: {}", e),
+            MaterialError::Parse(ref e) => write!(f, "invalid material file: {}", e),
+            MaterialError::DuplicateId(id) => write!(f, "material id {} used twice", id.0),
+            MaterialError::DuplicateName(ref n) => write!(f, "material {} defined twice", n),
+            MaterialError::MissingAir => write!(f, "no material with id 0"),
+            MaterialError::Reserved(id, name) => {
+                write!(f, "material id {} is reserved for {}", id.0, name)
+            }
+            MaterialError::DestructibleBedrock => write!(f, "bedrock is not indestructible"),
+        }
+    }
+}
+
+impl Error for MaterialError {
+    fn description(&self) -> &str {
+        match *self {
+            MaterialError::Io(_) => "i/o error",
+            MaterialError::Parse(_) => "invalid material file",
+            MaterialError::DuplicateId(_) => "material id used twice",
+            MaterialError::DuplicateName(_) => "material defined twice",
+            MaterialError::MissingAir => "no material with id 0",
+            MaterialError::Reserved(..) => "material id is reserved",
+            MaterialError::DestructibleBedrock => "bedrock is not indestructible",
+        }
+    }
+}
+
+impl From<io::Error> for MaterialError {
+    fn from(e: io::Error) -> Self {
+        MaterialError::Io(e)
+    }
+}
+
+impl From<ron::de::Error> for MaterialError {
+    fn from(e: ron::de::Error) -> Self {
+        MaterialError::Parse(e)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MaterialFile {
+    materials: Vec<MaterialDef>,
+}
+
+/// Every known material, looked up by id or name. Available to systems as a
+/// resource.
+#[derive(Debug, Clone)]
+pub struct MaterialRegistry {
+    defs: Vec<Option<MaterialDef>>,
+    names: HashMap<String, MaterialId>,
+}
+
+impl MaterialRegistry {
+    pub fn from_defs(defs: Vec<MaterialDef>) -> Result<Self, MaterialError> {
+        let mut registry = MaterialRegistry {
+            defs: vec![None; 256],
+            names: HashMap::new(),
+        };
+        for def in defs {
+            let i = def.id.0 as usize;
+            if registry.defs[i].is_some() {
+                return Err(MaterialError::DuplicateId(def.id));
+            }
+            if registry.names.contains_key(&def.name) {
+                return Err(MaterialError::DuplicateName(def.name));
+            }
+            registry.names.insert(def.name.clone(), def.id);
+            registry.defs[i] = Some(def);
+        }
+        if registry.defs[MaterialId::AIR.0 as usize].is_none() {
+            return Err(MaterialError::MissingAir);
+        }
+        for &(id, name) in &RESERVED {
+            if registry.id(name) != Some(id) {
+                return Err(MaterialError::Reserved(id, name));
+            }
+        }
+        if !registry.is_indestructible(MaterialId::BEDROCK) {
+            return Err(MaterialError::DestructibleBedrock);
+        }
+        Ok(registry)
+    }
+
+    pub fn from_ron(s: &str) -> Result<Self, MaterialError> {
+        let file: MaterialFile = ron::de::from_str(s)?;
+        MaterialRegistry::from_defs(file.materials)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, MaterialError> {
+        let mut s = String::new();
+        File::open(path)?.read_to_string(&mut s)?;
+        MaterialRegistry::from_ron(&s)
+    }
+
+    /// The materials shipped in resources/materials.ron.
+    pub fn builtin() -> Self {
+        MaterialRegistry::from_ron(include_str!("../resources/materials.ron"))
+            .expect("invalid built-in materials")
+    }
+
+    #[inline]
+    pub fn get(&self, id: MaterialId) -> Option<&MaterialDef> {
+        self.defs[id.0 as usize].as_ref()
+    }
+
+    #[inline]
+    pub fn id(&self, name: &str) -> Option<MaterialId> {
+        self.names.get(name).cloned()
+    }
+
+    /// True if `id` is known and indestructible.
+    #[inline]
+    pub fn is_indestructible(&self, id: MaterialId) -> bool {
+        match self.get(id) {
+            Some(def) => def.indestructible,
+            None => false,
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, id: MaterialId) -> bool {
+        self.defs[id.0 as usize].is_some()
+    }
+
+    /// Every material, ordered by id.
+    pub fn iter<'a>(&'a self) -> Materials<'a> {
+        Materials {
+            defs: self.defs.iter(),
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+}
+
+pub struct Materials<'a> {
+    defs: slice::Iter<'a, Option<MaterialDef>>,
+}
+
+impl<'a> Iterator for Materials<'a> {
+    type Item = &'a MaterialDef;
+
+    fn next(&mut self) -> Option<&'a MaterialDef> {
+        for d in self.defs.by_ref() {
+            if let Some(ref def) = *d {
+                return Some(def);
+            }
+        }
+        None
+    }
+}
+
+impl Default for MaterialRegistry {
+    fn default() -> Self {
+        MaterialRegistry::builtin()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_materials() {
+        let registry = MaterialRegistry::builtin();
+        assert_eq!(registry.id("air"), Some(MaterialId::AIR));
+        assert_eq!(registry.id("grass"), Some(MaterialId::GRASS));
+        assert_eq!(registry.id("snow"), Some(MaterialId::SNOW));
+        assert_eq!(registry.id("water"), Some(MaterialId::WATER));
+        assert_eq!(registry.id("ice"), Some(MaterialId::ICE));
+        assert_eq!(registry.id("rock"), Some(MaterialId::ROCK));
+        assert_eq!(registry.id("bedrock"), Some(MaterialId::BEDROCK));
+        assert!(registry.get(MaterialId::BEDROCK).unwrap().indestructible);
+        assert!(!registry.get(MaterialId::ROCK).unwrap().indestructible);
+        assert!(registry.get(MaterialId::SNOW).unwrap().holds_snow);
+        assert!(registry.get(MaterialId::GRASS).unwrap().talus_angle < 90.0);
+        assert_eq!(registry.iter().count(), registry.len());
+    }
+
+    #[test]
+    fn registry_rejects_invalid() {
+        let def = |id, name: &str| MaterialDef {
+            id: MaterialId(id),
+            name: name.to_owned(),
+            colour: [0.0; 3],
+            density: 1.0,
+            friction: 0.0,
+            thermal_conductivity: 0.0,
+            albedo: 0.0,
+            holds_snow: false,
+            talus_angle: 90.0,
+            hardness: 1.0,
+            indestructible: false,
+        };
+        match MaterialRegistry::from_defs(vec![def(0, "air"), def(7, "mud"), def(7, "clay")]) {
+            Err(MaterialError::DuplicateId(MaterialId(7))) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        match MaterialRegistry::from_defs(vec![def(0, "air"), def(1, "air")]) {
+            Err(MaterialError::DuplicateName(_)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        match MaterialRegistry::from_defs(vec![def(1, "mud")]) {
+            Err(MaterialError::MissingAir) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+
+        let mut defs = vec![def(0, "air")];
+        defs.extend(RESERVED.iter().map(|&(id, name)| def(id.0, name)));
+        defs[MaterialId::BEDROCK.0 as usize].indestructible = true;
+        assert!(MaterialRegistry::from_defs(defs.clone()).is_ok());
+        defs[MaterialId::BEDROCK.0 as usize].indestructible = false;
+        match MaterialRegistry::from_defs(defs.clone()) {
+            Err(MaterialError::DestructibleBedrock) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        defs[MaterialId::ICE.0 as usize].name = "slush".to_owned();
+        match MaterialRegistry::from_defs(defs.clone()) {
+            Err(MaterialError::Reserved(MaterialId::ICE, "ice")) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        defs.remove(MaterialId::ICE.0 as usize);
+        match MaterialRegistry::from_defs(defs) {
+            Err(MaterialError::Reserved(MaterialId::ICE, "ice")) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        match MaterialRegistry::from_ron("(materials: [(id: 0)])") {
+            Err(MaterialError::Parse(_)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+}
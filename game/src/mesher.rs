@@ -0,0 +1,685 @@
+use std::ops::Range;
+
+use amethyst::renderer::PosNormTex;
+use cgmath::{InnerSpace, Vector3};
+
+use voxel_grid::{Chunk, ISO_LEVEL};
+
+/// Corner offsets of a marching cube, corner `i` is at `(i & 1, (i >> 1) & 1, (i >> 2) & 1)`.
+const CORNERS: [[i32; 3]; 8] = [
+    [0, 0, 0],
+    [1, 0, 0],
+    [0, 1, 0],
+    [1, 1, 0],
+    [0, 0, 1],
+    [1, 0, 1],
+    [0, 1, 1],
+    [1, 1, 1],
+];
+
+/// Pairs of corners joined by each of the 12 cube edges.
+const EDGES: [[usize; 2]; 12] = [
+    [0, 1],
+    [2, 3],
+    [4, 5],
+    [6, 7],
+    [0, 2],
+    [1, 3],
+    [4, 6],
+    [5, 7],
+    [0, 4],
+    [1, 5],
+    [2, 6],
+    [3, 7],
+];
+
+/// Occupancy samples of a chunk, padded with one voxel on the negative side
+/// and two on the positive side so every cube owned by the chunk and every
+/// central difference can be evaluated without going back to the grid.
+#[derive(Debug, Clone)]
+pub struct OccupancyVolume {
+    dimension: i32,
+    samples: Vec<f32>,
+}
+
+impl OccupancyVolume {
+    /// Build a volume by calling `f` for every padded coordinate in
+    /// `-1..dim + 2` along each axis.
+    pub fn from_fn<F>(dim: u16, f: F) -> Self
+    where
+        F: Fn(i32, i32, i32) -> f32,
+    {
+        let dimension = i32::from(dim);
+        let side = dimension + 3;
+        let mut samples = Vec::with_capacity((side * side * side) as usize);
+        for z in -1..dimension + 2 {
+            for y in -1..dimension + 2 {
+                for x in -1..dimension + 2 {
+                    samples.push(f(x, y, z));
+                }
+            }
+        }
+        OccupancyVolume { dimension, samples }
+    }
+
+    /// Gather the samples for `chunk`, reading across borders from
+    /// `neighbors` (in `VoxelGrid::neighbors` order). Missing neighbours
+    /// repeat the chunk's own border so the surface stays open there.
+    pub fn from_chunk(chunk: &Chunk, neighbors: &[Option<&Chunk>; 6]) -> Self {
+        let dim = i32::from(chunk.dimension());
+        let present = present(neighbors);
+        OccupancyVolume::from_fn(chunk.dimension(), |x, y, z| {
+            let (slot, p) = resolve(dim, &present, [x, y, z]);
+            let source = match slot {
+                Some(s) => neighbors[s].unwrap_or(chunk),
+                None => chunk,
+            };
+            occupancy(source, p)
+        })
+    }
+
+    /// Same samples as `from_chunk` for the chunk `snapshot` was taken of.
+    pub fn from_snapshot(snapshot: &ChunkSnapshot) -> Self {
+        let chunk = &snapshot.chunk;
+        let dim = i32::from(chunk.dimension());
+        let mut present = [false; 6];
+        for (p, b) in present.iter_mut().zip(snapshot.borders.iter()) {
+            *p = b.is_some();
+        }
+        OccupancyVolume::from_fn(chunk.dimension(), |x, y, z| {
+            let (slot, p) = resolve(dim, &present, [x, y, z]);
+            match slot {
+                Some(s) => snapshot.border_at(s, p),
+                None => occupancy(chunk, p),
+            }
+        })
+    }
+
+    #[inline]
+    pub fn dimension(&self) -> i32 {
+        self.dimension
+    }
+
+    #[inline]
+    pub fn at(&self, x: i32, y: i32, z: i32) -> f32 {
+        let side = self.dimension + 3;
+        self.samples[((x + 1) + side * ((y + 1) + side * (z + 1))) as usize]
+    }
+
+    /// Occupancy gradient by central differences. Valid for `0..=dim`.
+    #[inline]
+    fn gradient(&self, x: i32, y: i32, z: i32) -> Vector3<f32> {
+        Vector3::new(
+            self.at(x + 1, y, z) - self.at(x - 1, y, z),
+            self.at(x, y + 1, z) - self.at(x, y - 1, z),
+            self.at(x, y, z + 1) - self.at(x, y, z - 1),
+        ) * 0.5
+    }
+}
+
+/// Copy of a chunk and of the neighbouring voxels `OccupancyVolume` reads
+/// across its faces, so the chunk can be meshed away from the grid.
+#[derive(Debug, Clone)]
+pub struct ChunkSnapshot {
+    chunk: Chunk,
+    /// Occupancy of the neighbour layers next to each face, in
+    /// `VoxelGrid::neighbors` order. See `border_layers`.
+    borders: [Option<Vec<f32>>; 6],
+}
+
+impl ChunkSnapshot {
+    pub fn new(chunk: &Chunk, neighbors: &[Option<&Chunk>; 6]) -> Self {
+        let mut borders: [Option<Vec<f32>>; 6] = Default::default();
+        for (slot, n) in neighbors.iter().enumerate() {
+            borders[slot] = n.map(|n| copy_border(n, slot));
+        }
+        ChunkSnapshot {
+            chunk: chunk.clone(),
+            borders,
+        }
+    }
+
+    #[inline]
+    pub fn chunk(&self) -> &Chunk {
+        &self.chunk
+    }
+
+    /// Occupancy at `p` in the neighbour across face `slot`.
+    #[inline]
+    fn border_at(&self, slot: usize, p: [i32; 3]) -> f32 {
+        let d = i32::from(self.chunk.dimension());
+        let axis = slot / 2;
+        let layer = p[axis] - i32::from(border_layers(self.chunk.dimension(), slot).start);
+        let (u, v) = (p[(axis + 1) % 3], p[(axis + 2) % 3]);
+        match self.borders[slot] {
+            Some(ref b) => b[(u + d * (v + d * layer)) as usize],
+            None => 0.0,
+        }
+    }
+}
+
+/// Layers of the neighbour across face `slot` that a chunk samples: the
+/// first two of the neighbour on the positive side of an axis, the last one
+/// on the negative side.
+#[inline]
+fn border_layers(dim: u16, slot: usize) -> Range<u16> {
+    if slot % 2 == 0 {
+        0..dim.min(2)
+    } else {
+        dim - 1..dim
+    }
+}
+
+fn copy_border(n: &Chunk, slot: usize) -> Vec<f32> {
+    let dim = n.dimension();
+    let axis = slot / 2;
+    let layers = border_layers(dim, slot);
+    let mut border = Vec::with_capacity(layers.len() * dim as usize * dim as usize);
+    for layer in layers {
+        for v in 0..dim {
+            for u in 0..dim {
+                let mut p = [0; 3];
+                p[axis] = layer;
+                p[(axis + 1) % 3] = u;
+                p[(axis + 2) % 3] = v;
+                border.push(occupancy(
+                    n,
+                    [i32::from(p[0]), i32::from(p[1]), i32::from(p[2])],
+                ));
+            }
+        }
+    }
+    border
+}
+
+fn present(neighbors: &[Option<&Chunk>; 6]) -> [bool; 6] {
+    let mut present = [false; 6];
+    for (p, n) in present.iter_mut().zip(neighbors.iter()) {
+        *p = n.is_some();
+    }
+    present
+}
+
+#[inline]
+fn occupancy(chunk: &Chunk, p: [i32; 3]) -> f32 {
+    chunk
+        .get_voxel_at(Vector3::new(p[0] as u16, p[1] as u16, p[2] as u16))
+        .get_occupancy_as_f32()
+}
+
+/// Where to read a chunk-local coordinate that may lie just outside the
+/// chunk: the face neighbour it crosses into, or `None` for the chunk
+/// itself, and the coordinate inside that chunk. Coordinates that leave the
+/// chunk along more than one axis are clamped into the first face neighbour,
+/// since diagonal chunks are not available.
+fn resolve(dim: i32, present: &[bool; 6], mut p: [i32; 3]) -> (Option<usize>, [i32; 3]) {
+    let mut source = None;
+    let mut crossed = false;
+
+    for axis in 0..3 {
+        if p[axis] >= 0 && p[axis] < dim {
+            continue;
+        }
+        if !crossed {
+            crossed = true;
+            let (slot, wrapped) = if p[axis] < 0 {
+                (axis * 2 + 1, p[axis] + dim)
+            } else {
+                (axis * 2, p[axis] - dim)
+            };
+            if present[slot] {
+                source = Some(slot);
+                p[axis] = wrapped;
+                continue;
+            }
+        }
+        p[axis] = p[axis].max(0).min(dim - 1);
+    }
+
+    (source, p)
+}
+
+/// Extract the iso-surface of `volume` as a triangle list in chunk-local
+/// space. Voxel `i` covers `[i, i + 1) * voxel_size` and is sampled at its
+/// centre. Triangles wind counter-clockwise seen from the air side.
+pub fn polygonise(volume: &OccupancyVolume, voxel_size: f32) -> Vec<PosNormTex> {
+    let dim = volume.dimension();
+    let mut vertices = Vec::new();
+
+    for z in 0..dim {
+        for y in 0..dim {
+            for x in 0..dim {
+                let mut values = [0.0; 8];
+                let mut case = 0;
+                for (i, c) in CORNERS.iter().enumerate() {
+                    values[i] = volume.at(x + c[0], y + c[1], z + c[2]);
+                    if values[i] >= ISO_LEVEL {
+                        case |= 1 << i;
+                    }
+                }
+                if case == 0 || case == 0xff {
+                    continue;
+                }
+
+                for &edge in TRIANGLES[case].iter().take_while(|&&e| e >= 0) {
+                    let (a, b) = (EDGES[edge as usize][0], EDGES[edge as usize][1]);
+                    let (ca, cb) = (CORNERS[a], CORNERS[b]);
+                    let t = {
+                        let d = values[b] - values[a];
+                        if d.abs() < ::std::f32::EPSILON {
+                            0.5
+                        } else {
+                            (ISO_LEVEL - values[a]) / d
+                        }
+                    };
+
+                    let pa = Vector3::new(x + ca[0], y + ca[1], z + ca[2]);
+                    let pb = Vector3::new(x + cb[0], y + cb[1], z + cb[2]);
+                    let position = Vector3::new(
+                        pa.x as f32 + (pb.x - pa.x) as f32 * t + 0.5,
+                        pa.y as f32 + (pb.y - pa.y) as f32 * t + 0.5,
+                        pa.z as f32 + (pb.z - pa.z) as f32 * t + 0.5,
+                    ) * voxel_size;
+
+                    // Occupancy grows into the ground, so the surface faces
+                    // down the gradient.
+                    let gradient = volume.gradient(pa.x, pa.y, pa.z) * (1.0 - t)
+                        + volume.gradient(pb.x, pb.y, pb.z) * t;
+                    let normal = if gradient.magnitude2() > 0.0 {
+                        -gradient.normalize()
+                    } else {
+                        Vector3::unit_y()
+                    };
+
+                    vertices.push(PosNormTex {
+                        position: position.into(),
+                        normal: normal.into(),
+                        tex_coord: [
+                            position.x / (dim as f32 * voxel_size),
+                            position.z / (dim as f32 * voxel_size),
+                        ],
+                    });
+                }
+            }
+        }
+    }
+
+    vertices
+}
+
+/// Mesh a chunk of the grid, see `OccupancyVolume::from_chunk`.
+pub fn mesh_chunk(
+    chunk: &Chunk,
+    neighbors: &[Option<&Chunk>; 6],
+    voxel_size: f32,
+) -> Vec<PosNormTex> {
+    polygonise(&OccupancyVolume::from_chunk(chunk, neighbors), voxel_size)
+}
+
+/// Mesh a chunk from a snapshot, e.g. on a worker thread.
+pub fn mesh_snapshot(snapshot: &ChunkSnapshot, voxel_size: f32) -> Vec<PosNormTex> {
+    polygonise(&OccupancyVolume::from_snapshot(snapshot), voxel_size)
+}
+
+/// Edges cut by the surface for each of the 256 corner configurations, three
+/// per triangle and terminated by -1. Ambiguous faces always separate the
+/// solid corners, which keeps neighbouring cubes (and chunks) crack free.
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const TRIANGLES: [[i8; 16]; 256] = [
+    [-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 0, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 5, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 9, 4, 9, 5, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [1, 10, 4, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [1, 10, 8, 1, 8, 0, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 5, 1, 10, 4, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [1, 10, 8, 1, 8, 9, 1, 9, 5, -1, -1, -1, -1, -1, -1, -1],
+    [5, 11, 1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 0, 5, 11, 1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 11, 0, 11, 1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 9, 4, 9, 11, 4, 11, 1, -1, -1, -1, -1, -1, -1, -1],
+    [5, 11, 10, 5, 10, 4, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [5, 11, 10, 5, 10, 8, 5, 8, 0, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 11, 0, 11, 10, 0, 10, 4, -1, -1, -1, -1, -1, -1, -1],
+    [9, 11, 10, 9, 10, 8, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [2, 8, 6, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 6, 2, 4, 2, 0, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 5, 2, 8, 6, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [2, 9, 5, 2, 5, 4, 2, 4, 6, -1, -1, -1, -1, -1, -1, -1],
+    [1, 10, 4, 2, 8, 6, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [1, 10, 6, 1, 6, 2, 1, 2, 0, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 5, 1, 10, 4, 2, 8, 6, -1, -1, -1, -1, -1, -1, -1],
+    [1, 10, 6, 1, 6, 2, 1, 2, 9, 1, 9, 5, -1, -1, -1, -1],
+    [5, 11, 1, 2, 8, 6, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 6, 2, 4, 2, 0, 5, 11, 1, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 11, 0, 11, 1, 2, 8, 6, -1, -1, -1, -1, -1, -1, -1],
+    [4, 6, 2, 4, 2, 9, 4, 9, 11, 4, 11, 1, -1, -1, -1, -1],
+    [2, 8, 6, 5, 11, 10, 5, 10, 4, -1, -1, -1, -1, -1, -1, -1],
+    [5, 11, 10, 5, 10, 6, 5, 6, 2, 5, 2, 0, -1, -1, -1, -1],
+    [0, 9, 11, 0, 11, 10, 0, 10, 4, 2, 8, 6, -1, -1, -1, -1],
+    [2, 9, 11, 2, 11, 10, 2, 10, 6, -1, -1, -1, -1, -1, -1, -1],
+    [7, 9, 2, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 0, 7, 9, 2, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [0, 2, 7, 0, 7, 5, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [7, 5, 4, 7, 4, 8, 7, 8, 2, -1, -1, -1, -1, -1, -1, -1],
+    [1, 10, 4, 7, 9, 2, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [1, 10, 8, 1, 8, 0, 7, 9, 2, -1, -1, -1, -1, -1, -1, -1],
+    [0, 2, 7, 0, 7, 5, 1, 10, 4, -1, -1, -1, -1, -1, -1, -1],
+    [1, 10, 8, 1, 8, 2, 1, 2, 7, 1, 7, 5, -1, -1, -1, -1],
+    [5, 11, 1, 7, 9, 2, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 0, 5, 11, 1, 7, 9, 2, -1, -1, -1, -1, -1, -1, -1],
+    [0, 2, 7, 0, 7, 11, 0, 11, 1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 2, 4, 2, 7, 4, 7, 11, 4, 11, 1, -1, -1, -1, -1],
+    [7, 9, 2, 5, 11, 10, 5, 10, 4, -1, -1, -1, -1, -1, -1, -1],
+    [5, 11, 10, 5, 10, 8, 5, 8, 0, 7, 9, 2, -1, -1, -1, -1],
+    [0, 2, 7, 0, 7, 11, 0, 11, 10, 0, 10, 4, -1, -1, -1, -1],
+    [7, 11, 10, 7, 10, 8, 7, 8, 2, -1, -1, -1, -1, -1, -1, -1],
+    [7, 9, 8, 7, 8, 6, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 6, 7, 4, 7, 9, 4, 9, 0, -1, -1, -1, -1, -1, -1, -1],
+    [0, 8, 6, 0, 6, 7, 0, 7, 5, -1, -1, -1, -1, -1, -1, -1],
+    [4, 6, 7, 4, 7, 5, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [1, 10, 4, 7, 9, 8, 7, 8, 6, -1, -1, -1, -1, -1, -1, -1],
+    [1, 10, 6, 1, 6, 7, 1, 7, 9, 1, 9, 0, -1, -1, -1, -1],
+    [0, 8, 6, 0, 6, 7, 0, 7, 5, 1, 10, 4, -1, -1, -1, -1],
+    [1, 10, 6, 1, 6, 7, 1, 7, 5, -1, -1, -1, -1, -1, -1, -1],
+    [5, 11, 1, 7, 9, 8, 7, 8, 6, -1, -1, -1, -1, -1, -1, -1],
+    [4, 6, 7, 4, 7, 9, 4, 9, 0, 5, 11, 1, -1, -1, -1, -1],
+    [0, 8, 6, 0, 6, 7, 0, 7, 11, 0, 11, 1, -1, -1, -1, -1],
+    [4, 6, 7, 4, 7, 11, 4, 11, 1, -1, -1, -1, -1, -1, -1, -1],
+    [5, 11, 10, 5, 10, 4, 7, 9, 8, 7, 8, 6, -1, -1, -1, -1],
+    [5, 11, 10, 5, 10, 6, 5, 6, 7, 5, 7, 9, 5, 9, 0, -1],
+    [0, 8, 6, 0, 6, 7, 0, 7, 11, 0, 11, 10, 0, 10, 4, -1],
+    [7, 11, 10, 7, 10, 6, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [6, 10, 3, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 0, 6, 10, 3, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 5, 6, 10, 3, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [6, 10, 3, 4, 8, 9, 4, 9, 5, -1, -1, -1, -1, -1, -1, -1],
+    [1, 3, 6, 1, 6, 4, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [1, 3, 6, 1, 6, 8, 1, 8, 0, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 5, 1, 3, 6, 1, 6, 4, -1, -1, -1, -1, -1, -1, -1],
+    [1, 3, 6, 1, 6, 8, 1, 8, 9, 1, 9, 5, -1, -1, -1, -1],
+    [5, 11, 1, 6, 10, 3, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 0, 5, 11, 1, 6, 10, 3, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 11, 0, 11, 1, 6, 10, 3, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 9, 4, 9, 11, 4, 11, 1, 6, 10, 3, -1, -1, -1, -1],
+    [6, 4, 5, 6, 5, 11, 6, 11, 3, -1, -1, -1, -1, -1, -1, -1],
+    [5, 11, 3, 5, 3, 6, 5, 6, 8, 5, 8, 0, -1, -1, -1, -1],
+    [0, 9, 11, 0, 11, 3, 0, 3, 6, 0, 6, 4, -1, -1, -1, -1],
+    [6, 8, 9, 6, 9, 11, 6, 11, 3, -1, -1, -1, -1, -1, -1, -1],
+    [2, 8, 10, 2, 10, 3, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 10, 3, 4, 3, 2, 4, 2, 0, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 5, 2, 8, 10, 2, 10, 3, -1, -1, -1, -1, -1, -1, -1],
+    [2, 9, 5, 2, 5, 4, 2, 4, 10, 2, 10, 3, -1, -1, -1, -1],
+    [1, 3, 2, 1, 2, 8, 1, 8, 4, -1, -1, -1, -1, -1, -1, -1],
+    [1, 3, 2, 1, 2, 0, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 5, 1, 3, 2, 1, 2, 8, 1, 8, 4, -1, -1, -1, -1],
+    [1, 3, 2, 1, 2, 9, 1, 9, 5, -1, -1, -1, -1, -1, -1, -1],
+    [5, 11, 1, 2, 8, 10, 2, 10, 3, -1, -1, -1, -1, -1, -1, -1],
+    [4, 10, 3, 4, 3, 2, 4, 2, 0, 5, 11, 1, -1, -1, -1, -1],
+    [0, 9, 11, 0, 11, 1, 2, 8, 10, 2, 10, 3, -1, -1, -1, -1],
+    [4, 10, 3, 4, 3, 2, 4, 2, 9, 4, 9, 11, 4, 11, 1, -1],
+    [2, 8, 4, 2, 4, 5, 2, 5, 11, 2, 11, 3, -1, -1, -1, -1],
+    [5, 11, 3, 5, 3, 2, 5, 2, 0, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 11, 0, 11, 3, 0, 3, 2, 0, 2, 8, 0, 8, 4, -1],
+    [2, 9, 11, 2, 11, 3, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [7, 9, 2, 6, 10, 3, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 0, 7, 9, 2, 6, 10, 3, -1, -1, -1, -1, -1, -1, -1],
+    [0, 2, 7, 0, 7, 5, 6, 10, 3, -1, -1, -1, -1, -1, -1, -1],
+    [7, 5, 4, 7, 4, 8, 7, 8, 2, 6, 10, 3, -1, -1, -1, -1],
+    [1, 3, 6, 1, 6, 4, 7, 9, 2, -1, -1, -1, -1, -1, -1, -1],
+    [1, 3, 6, 1, 6, 8, 1, 8, 0, 7, 9, 2, -1, -1, -1, -1],
+    [0, 2, 7, 0, 7, 5, 1, 3, 6, 1, 6, 4, -1, -1, -1, -1],
+    [1, 3, 6, 1, 6, 8, 1, 8, 2, 1, 2, 7, 1, 7, 5, -1],
+    [5, 11, 1, 7, 9, 2, 6, 10, 3, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 0, 5, 11, 1, 7, 9, 2, 6, 10, 3, -1, -1, -1, -1],
+    [0, 2, 7, 0, 7, 11, 0, 11, 1, 6, 10, 3, -1, -1, -1, -1],
+    [4, 8, 2, 4, 2, 7, 4, 7, 11, 4, 11, 1, 6, 10, 3, -1],
+    [7, 9, 2, 6, 4, 5, 6, 5, 11, 6, 11, 3, -1, -1, -1, -1],
+    [5, 11, 3, 5, 3, 6, 5, 6, 8, 5, 8, 0, 7, 9, 2, -1],
+    [0, 2, 7, 0, 7, 11, 0, 11, 3, 0, 3, 6, 0, 6, 4, -1],
+    [7, 11, 3, 7, 3, 6, 7, 6, 8, 7, 8, 2, -1, -1, -1, -1],
+    [7, 9, 8, 7, 8, 10, 7, 10, 3, -1, -1, -1, -1, -1, -1, -1],
+    [4, 10, 3, 4, 3, 7, 4, 7, 9, 4, 9, 0, -1, -1, -1, -1],
+    [0, 8, 10, 0, 10, 3, 0, 3, 7, 0, 7, 5, -1, -1, -1, -1],
+    [7, 5, 4, 7, 4, 10, 7, 10, 3, -1, -1, -1, -1, -1, -1, -1],
+    [1, 3, 7, 1, 7, 9, 1, 9, 8, 1, 8, 4, -1, -1, -1, -1],
+    [1, 3, 7, 1, 7, 9, 1, 9, 0, -1, -1, -1, -1, -1, -1, -1],
+    [0, 8, 4, 0, 4, 1, 0, 1, 3, 0, 3, 7, 0, 7, 5, -1],
+    [1, 3, 7, 1, 7, 5, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [5, 11, 1, 7, 9, 8, 7, 8, 10, 7, 10, 3, -1, -1, -1, -1],
+    [4, 10, 3, 4, 3, 7, 4, 7, 9, 4, 9, 0, 5, 11, 1, -1],
+    [0, 8, 10, 0, 10, 3, 0, 3, 7, 0, 7, 11, 0, 11, 1, -1],
+    [4, 10, 3, 4, 3, 7, 4, 7, 11, 4, 11, 1, -1, -1, -1, -1],
+    [7, 9, 8, 7, 8, 4, 7, 4, 5, 7, 5, 11, 7, 11, 3, -1],
+    [5, 11, 3, 5, 3, 7, 5, 7, 9, 5, 9, 0, -1, -1, -1, -1],
+    [0, 8, 4, 7, 11, 3, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [7, 11, 3, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [3, 11, 7, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 0, 3, 11, 7, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 5, 3, 11, 7, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [3, 11, 7, 4, 8, 9, 4, 9, 5, -1, -1, -1, -1, -1, -1, -1],
+    [1, 10, 4, 3, 11, 7, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [1, 10, 8, 1, 8, 0, 3, 11, 7, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 5, 1, 10, 4, 3, 11, 7, -1, -1, -1, -1, -1, -1, -1],
+    [1, 10, 8, 1, 8, 9, 1, 9, 5, 3, 11, 7, -1, -1, -1, -1],
+    [5, 7, 3, 5, 3, 1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 0, 5, 7, 3, 5, 3, 1, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 7, 0, 7, 3, 0, 3, 1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 9, 4, 9, 7, 4, 7, 3, 4, 3, 1, -1, -1, -1, -1],
+    [3, 10, 4, 3, 4, 5, 3, 5, 7, -1, -1, -1, -1, -1, -1, -1],
+    [5, 7, 3, 5, 3, 10, 5, 10, 8, 5, 8, 0, -1, -1, -1, -1],
+    [0, 9, 7, 0, 7, 3, 0, 3, 10, 0, 10, 4, -1, -1, -1, -1],
+    [3, 10, 8, 3, 8, 9, 3, 9, 7, -1, -1, -1, -1, -1, -1, -1],
+    [2, 8, 6, 3, 11, 7, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 6, 2, 4, 2, 0, 3, 11, 7, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 5, 2, 8, 6, 3, 11, 7, -1, -1, -1, -1, -1, -1, -1],
+    [2, 9, 5, 2, 5, 4, 2, 4, 6, 3, 11, 7, -1, -1, -1, -1],
+    [1, 10, 4, 2, 8, 6, 3, 11, 7, -1, -1, -1, -1, -1, -1, -1],
+    [1, 10, 6, 1, 6, 2, 1, 2, 0, 3, 11, 7, -1, -1, -1, -1],
+    [0, 9, 5, 1, 10, 4, 2, 8, 6, 3, 11, 7, -1, -1, -1, -1],
+    [1, 10, 6, 1, 6, 2, 1, 2, 9, 1, 9, 5, 3, 11, 7, -1],
+    [5, 7, 3, 5, 3, 1, 2, 8, 6, -1, -1, -1, -1, -1, -1, -1],
+    [4, 6, 2, 4, 2, 0, 5, 7, 3, 5, 3, 1, -1, -1, -1, -1],
+    [0, 9, 7, 0, 7, 3, 0, 3, 1, 2, 8, 6, -1, -1, -1, -1],
+    [4, 6, 2, 4, 2, 9, 4, 9, 7, 4, 7, 3, 4, 3, 1, -1],
+    [2, 8, 6, 3, 10, 4, 3, 4, 5, 3, 5, 7, -1, -1, -1, -1],
+    [5, 7, 3, 5, 3, 10, 5, 10, 6, 5, 6, 2, 5, 2, 0, -1],
+    [0, 9, 7, 0, 7, 3, 0, 3, 10, 0, 10, 4, 2, 8, 6, -1],
+    [2, 9, 7, 2, 7, 3, 2, 3, 10, 2, 10, 6, -1, -1, -1, -1],
+    [3, 11, 9, 3, 9, 2, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 0, 3, 11, 9, 3, 9, 2, -1, -1, -1, -1, -1, -1, -1],
+    [0, 2, 3, 0, 3, 11, 0, 11, 5, -1, -1, -1, -1, -1, -1, -1],
+    [3, 11, 5, 3, 5, 4, 3, 4, 8, 3, 8, 2, -1, -1, -1, -1],
+    [1, 10, 4, 3, 11, 9, 3, 9, 2, -1, -1, -1, -1, -1, -1, -1],
+    [1, 10, 8, 1, 8, 0, 3, 11, 9, 3, 9, 2, -1, -1, -1, -1],
+    [0, 2, 3, 0, 3, 11, 0, 11, 5, 1, 10, 4, -1, -1, -1, -1],
+    [1, 10, 8, 1, 8, 2, 1, 2, 3, 1, 3, 11, 1, 11, 5, -1],
+    [5, 9, 2, 5, 2, 3, 5, 3, 1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 0, 5, 9, 2, 5, 2, 3, 5, 3, 1, -1, -1, -1, -1],
+    [0, 2, 3, 0, 3, 1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 2, 4, 2, 3, 4, 3, 1, -1, -1, -1, -1, -1, -1, -1],
+    [3, 10, 4, 3, 4, 5, 3, 5, 9, 3, 9, 2, -1, -1, -1, -1],
+    [5, 9, 2, 5, 2, 3, 5, 3, 10, 5, 10, 8, 5, 8, 0, -1],
+    [0, 2, 3, 0, 3, 10, 0, 10, 4, -1, -1, -1, -1, -1, -1, -1],
+    [3, 10, 8, 3, 8, 2, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [3, 11, 9, 3, 9, 8, 3, 8, 6, -1, -1, -1, -1, -1, -1, -1],
+    [4, 6, 3, 4, 3, 11, 4, 11, 9, 4, 9, 0, -1, -1, -1, -1],
+    [0, 8, 6, 0, 6, 3, 0, 3, 11, 0, 11, 5, -1, -1, -1, -1],
+    [3, 11, 5, 3, 5, 4, 3, 4, 6, -1, -1, -1, -1, -1, -1, -1],
+    [1, 10, 4, 3, 11, 9, 3, 9, 8, 3, 8, 6, -1, -1, -1, -1],
+    [1, 10, 6, 1, 6, 3, 1, 3, 11, 1, 11, 9, 1, 9, 0, -1],
+    [0, 8, 6, 0, 6, 3, 0, 3, 11, 0, 11, 5, 1, 10, 4, -1],
+    [1, 10, 6, 1, 6, 3, 1, 3, 11, 1, 11, 5, -1, -1, -1, -1],
+    [5, 9, 8, 5, 8, 6, 5, 6, 3, 5, 3, 1, -1, -1, -1, -1],
+    [4, 6, 3, 4, 3, 1, 4, 1, 5, 4, 5, 9, 4, 9, 0, -1],
+    [0, 8, 6, 0, 6, 3, 0, 3, 1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 6, 3, 4, 3, 1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [3, 10, 4, 3, 4, 5, 3, 5, 9, 3, 9, 8, 3, 8, 6, -1],
+    [5, 9, 0, 3, 10, 6, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [0, 8, 6, 0, 6, 3, 0, 3, 10, 0, 10, 4, -1, -1, -1, -1],
+    [3, 10, 6, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [6, 10, 11, 6, 11, 7, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 0, 6, 10, 11, 6, 11, 7, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 5, 6, 10, 11, 6, 11, 7, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 9, 4, 9, 5, 6, 10, 11, 6, 11, 7, -1, -1, -1, -1],
+    [1, 11, 7, 1, 7, 6, 1, 6, 4, -1, -1, -1, -1, -1, -1, -1],
+    [1, 11, 7, 1, 7, 6, 1, 6, 8, 1, 8, 0, -1, -1, -1, -1],
+    [0, 9, 5, 1, 11, 7, 1, 7, 6, 1, 6, 4, -1, -1, -1, -1],
+    [1, 11, 7, 1, 7, 6, 1, 6, 8, 1, 8, 9, 1, 9, 5, -1],
+    [5, 7, 6, 5, 6, 10, 5, 10, 1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 0, 5, 7, 6, 5, 6, 10, 5, 10, 1, -1, -1, -1, -1],
+    [0, 9, 7, 0, 7, 6, 0, 6, 10, 0, 10, 1, -1, -1, -1, -1],
+    [4, 8, 9, 4, 9, 7, 4, 7, 6, 4, 6, 10, 4, 10, 1, -1],
+    [5, 7, 6, 5, 6, 4, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [5, 7, 6, 5, 6, 8, 5, 8, 0, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 7, 0, 7, 6, 0, 6, 4, -1, -1, -1, -1, -1, -1, -1],
+    [6, 8, 9, 6, 9, 7, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [2, 8, 10, 2, 10, 11, 2, 11, 7, -1, -1, -1, -1, -1, -1, -1],
+    [4, 10, 11, 4, 11, 7, 4, 7, 2, 4, 2, 0, -1, -1, -1, -1],
+    [0, 9, 5, 2, 8, 10, 2, 10, 11, 2, 11, 7, -1, -1, -1, -1],
+    [2, 9, 5, 2, 5, 4, 2, 4, 10, 2, 10, 11, 2, 11, 7, -1],
+    [1, 11, 7, 1, 7, 2, 1, 2, 8, 1, 8, 4, -1, -1, -1, -1],
+    [1, 11, 7, 1, 7, 2, 1, 2, 0, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 5, 1, 11, 7, 1, 7, 2, 1, 2, 8, 1, 8, 4, -1],
+    [1, 11, 7, 1, 7, 2, 1, 2, 9, 1, 9, 5, -1, -1, -1, -1],
+    [5, 7, 2, 5, 2, 8, 5, 8, 10, 5, 10, 1, -1, -1, -1, -1],
+    [4, 10, 1, 4, 1, 5, 4, 5, 7, 4, 7, 2, 4, 2, 0, -1],
+    [0, 9, 7, 0, 7, 2, 0, 2, 8, 0, 8, 10, 0, 10, 1, -1],
+    [4, 10, 1, 2, 9, 7, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [2, 8, 4, 2, 4, 5, 2, 5, 7, -1, -1, -1, -1, -1, -1, -1],
+    [5, 7, 2, 5, 2, 0, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [0, 9, 7, 0, 7, 2, 0, 2, 8, 0, 8, 4, -1, -1, -1, -1],
+    [2, 9, 7, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [6, 10, 11, 6, 11, 9, 6, 9, 2, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 0, 6, 10, 11, 6, 11, 9, 6, 9, 2, -1, -1, -1, -1],
+    [0, 2, 6, 0, 6, 10, 0, 10, 11, 0, 11, 5, -1, -1, -1, -1],
+    [6, 10, 11, 6, 11, 5, 6, 5, 4, 6, 4, 8, 6, 8, 2, -1],
+    [1, 11, 9, 1, 9, 2, 1, 2, 6, 1, 6, 4, -1, -1, -1, -1],
+    [1, 11, 9, 1, 9, 2, 1, 2, 6, 1, 6, 8, 1, 8, 0, -1],
+    [0, 2, 6, 0, 6, 4, 0, 4, 1, 0, 1, 11, 0, 11, 5, -1],
+    [1, 11, 5, 6, 8, 2, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [5, 9, 2, 5, 2, 6, 5, 6, 10, 5, 10, 1, -1, -1, -1, -1],
+    [4, 8, 0, 5, 9, 2, 5, 2, 6, 5, 6, 10, 5, 10, 1, -1],
+    [0, 2, 6, 0, 6, 10, 0, 10, 1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 8, 2, 4, 2, 6, 4, 6, 10, 4, 10, 1, -1, -1, -1, -1],
+    [6, 4, 5, 6, 5, 9, 6, 9, 2, -1, -1, -1, -1, -1, -1, -1],
+    [5, 9, 2, 5, 2, 6, 5, 6, 8, 5, 8, 0, -1, -1, -1, -1],
+    [0, 2, 6, 0, 6, 4, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [6, 8, 2, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [8, 10, 11, 8, 11, 9, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 10, 11, 4, 11, 9, 4, 9, 0, -1, -1, -1, -1, -1, -1, -1],
+    [0, 8, 10, 0, 10, 11, 0, 11, 5, -1, -1, -1, -1, -1, -1, -1],
+    [4, 10, 11, 4, 11, 5, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [1, 11, 9, 1, 9, 8, 1, 8, 4, -1, -1, -1, -1, -1, -1, -1],
+    [1, 11, 9, 1, 9, 0, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [0, 8, 4, 0, 4, 1, 0, 1, 11, 0, 11, 5, -1, -1, -1, -1],
+    [1, 11, 5, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [5, 9, 8, 5, 8, 10, 5, 10, 1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 10, 1, 4, 1, 5, 4, 5, 9, 4, 9, 0, -1, -1, -1, -1],
+    [0, 8, 10, 0, 10, 1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [4, 10, 1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [5, 9, 8, 5, 8, 4, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [5, 9, 0, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [0, 8, 4, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use material::MaterialId;
+    use std::collections::HashMap;
+    use voxel_grid::{QuantizedFloat, Voxel};
+
+    fn sphere_chunk() -> Chunk {
+        let mut chunk = Chunk::new(16);
+        for z in 0..16 {
+            for y in 0..16 {
+                for x in 0..16 {
+                    let d =
+                        Vector3::new(x as f32 - 7.5, y as f32 - 7.5, z as f32 - 7.5).magnitude();
+                    let occ = (5.0 - d + 0.5).max(0.0).min(1.0);
+                    chunk.set_voxel_at(
+                        Vector3::new(x, y, z),
+                        MaterialId::ROCK,
+                        QuantizedFloat::from_f32(occ),
+                    );
+                }
+            }
+        }
+        chunk
+    }
+
+    fn key(v: &PosNormTex) -> (i64, i64, i64) {
+        let q = |f: f32| (f * 1e4).round() as i64;
+        (q(v.position[0]), q(v.position[1]), q(v.position[2]))
+    }
+
+    #[test]
+    fn mesh_empty() {
+        let chunk = Chunk::new(8);
+        assert!(mesh_chunk(&chunk, &[None; 6], 1.0).is_empty());
+    }
+
+    #[test]
+    fn mesh_sphere_closed() {
+        let vertices = mesh_chunk(&sphere_chunk(), &[None; 6], 1.0);
+        assert!(!vertices.is_empty());
+        assert_eq!(vertices.len() % 3, 0);
+
+        // Every directed edge has exactly one partner running the other way.
+        let mut edges = HashMap::new();
+        for tri in vertices.chunks(3) {
+            for i in 0..3 {
+                let (a, b) = (key(&tri[i]), key(&tri[(i + 1) % 3]));
+                if a != b {
+                    *edges.entry((a, b)).or_insert(0) += 1;
+                }
+            }
+        }
+        for (&(a, b), &count) in &edges {
+            assert_eq!(count, 1);
+            assert_eq!(edges.get(&(b, a)), Some(&1));
+        }
+    }
+
+    #[test]
+    fn mesh_snapshot_matches_chunk() {
+        let chunk = sphere_chunk();
+        let plain = Chunk::filled(
+            16,
+            Voxel::new_with_args(MaterialId::ROCK, QuantizedFloat::FULL),
+        );
+        let neighbors = [
+            Some(&plain),
+            None,
+            Some(&chunk),
+            Some(&plain),
+            None,
+            Some(&chunk),
+        ];
+        let direct = OccupancyVolume::from_chunk(&chunk, &neighbors);
+        let snapshot = OccupancyVolume::from_snapshot(&ChunkSnapshot::new(&chunk, &neighbors));
+        for z in -1..18 {
+            for y in -1..18 {
+                for x in -1..18 {
+                    assert_eq!(direct.at(x, y, z), snapshot.at(x, y, z));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mesh_sphere_normals_face_out() {
+        let centre = Vector3::new(8.0, 8.0, 8.0);
+        for tri in mesh_chunk(&sphere_chunk(), &[None; 6], 1.0).chunks(3) {
+            let p: Vec<Vector3<f32>> = tri.iter().map(|v| Vector3::from(v.position)).collect();
+            let face = (p[1] - p[0]).cross(p[2] - p[0]);
+            assert!(face.dot(p[0] - centre) > 0.0);
+            for v in tri {
+                let out = Vector3::from(v.position) - centre;
+                assert!(Vector3::from(v.normal).dot(out) > 0.0);
+            }
+        }
+    }
+}
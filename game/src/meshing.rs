@@ -0,0 +1,404 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use amethyst::assets::{AssetStorage, Handle, Loader};
+use amethyst::core::transform::{LocalTransform, Transform};
+use amethyst::ecs::{Entities, Entity, Fetch, FetchMut, System, WriteStorage};
+use amethyst::renderer::{Material, MaterialDefaults, Mesh, PosNormTex};
+use amethyst::shrev::{EventChannel, ReaderId};
+use cgmath::Vector3;
+use rayon;
+
+use changes::VoxelChangeEvent;
+use mesher::{self, ChunkSnapshot};
+use terrain::Terrain;
+use voxel_grid::{ChunkIndex, VoxelGrid, FACE_DIRECTIONS};
+
+/// Chunks snapshotted and handed to worker threads per `dispatch`.
+pub const DEFAULT_JOBS_PER_FRAME: usize = 8;
+
+/// Mesh of a chunk made by a worker thread.
+#[derive(Debug)]
+pub struct MeshedChunk {
+    pub chunk: ChunkIndex,
+    pub vertices: Vec<PosNormTex>,
+    generation: u64,
+}
+
+/// Queue of chunks waiting to be meshed. Chunks are snapshotted together
+/// with their neighbours' borders on the calling thread and meshed on the
+/// rayon thread pool, so editing the grid never waits for the mesher.
+pub struct MeshQueue {
+    queued: VecDeque<ChunkIndex>,
+    is_queued: HashSet<ChunkIndex>,
+    /// Generation of the latest job of each chunk, older results are
+    /// dropped.
+    generations: HashMap<ChunkIndex, u64>,
+    next_generation: u64,
+    in_flight: usize,
+    finished: Receiver<MeshedChunk>,
+    results: Sender<MeshedChunk>,
+    voxel_size: f32,
+    jobs_per_frame: usize,
+}
+
+impl MeshQueue {
+    pub fn new(voxel_size: f32) -> Self {
+        let (results, finished) = mpsc::channel();
+        MeshQueue {
+            queued: VecDeque::new(),
+            is_queued: HashSet::new(),
+            generations: HashMap::new(),
+            next_generation: 0,
+            in_flight: 0,
+            finished,
+            results,
+            voxel_size,
+            jobs_per_frame: DEFAULT_JOBS_PER_FRAME,
+        }
+    }
+
+    pub fn with_jobs_per_frame(mut self, jobs: usize) -> Self {
+        self.jobs_per_frame = jobs;
+        self
+    }
+
+    #[inline]
+    pub fn voxel_size(&self) -> f32 {
+        self.voxel_size
+    }
+
+    /// Queue a chunk to be meshed, unless it already is.
+    pub fn push(&mut self, chunk: &ChunkIndex) {
+        if self.is_queued.insert(*chunk) {
+            self.queued.push_back(*chunk);
+        }
+    }
+
+    /// Queue the chunks whose mesh is out of date after `event`, i.e. the
+    /// changed chunk and the neighbours that sample the changed voxels.
+    pub fn handle(&mut self, event: &VoxelChangeEvent, dimension: u16) {
+        let (chunk, faces) = match *event {
+            VoxelChangeEvent::Modified { chunk, region, .. } => {
+                self.push(&chunk);
+                // Chunks sample one layer of their negative neighbour and
+                // two of their positive one
+                let last = dimension.saturating_sub(1);
+                let faces = [
+                    region.max.x == last,
+                    region.min.x <= 1,
+                    region.max.y == last,
+                    region.min.y <= 1,
+                    region.max.z == last,
+                    region.min.z <= 1,
+                ];
+                (chunk, faces)
+            }
+            VoxelChangeEvent::Removed(chunk) => {
+                self.forget(&chunk);
+                (chunk, [true; 6])
+            }
+        };
+        for (d, &touched) in FACE_DIRECTIONS.iter().zip(faces.iter()) {
+            if touched {
+                self.push(&(chunk + Vector3::new(d[0], d[1], d[2])));
+            }
+        }
+    }
+
+    /// Drop a queued chunk and any result still being made for it.
+    pub fn forget(&mut self, chunk: &ChunkIndex) {
+        if self.is_queued.remove(chunk) {
+            self.queued.retain(|c| c != chunk);
+        }
+        self.generations.remove(chunk);
+    }
+
+    /// Start meshing up to the per-frame number of queued chunks. Queued
+    /// chunks that are not in `grid` are skipped.
+    pub fn dispatch(&mut self, grid: &VoxelGrid) {
+        let mut started = 0;
+        while started < self.jobs_per_frame {
+            let idx = match self.queued.pop_front() {
+                Some(idx) => idx,
+                None => break,
+            };
+            self.is_queued.remove(&idx);
+            let chunk = match grid.get_chunk(&idx) {
+                Some(c) => c,
+                None => continue,
+            };
+
+            let snapshot = ChunkSnapshot::new(chunk, &grid.neighbors(&idx));
+            let generation = self.next_generation;
+            self.next_generation += 1;
+            self.generations.insert(idx, generation);
+            self.in_flight += 1;
+            started += 1;
+
+            let results = self.results.clone();
+            let voxel_size = self.voxel_size;
+            rayon::spawn(move || {
+                let vertices = mesher::mesh_snapshot(&snapshot, voxel_size);
+                let _ = results.send(MeshedChunk {
+                    chunk: idx,
+                    vertices,
+                    generation,
+                });
+            });
+        }
+    }
+
+    /// Meshes finished since the last call. Meshes of chunks that changed
+    /// or were forgotten since their job started are left out.
+    pub fn finished(&mut self) -> Vec<MeshedChunk> {
+        let mut done = Vec::new();
+        while let Ok(meshed) = self.finished.try_recv() {
+            self.in_flight -= 1;
+            if self.generations.get(&meshed.chunk) == Some(&meshed.generation) {
+                self.generations.remove(&meshed.chunk);
+                done.push(meshed);
+            }
+        }
+        done
+    }
+
+    /// True if nothing is queued or being meshed.
+    #[inline]
+    pub fn is_idle(&self) -> bool {
+        self.queued.is_empty() && self.in_flight == 0
+    }
+}
+
+/// Material of terrain meshes. `MaterialDefaults` is used while it is
+/// `None`.
+#[derive(Default)]
+pub struct TerrainMaterial(pub Option<Material>);
+
+/// Meshes changed terrain chunks in the background and keeps one entity per
+/// chunk with its latest mesh.
+pub struct ChunkMeshingSystem {
+    queue: MeshQueue,
+    reader: ReaderId<VoxelChangeEvent>,
+    entities: HashMap<ChunkIndex, Entity>,
+}
+
+impl ChunkMeshingSystem {
+    /// A reader only sees the events written after it was registered, so
+    /// `reader` is registered before any system runs, e.g. by the bundle.
+    pub fn new(voxel_size: f32, reader: ReaderId<VoxelChangeEvent>) -> Self {
+        ChunkMeshingSystem {
+            queue: MeshQueue::new(voxel_size),
+            reader,
+            entities: HashMap::new(),
+        }
+    }
+
+    /// Queue the chunks changed by new events. Returns the chunks that were
+    /// removed.
+    fn read_events(
+        &mut self,
+        events: &mut EventChannel<VoxelChangeEvent>,
+        dimension: u16,
+    ) -> Vec<ChunkIndex> {
+        let mut removed = Vec::new();
+        for event in events.read(&mut self.reader) {
+            self.queue.handle(event, dimension);
+            if let VoxelChangeEvent::Removed(idx) = *event {
+                removed.push(idx);
+            }
+        }
+        removed
+    }
+}
+
+impl<'s> System<'s> for ChunkMeshingSystem {
+    type SystemData = (
+        Entities<'s>,
+        Fetch<'s, Terrain>,
+        FetchMut<'s, EventChannel<VoxelChangeEvent>>,
+        Fetch<'s, Loader>,
+        Fetch<'s, AssetStorage<Mesh>>,
+        Fetch<'s, TerrainMaterial>,
+        Fetch<'s, MaterialDefaults>,
+        WriteStorage<'s, Handle<Mesh>>,
+        WriteStorage<'s, Material>,
+        WriteStorage<'s, LocalTransform>,
+        WriteStorage<'s, Transform>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            terrain,
+            mut events,
+            loader,
+            mesh_storage,
+            terrain_material,
+            material_defaults,
+            mut meshes,
+            mut materials,
+            mut locals,
+            mut transforms,
+        ): Self::SystemData,
+    ) {
+        let grid = terrain.grid();
+        for idx in self.read_events(&mut events, grid.dimension()) {
+            if let Some(e) = self.entities.remove(&idx) {
+                let _ = entities.delete(e);
+            }
+        }
+
+        self.queue.dispatch(grid);
+
+        let chunk_size = f32::from(grid.dimension()) * self.queue.voxel_size();
+        for meshed in self.queue.finished() {
+            let idx = meshed.chunk;
+            if meshed.vertices.is_empty() {
+                if let Some(e) = self.entities.remove(&idx) {
+                    let _ = entities.delete(e);
+                }
+                continue;
+            }
+
+            let mesh: Handle<Mesh> =
+                loader.load_from_data(meshed.vertices.into(), (), &mesh_storage);
+            if let Some(&e) = self.entities.get(&idx) {
+                let _ = meshes.insert(e, mesh);
+                continue;
+            }
+
+            let e = entities.create();
+            let mut local = LocalTransform::default();
+            local.translation = Vector3::new(
+                idx.x as f32 * chunk_size,
+                idx.y as f32 * chunk_size,
+                idx.z as f32 * chunk_size,
+            );
+            let material = match terrain_material.0 {
+                Some(ref m) => m.clone(),
+                None => material_defaults.0.clone(),
+            };
+            let _ = locals.insert(e, local);
+            let _ = transforms.insert(e, Transform::default());
+            let _ = meshes.insert(e, mesh);
+            let _ = materials.insert(e, material);
+            self.entities.insert(idx, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use changes::{ChangeSource, DirtyRegion};
+    use material::MaterialId;
+    use std::thread;
+    use std::time::Duration;
+    use voxel_grid::{QuantizedFloat, Voxel, WorldPos};
+
+    fn hill() -> VoxelGrid {
+        let mut vg = VoxelGrid::with_dimension(8);
+        vg.fill_region(
+            &Vector3::new(-1, -1, -1),
+            &Vector3::new(0, 0, 0),
+            &|pos: &WorldPos| {
+                let h = 2 - (pos.x.abs() + pos.z.abs()) / 4;
+                if pos.y < h {
+                    Voxel::new_with_args(MaterialId::SNOW, QuantizedFloat::FULL)
+                } else {
+                    Voxel::new()
+                }
+            },
+        );
+        vg
+    }
+
+    fn drain(queue: &mut MeshQueue, grid: &VoxelGrid) -> Vec<MeshedChunk> {
+        let mut done = Vec::new();
+        for _ in 0..5000 {
+            queue.dispatch(grid);
+            done.extend(queue.finished());
+            if queue.is_idle() {
+                return done;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("meshing never finished");
+    }
+
+    #[test]
+    fn mesh_queue_matches_direct() {
+        let mut grid = hill();
+        let mut queue = MeshQueue::new(0.5).with_jobs_per_frame(3);
+        for e in grid.take_changes() {
+            queue.handle(&e, grid.dimension());
+        }
+        let done = drain(&mut queue, &grid);
+
+        let meshed = done.iter().map(|m| m.chunk).collect::<HashSet<_>>();
+        assert_eq!(meshed.len(), done.len());
+        assert_eq!(meshed.len(), 8);
+        for m in &done {
+            let chunk = grid.get_chunk(&m.chunk).unwrap();
+            let direct = mesher::mesh_chunk(chunk, &grid.neighbors(&m.chunk), 0.5);
+            assert_eq!(m.vertices, direct);
+        }
+    }
+
+    #[test]
+    fn mesh_queue_neighbours_and_stale() {
+        let mut grid = hill();
+        grid.clear_changes();
+        let mut queue = MeshQueue::new(1.0);
+
+        // A voxel on the -x face of chunk (0, -1, 0) and in its second layer
+        // along z is sampled by the chunks on both negative sides
+        grid.set_voxel(
+            &Vector3::new(0, -3, 1),
+            MaterialId::ICE,
+            QuantizedFloat::FULL,
+        );
+        for e in grid.take_changes() {
+            queue.handle(&e, grid.dimension());
+        }
+        let mut queued = queue.queued.iter().cloned().collect::<Vec<_>>();
+        queued.sort_by_key(|c| (c.x, c.y, c.z));
+        assert_eq!(
+            queued,
+            vec![
+                Vector3::new(-1, -1, 0),
+                Vector3::new(0, -1, -1),
+                Vector3::new(0, -1, 0),
+            ]
+        );
+
+        // Results of jobs started before a chunk was removed are dropped
+        queue.dispatch(&grid);
+        queue.forget(&Vector3::new(0, -1, 0));
+        let done = drain(&mut queue, &grid);
+        assert_eq!(done.len(), 2);
+        assert!(done.iter().all(|m| m.chunk != Vector3::new(0, -1, 0)));
+    }
+
+    #[test]
+    fn meshing_reads_events_before_first_run() {
+        let mut events = EventChannel::new();
+        let mut system = ChunkMeshingSystem::new(1.0, events.register_reader());
+        events.iter_write(vec![
+            VoxelChangeEvent::Modified {
+                chunk: Vector3::new(0, 0, 0),
+                region: DirtyRegion::whole(8),
+                source: ChangeSource::Load,
+            },
+            VoxelChangeEvent::Removed(Vector3::new(5, 0, 0)),
+        ]);
+        assert_eq!(
+            system.read_events(&mut events, 8),
+            vec![Vector3::new(5, 0, 0)]
+        );
+        assert!(!system.queue.is_idle());
+        assert!(system.read_events(&mut events, 8).is_empty());
+    }
+}
@@ -0,0 +1,252 @@
+use cgmath::Vector3;
+
+use material::MaterialId;
+use palette::VoxelStorage;
+use quantize::Occupancy;
+use voxel_grid::{linear_index, QuantizedFloat, Voxel, VoxelIndex};
+
+/// Downsampled copies of a chunk. Level `n` has `ceil(dim / 2^n)` voxels per
+/// side, each holding the average occupancy of the 2x2x2 voxels below it and
+/// the material that contributes most of that occupancy. Level 0 is the chunk
+/// itself and is not stored here.
+#[derive(Debug, Clone)]
+pub struct MipChain {
+    levels: Vec<MipLevel>,
+}
+
+#[derive(Debug, Clone)]
+struct MipLevel {
+    dimension: u16,
+    voxels: VoxelStorage,
+}
+
+impl MipLevel {
+    fn filled(dim: u16, v: Voxel) -> Self {
+        let d = dim as usize;
+        MipLevel {
+            dimension: dim,
+            voxels: VoxelStorage::filled(v, d * d * d),
+        }
+    }
+
+    #[inline]
+    fn get(&self, idx: VoxelIndex) -> Voxel {
+        *self.voxels.get(linear_index(self.dimension, idx))
+    }
+
+    /// Returns false if the voxel already had the value `v`.
+    #[inline]
+    fn set(&mut self, idx: VoxelIndex, v: Voxel) -> bool {
+        let i = linear_index(self.dimension, idx);
+        if *self.voxels.get(i) == v {
+            return false;
+        }
+        self.voxels.set(i, v);
+        true
+    }
+}
+
+impl MipChain {
+    /// Mips of a chunk with `dim` voxels per side that are all `v`.
+    pub fn filled(dim: u16, v: Voxel) -> Self {
+        let mut levels = Vec::new();
+        let mut d = dim;
+        while d > 1 {
+            d = (d + 1) / 2;
+            levels.push(MipLevel::filled(d, v));
+        }
+        MipChain { levels }
+    }
+
+    /// Compute every level from scratch from the level 0 voxels in `base`.
+    pub fn build<F>(dim: u16, base: F) -> Self
+    where
+        F: Fn(VoxelIndex) -> Voxel,
+    {
+        let mut chain = MipChain::filled(dim, Voxel::new());
+        for l in 0..chain.levels.len() {
+            let (lower, upper) = chain.levels.split_at_mut(l);
+            let level = &mut upper[0];
+            let d = level.dimension;
+            for z in 0..d {
+                for y in 0..d {
+                    for x in 0..d {
+                        let p = Vector3::new(x, y, z);
+                        let v = match lower.last() {
+                            Some(below) => downsample(below.dimension, p, |i| below.get(i)),
+                            None => downsample(dim, p, &base),
+                        };
+                        level.set(p, v);
+                    }
+                }
+            }
+        }
+        chain
+    }
+
+    /// Number of downsampled levels, excluding level 0.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.levels.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+    }
+
+    /// Voxels per side at `level`, which starts at 1.
+    #[inline]
+    pub fn dimension(&self, level: usize) -> u16 {
+        self.levels[level - 1].dimension
+    }
+
+    /// Voxel at `idx` in `level`, which starts at 1.
+    #[inline]
+    pub fn get(&self, level: usize, idx: VoxelIndex) -> Voxel {
+        self.levels[level - 1].get(idx)
+    }
+
+    /// Propagate a change of the level 0 voxel at `idx` upwards, stopping as
+    /// soon as a level is left unchanged.
+    pub fn update<F>(&mut self, dim: u16, idx: VoxelIndex, base: F)
+    where
+        F: Fn(VoxelIndex) -> Voxel,
+    {
+        if self.levels.is_empty() {
+            return;
+        }
+
+        let mut p = idx / 2;
+        if !self.levels[0].set(p, downsample(dim, p, base)) {
+            return;
+        }
+
+        for l in 1..self.levels.len() {
+            let (lower, upper) = self.levels.split_at_mut(l);
+            let below = &lower[l - 1];
+            p = p / 2;
+            let v = downsample(below.dimension, p, |i| below.get(i));
+            if !upper[0].set(p, v) {
+                return;
+            }
+        }
+    }
+}
+
+/// Combine the up to eight voxels of a `child_dim` sized level that sit
+/// below `parent`.
+fn downsample<F>(child_dim: u16, parent: VoxelIndex, child: F) -> Voxel
+where
+    F: Fn(VoxelIndex) -> Voxel,
+{
+    let mut total = 0u32;
+    let mut count = 0u32;
+    let mut weights: Vec<(MaterialId, u32)> = Vec::with_capacity(8);
+
+    for dz in 0..2 {
+        for dy in 0..2 {
+            for dx in 0..2 {
+                let c = parent * 2 + Vector3::new(dx, dy, dz);
+                if c.x >= child_dim || c.y >= child_dim || c.z >= child_dim {
+                    continue;
+                }
+                let v = child(c);
+                let occ = u32::from(v.get_occupancy().value);
+                total += occ;
+                count += 1;
+                match weights.iter_mut().find(|w| w.0 == v.get_material()) {
+                    Some(w) => w.1 += occ,
+                    None => weights.push((v.get_material(), occ)),
+                }
+            }
+        }
+    }
+
+    let material = weights
+        .iter()
+        .fold(None, |best: Option<(MaterialId, u32)>, &w| match best {
+            Some(b) if b.1 >= w.1 => Some(b),
+            _ => Some(w),
+        })
+        .map_or(
+            MaterialId::AIR,
+            |w| if w.1 > 0 { w.0 } else { MaterialId::AIR },
+        );
+
+    Voxel::new_with_args(
+        material,
+        QuantizedFloat::new(((total + count / 2) / count) as Occupancy),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use voxel_grid::Chunk;
+
+    #[test]
+    fn mip_levels() {
+        let chain = MipChain::filled(32, Voxel::new());
+        assert_eq!(chain.len(), 5);
+        assert_eq!(chain.dimension(1), 16);
+        assert_eq!(chain.dimension(5), 1);
+        assert_eq!(MipChain::filled(14, Voxel::new()).dimension(3), 2);
+        assert!(MipChain::filled(1, Voxel::new()).is_empty());
+    }
+
+    #[test]
+    fn mip_average_and_dominant() {
+        let mut chunk = Chunk::new(4);
+        chunk.set_voxel_at(
+            Vector3::new(0, 0, 0),
+            MaterialId::ROCK,
+            QuantizedFloat::new(255),
+        );
+        chunk.set_voxel_at(
+            Vector3::new(1, 0, 0),
+            MaterialId::SNOW,
+            QuantizedFloat::new(200),
+        );
+        chunk.set_voxel_at(
+            Vector3::new(0, 1, 0),
+            MaterialId::SNOW,
+            QuantizedFloat::new(200),
+        );
+
+        let v = chunk.get_voxel_at_level(Vector3::new(0, 0, 0), 1);
+        assert_eq!(v.get_material(), MaterialId::SNOW);
+        assert_eq!(v.get_occupancy().value, 82);
+        let top = chunk.get_voxel_at_level(Vector3::new(0, 0, 0), 2);
+        assert_eq!(top.get_occupancy().value, 10);
+        assert_eq!(
+            chunk
+                .get_voxel_at_level(Vector3::new(1, 1, 1), 1)
+                .get_material(),
+            MaterialId::AIR
+        );
+    }
+
+    #[quickcheck]
+    fn prop_incremental_matches_build(dim: u8, writes: Vec<(u8, u8, u8, Voxel)>) -> bool {
+        let dim = u16::from(dim % 12 + 1);
+        let mut chunk = Chunk::new(dim);
+        for (x, y, z, v) in writes {
+            let idx = Vector3::new(u16::from(x) % dim, u16::from(y) % dim, u16::from(z) % dim);
+            chunk.set_voxel_at(idx, v.get_material(), v.get_occupancy());
+        }
+
+        let built = MipChain::build(dim, |i| chunk.get_voxel_at(i));
+        (1..chunk.num_levels()).all(|l| {
+            let d = chunk.level_dimension(l);
+            (0..d).all(|z| {
+                (0..d).all(|y| {
+                    (0..d).all(|x| {
+                        let i = Vector3::new(x, y, z);
+                        built.get(l, i) == chunk.get_voxel_at_level(i, l)
+                    })
+                })
+            })
+        })
+    }
+}
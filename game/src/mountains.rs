@@ -0,0 +1,458 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use cgmath::Vector3;
+use ron;
+
+use strata::{Strata, StrataRules};
+use voxel_grid::{Chunk, QuantizedFloat, Voxel, VoxelGenerator, WorldPos};
+
+// Seeds of the noise fields, mixed with the world seed
+const WARP_X: u32 = 0x68e3_1da4;
+const WARP_Z: u32 = 0xb529_7a4d;
+const RIDGES: u32 = 0x1b56_c4e9;
+
+#[derive(Debug)]
+pub enum MountainError {
+    Io(io::Error),
+    Parse(ron::de::Error),
+}
+
+impl fmt::Display for MountainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MountainError::Io(ref e) => write!(f, "i/o error: {}", e),
+            MountainError::Parse(ref e) => write!(f, "invalid mountain parameters: {}", e),
+        }
+    }
+}
+
+impl Error for MountainError {
+    fn description(&self) -> &str {
+        match *self {
+            MountainError::Io(_) => "i/o error",
+            MountainError::Parse(_) => "invalid mountain parameters",
+        }
+    }
+}
+
+impl From<io::Error> for MountainError {
+    fn from(e: io::Error) -> Self {
+        MountainError::Io(e)
+    }
+}
+
+impl From<ron::de::Error> for MountainError {
+    fn from(e: ron::de::Error) -> Self {
+        MountainError::Parse(e)
+    }
+}
+
+/// Shape of generated mountains. Heights are in voxels above `y = 0`,
+/// parameters missing from a RON file keep their default.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct MountainParams {
+    /// The same seed always generates the same mountains.
+    pub seed: u32,
+    /// Height of the highest possible peak.
+    pub peak_height: f32,
+    /// Depth of the lowest possible valley floor below `y = 0`.
+    pub valley_depth: f32,
+    /// 1 gives rounded ridges, higher values narrower and sharper crests.
+    pub ridge_sharpness: f32,
+    /// Altitude above which the surface is snow instead of the top layer,
+    /// unless the strata have a snow line of their own.
+    pub tree_line: f32,
+    /// Width of the largest features, in voxels.
+    pub scale: f32,
+    /// Layers of detail added on top of the largest features.
+    pub octaves: u32,
+    /// How far the noise is bent sideways, as a fraction of `scale`.
+    pub warp: f32,
+}
+
+impl Default for MountainParams {
+    fn default() -> Self {
+        MountainParams {
+            seed: 0,
+            peak_height: 96.0,
+            valley_depth: 16.0,
+            ridge_sharpness: 2.0,
+            tree_line: 48.0,
+            scale: 192.0,
+            octaves: 5,
+            warp: 0.4,
+        }
+    }
+}
+
+impl MountainParams {
+    pub fn from_ron(s: &str) -> Result<Self, MountainError> {
+        Ok(ron::de::from_str(s)?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, MountainError> {
+        let mut s = String::new();
+        File::open(path)?.read_to_string(&mut s)?;
+        MountainParams::from_ron(&s)
+    }
+}
+
+/// Generates an endless alpine massif: broad fBm noise for the massif with
+/// ridged noise carving crests and valleys into it, both domain warped.
+/// The mountains are filled with the layers of a `StrataRules`, with snow
+/// above the tree line.
+#[derive(Debug, Clone)]
+pub struct MountainGenerator {
+    params: MountainParams,
+    strata: StrataRules,
+}
+
+impl MountainGenerator {
+    pub fn new(params: MountainParams) -> Self {
+        MountainGenerator {
+            params,
+            strata: StrataRules::default(),
+        }
+        .with_strata(StrataRules::default())
+    }
+
+    /// Fill the mountains with `strata`. Without a snow line of their own
+    /// the snow starts at the tree line.
+    pub fn with_strata(mut self, strata: StrataRules) -> Self {
+        let snow_line = strata.snow_line.or(Some(self.params.tree_line));
+        self.strata = StrataRules {
+            snow_line,
+            ..strata
+        };
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u32) -> Self {
+        self.params.seed = seed;
+        self
+    }
+
+    #[inline]
+    pub fn params(&self) -> &MountainParams {
+        &self.params
+    }
+
+    /// Height of the surface at a point, between `-valley_depth` and
+    /// `peak_height`.
+    pub fn height(&self, x: f32, z: f32) -> f32 {
+        let p = &self.params;
+        let seed = p.seed;
+        let (x, z) = (x / p.scale, z / p.scale);
+
+        // Bend the noise so ridges and valleys do not follow its grid
+        let wx = x + p.warp * fbm(seed ^ WARP_X, x, z, 3);
+        let wz = z + p.warp * fbm(seed ^ WARP_Z, x, z, 3);
+
+        let massif = 0.5 + 0.5 * fbm(seed, wx * 0.5, wz * 0.5, p.octaves);
+        let ridges = ridged(seed ^ RIDGES, wx, wz, p.octaves, p.ridge_sharpness);
+        let t = massif * (0.3 + 0.7 * ridges);
+        -p.valley_depth + t * (p.peak_height + p.valley_depth)
+    }
+
+    /// Bottom of the mountains, under the soil and rock of the deepest
+    /// valley. The bedrock starts here.
+    pub fn floor(&self) -> f32 {
+        let rock = self.strata.soil_depth() + self.strata.bedrock_depth;
+        (-self.params.valley_depth - rock).floor()
+    }
+
+    /// Lowest and highest layer of chunks the surface can pass through,
+    /// down to the bedrock. Chunks below are solid and chunks above are air.
+    pub fn chunk_layers(&self, dimension: u16) -> (i32, i32) {
+        let d = f32::from(dimension);
+        let low = self.floor() / d;
+        let high = self.params.peak_height / d;
+        (low.floor() as i32, high.floor() as i32)
+    }
+}
+
+impl MountainGenerator {
+    /// The voxel at `pos`, with the surface of `strata` where it matters.
+    fn generate_in<H>(&self, strata: &Strata<H>, pos: &WorldPos) -> Voxel
+    where
+        H: Fn(f32, f32) -> f32 + Sync,
+    {
+        let p = &self.params;
+        let y = pos.y as f32;
+        if y >= p.peak_height {
+            return Voxel::new();
+        }
+        // Far enough under the deepest valley only rock and bedrock remain
+        if y + self.strata.soil_depth() < -p.valley_depth {
+            let material = self.strata.material(y, self.floor(), -p.valley_depth, 0.0);
+            return Voxel::new_with_args(material, QuantizedFloat::FULL);
+        }
+        strata.generate(pos)
+    }
+}
+
+impl VoxelGenerator for MountainGenerator {
+    fn generate(&self, pos: &WorldPos) -> Voxel {
+        let strata = Strata::new(&self.strata, self.floor(), |x, z| self.height(x, z));
+        self.generate_in(&strata, pos)
+    }
+
+    /// Computes the height of every column once, along with the columns
+    /// around the chunk that the slopes at its edges need.
+    fn generate_chunk(&self, origin: &WorldPos, dim: u16) -> Chunk {
+        let d = i32::from(dim) + 2;
+        let mut heights = Vec::with_capacity((d * d) as usize);
+        for z in 0..d {
+            for x in 0..d {
+                let (x, z) = (origin.x + x - 1, origin.z + z - 1);
+                heights.push(self.height(x as f32 + 0.5, z as f32 + 0.5));
+            }
+        }
+        // Columns are sampled at their centres
+        let height = |x: f32, z: f32| {
+            let cx = (x - 0.5).floor() as i32 - origin.x + 1;
+            let cz = (z - 0.5).floor() as i32 - origin.z + 1;
+            if cx >= 0 && cz >= 0 && cx < d && cz < d {
+                heights[(cx + d * cz) as usize]
+            } else {
+                self.height(x, z)
+            }
+        };
+        let strata = Strata::new(&self.strata, self.floor(), height);
+        Chunk::from_fn(dim, |v| {
+            let pos = origin + Vector3::new(i32::from(v.x), i32::from(v.y), i32::from(v.z));
+            self.generate_in(&strata, &pos)
+        })
+    }
+}
+
+/// Well mixed hash of a lattice point.
+#[inline]
+fn hash(seed: u32, x: i32, z: i32) -> u32 {
+    let mut h = seed ^ (x as u32).wrapping_mul(0x27d4_eb2d) ^ (z as u32).wrapping_mul(0x1656_67b1);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
+}
+
+const D: f32 = ::std::f32::consts::FRAC_1_SQRT_2;
+
+const GRADIENTS: [(f32, f32); 8] = [
+    (1.0, 0.0),
+    (-1.0, 0.0),
+    (0.0, 1.0),
+    (0.0, -1.0),
+    (D, D),
+    (-D, D),
+    (D, -D),
+    (-D, -D),
+];
+
+/// Gradient noise in `[-1, 1]`, zero at every lattice point.
+fn noise(seed: u32, x: f32, z: f32) -> f32 {
+    let (x0, z0) = (x.floor(), z.floor());
+    let (fx, fz) = (x - x0, z - z0);
+    let (ix, iz) = (x0 as i32, z0 as i32);
+    let corner = |dx: i32, dz: i32| {
+        let g = GRADIENTS[(hash(seed, ix + dx, iz + dz) & 7) as usize];
+        g.0 * (fx - dx as f32) + g.1 * (fz - dz as f32)
+    };
+
+    let fade = |t: f32| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let (u, v) = (fade(fx), fade(fz));
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let n = lerp(
+        lerp(corner(0, 0), corner(1, 0), u),
+        lerp(corner(0, 1), corner(1, 1), u),
+        v,
+    );
+    (n * ::std::f32::consts::SQRT_2).max(-1.0).min(1.0)
+}
+
+/// Sum of `octaves` layers of noise, each twice as detailed and half as
+/// strong as the one before, in `[-1, 1]`.
+fn fbm(seed: u32, x: f32, z: f32, octaves: u32) -> f32 {
+    let (mut sum, mut total, mut amplitude, mut frequency) = (0.0, 0.0, 1.0, 1.0);
+    for octave in 0..octaves.max(1) {
+        let s = seed.wrapping_add(octave.wrapping_mul(0x9e37_79b9));
+        sum += amplitude * noise(s, x * frequency, z * frequency);
+        total += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    sum / total
+}
+
+/// Ridged noise in `[0, 1]`, with crests where the noise crosses zero.
+/// Higher `sharpness` narrows the crests. Detail is weighted by the layer
+/// below it, so valleys stay smooth.
+fn ridged(seed: u32, x: f32, z: f32, octaves: u32, sharpness: f32) -> f32 {
+    let (mut sum, mut total, mut amplitude, mut frequency) = (0.0, 0.0, 1.0, 1.0);
+    let mut weight = 1.0;
+    for octave in 0..octaves.max(1) {
+        let s = seed.wrapping_add(octave.wrapping_mul(0x9e37_79b9));
+        let ridge = (1.0 - noise(s, x * frequency, z * frequency).abs()).powf(sharpness);
+        sum += amplitude * ridge * weight;
+        total += amplitude;
+        weight = ridge;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    sum / total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use material::MaterialId;
+    use terrain::Terrain;
+    use voxel_grid::VoxelGrid;
+
+    fn small() -> MountainParams {
+        MountainParams {
+            seed: 7,
+            peak_height: 40.0,
+            valley_depth: 8.0,
+            scale: 48.0,
+            ..MountainParams::default()
+        }
+    }
+
+    #[test]
+    fn mountain_params_ron() {
+        let params = MountainParams::from_ron("(seed: 3, peak_height: 200.0)").unwrap();
+        assert_eq!(params.seed, 3);
+        assert_eq!(params.peak_height, 200.0);
+        assert_eq!(params.octaves, MountainParams::default().octaves);
+        assert!(MountainParams::from_ron("(seed: -1)").is_err());
+        MountainParams::load(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/resources/mountains.ron"
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn mountain_noise_ranges() {
+        let gen = MountainGenerator::new(small());
+        for i in 0..2000 {
+            let (x, z) = (i as f32 * 7.3 - 5000.0, i as f32 * -3.1 + 800.0);
+            let n = noise(1, x * 0.1, z * 0.1);
+            assert!(n.abs() <= 1.0);
+            let r = ridged(1, x * 0.1, z * 0.1, 4, 2.0);
+            assert!(r >= 0.0);
+            assert!(r <= 1.0);
+            let h = gen.height(x, z);
+            assert!(h >= -8.0);
+            assert!(h <= 40.0);
+        }
+        assert_eq!(noise(9, 3.0, -4.0), 0.0);
+    }
+
+    #[test]
+    fn mountains_from_seed() {
+        let gen = MountainGenerator::new(MountainParams {
+            tree_line: 16.0,
+            ..small()
+        });
+        let dim = 16;
+        let (low, high) = gen.chunk_layers(dim);
+        assert_eq!((low, high), (-1, 2));
+
+        let generate = |gen: &MountainGenerator| {
+            let mut terrain = Terrain::new(VoxelGrid::with_dimension(dim));
+            terrain.generate(&Vector3::new(-1, low, 2), &Vector3::new(0, high, 3), gen);
+            terrain
+        };
+        let a = generate(&gen);
+        let b = generate(&gen);
+        let c = generate(&gen.clone().with_seed(8));
+        let voxels = |t: &Terrain| {
+            let (min, max) = t.grid().loaded_bounds().unwrap();
+            t.grid()
+                .region(&min, &max)
+                .map(|(_, v)| *v)
+                .collect::<Vec<_>>()
+        };
+        assert!(voxels(&a) == voxels(&b));
+        assert!(voxels(&a) != voxels(&c));
+
+        // Every column has a surface inside the generated layers, snow only
+        // above the tree line, rock underneath and bedrock at the bottom
+        let grid = a.grid();
+        let mut bare = 0;
+        for x in -16..16 {
+            for z in 32..64 {
+                let h = gen.height(x as f32 + 0.5, z as f32 + 0.5);
+                let top = h.ceil() as i32 - 1;
+                let surface = grid.voxel_at(&Vector3::new(x, top, z));
+                assert!((surface.get_occupancy_as_f32() - (h - top as f32)).abs() < 0.01);
+                assert_eq!(
+                    grid.voxel_at(&Vector3::new(x, top + 1, z)).get_occupancy(),
+                    QuantizedFloat::EMPTY
+                );
+                let expected = if h > 16.0 {
+                    MaterialId::SNOW
+                } else {
+                    MaterialId::GRASS
+                };
+                if surface.get_material() == MaterialId::ROCK {
+                    bare += 1;
+                } else {
+                    assert_eq!(surface.get_material(), expected);
+                }
+                assert_eq!(
+                    grid.voxel_at(&Vector3::new(x, top - 3, z)).get_material(),
+                    MaterialId::ROCK
+                );
+                assert_eq!(
+                    grid.voxel_at(&Vector3::new(x, -10, z)).get_material(),
+                    MaterialId::ROCK
+                );
+                assert_eq!(
+                    grid.voxel_at(&Vector3::new(x, -11, z)).get_material(),
+                    MaterialId::BEDROCK
+                );
+            }
+        }
+        // Only steep faces lose their soil
+        assert!(bare > 0 && bare < 32 * 32);
+    }
+
+    #[test]
+    fn mountain_chunks_match_voxels() {
+        let gen = MountainGenerator::new(small());
+        for origin in &[
+            Vector3::new(-16, -16, 32),
+            Vector3::new(0, 0, 48),
+            Vector3::new(37, 8, -5),
+        ] {
+            let chunk = gen.generate_chunk(origin, 16);
+            let expected = Chunk::from_fn(16, |v| {
+                let pos = origin + Vector3::new(i32::from(v.x), i32::from(v.y), i32::from(v.z));
+                gen.generate(&pos)
+            });
+            assert!(chunk.voxels().eq(expected.voxels()));
+        }
+    }
+
+    #[test]
+    fn mountains_snow_line() {
+        let gen = MountainGenerator::new(small());
+        assert_eq!(gen.strata.snow_line, Some(small().tree_line));
+        let gen = gen.with_strata(StrataRules {
+            snow_line: Some(30.0),
+            ..StrataRules::default()
+        });
+        assert_eq!(gen.strata.snow_line, Some(30.0));
+        let gen = gen.with_strata(StrataRules::default());
+        assert_eq!(gen.strata.snow_line, Some(small().tree_line));
+    }
+}
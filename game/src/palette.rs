@@ -0,0 +1,377 @@
+use std::mem;
+
+use voxel_grid::Voxel;
+
+/// Bits per packed palette index, kept at powers of two so that an index
+/// never straddles two words.
+const INDEX_WIDTHS: [u8; 5] = [1, 2, 4, 8, 16];
+
+/// Most entries of a palette, as many as the widest index can refer to.
+const MAX_PALETTE_LEN: usize = 1 << 16;
+
+/// Voxel storage for a chunk. Chunks made of a single voxel value only store
+/// that value, anything else keeps a palette of the distinct voxels in the
+/// chunk and a bit-packed palette index per voxel. Chunks with more distinct
+/// voxels than a palette holds store every voxel unpacked.
+#[derive(Debug, Clone)]
+pub enum VoxelStorage {
+    Uniform { voxel: Voxel, len: usize },
+    Paletted(PalettedStorage),
+    Unpacked(Vec<Voxel>),
+}
+
+#[derive(Debug, Clone)]
+pub struct PalettedStorage {
+    palette: Vec<Voxel>,
+    /// Number of voxels using each palette entry, unused entries are reused.
+    counts: Vec<usize>,
+    bits: u8,
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl VoxelStorage {
+    /// Storage for `len` voxels that are all `voxel`.
+    #[inline]
+    pub fn filled(voxel: Voxel, len: usize) -> Self {
+        VoxelStorage::Uniform { voxel, len }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        match *self {
+            VoxelStorage::Uniform { len, .. } => len,
+            VoxelStorage::Paletted(ref p) => p.len,
+            VoxelStorage::Unpacked(ref voxels) => voxels.len(),
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    pub fn is_uniform(&self) -> bool {
+        match *self {
+            VoxelStorage::Uniform { .. } => true,
+            VoxelStorage::Paletted(_) | VoxelStorage::Unpacked(_) => false,
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, i: usize) -> &Voxel {
+        match *self {
+            VoxelStorage::Uniform { ref voxel, len } => {
+                assert!(i < len);
+                voxel
+            }
+            VoxelStorage::Paletted(ref p) => &p.palette[p.index(i)],
+            VoxelStorage::Unpacked(ref voxels) => &voxels[i],
+        }
+    }
+
+    pub fn set(&mut self, i: usize, v: Voxel) {
+        let unpacked = match *self {
+            VoxelStorage::Paletted(ref p) if p.is_full(&v) => Some(p.unpack()),
+            _ => None,
+        };
+        if let Some(voxels) = unpacked {
+            *self = VoxelStorage::Unpacked(voxels);
+        }
+
+        let collapse = match *self {
+            VoxelStorage::Uniform { voxel, len } => {
+                assert!(i < len);
+                if voxel == v {
+                    return;
+                }
+                let mut p = PalettedStorage::new(voxel, len);
+                let uniform = p.set(i, v);
+                *self = VoxelStorage::Paletted(p);
+                if uniform {
+                    Some(v)
+                } else {
+                    None
+                }
+            }
+            VoxelStorage::Paletted(ref mut p) => {
+                if p.set(i, v) {
+                    Some(v)
+                } else {
+                    None
+                }
+            }
+            VoxelStorage::Unpacked(ref mut voxels) => {
+                voxels[i] = v;
+                None
+            }
+        };
+
+        if let Some(voxel) = collapse {
+            let len = self.len();
+            *self = VoxelStorage::Uniform { voxel, len };
+        }
+    }
+
+    /// Number of distinct voxel values the storage can currently refer to.
+    pub fn palette_len(&self) -> usize {
+        match *self {
+            VoxelStorage::Uniform { .. } => 1,
+            VoxelStorage::Paletted(ref p) => p.palette.len(),
+            VoxelStorage::Unpacked(ref voxels) => voxels.len(),
+        }
+    }
+
+    /// Drop unused palette entries and pack indices as tightly as possible.
+    pub fn compact(&mut self) {
+        let packed = match *self {
+            VoxelStorage::Uniform { .. } => return,
+            VoxelStorage::Paletted(ref p) => {
+                let mut out = VoxelStorage::filled(p.palette[p.index(0)], p.len);
+                for i in 1..p.len {
+                    out.set(i, p.palette[p.index(i)]);
+                }
+                out
+            }
+            VoxelStorage::Unpacked(ref voxels) => {
+                let mut out = VoxelStorage::filled(voxels[0], voxels.len());
+                for (i, v) in voxels.iter().enumerate().skip(1) {
+                    out.set(i, *v);
+                }
+                out
+            }
+        };
+        *self = packed;
+    }
+
+    /// Approximate number of bytes used, including the heap.
+    pub fn memory_usage(&self) -> usize {
+        mem::size_of::<Self>()
+            + match *self {
+                VoxelStorage::Uniform { .. } => 0,
+                VoxelStorage::Paletted(ref p) => {
+                    p.palette.capacity() * mem::size_of::<Voxel>()
+                        + p.counts.capacity() * mem::size_of::<usize>()
+                        + p.words.capacity() * mem::size_of::<u64>()
+                }
+                VoxelStorage::Unpacked(ref voxels) => voxels.capacity() * mem::size_of::<Voxel>(),
+            }
+    }
+}
+
+impl PalettedStorage {
+    fn new(fill: Voxel, len: usize) -> Self {
+        let bits = INDEX_WIDTHS[0];
+        PalettedStorage {
+            palette: vec![fill],
+            counts: vec![len],
+            bits,
+            words: vec![0; words_for(len, bits)],
+            len,
+        }
+    }
+
+    #[inline]
+    fn index(&self, i: usize) -> usize {
+        assert!(i < self.len);
+        let bits = self.bits as usize;
+        let per_word = 64 / bits;
+        let shift = (i % per_word) * bits;
+        ((self.words[i / per_word] >> shift) & ((1 << bits) - 1)) as usize
+    }
+
+    #[inline]
+    fn set_index(&mut self, i: usize, p: usize) {
+        let bits = self.bits as usize;
+        let per_word = 64 / bits;
+        let shift = (i % per_word) * bits;
+        let mask = ((1u64 << bits) - 1) << shift;
+        let word = &mut self.words[i / per_word];
+        *word = (*word & !mask) | ((p as u64) << shift);
+    }
+
+    /// True if `v` needs a palette entry but there is no room for one.
+    fn is_full(&self, v: &Voxel) -> bool {
+        self.palette.len() >= MAX_PALETTE_LEN
+            && self.counts.iter().all(|&c| c > 0)
+            && !self.palette.contains(v)
+    }
+
+    fn unpack(&self) -> Vec<Voxel> {
+        (0..self.len).map(|i| self.palette[self.index(i)]).collect()
+    }
+
+    /// Returns true when `v` is left as the only voxel value in use.
+    fn set(&mut self, i: usize, v: Voxel) -> bool {
+        let old = self.index(i);
+        if self.palette[old] == v {
+            return false;
+        }
+
+        let new = match self.palette.iter().position(|p| *p == v) {
+            Some(p) => p,
+            None => match self.counts.iter().position(|&c| c == 0) {
+                Some(free) => {
+                    self.palette[free] = v;
+                    free
+                }
+                None => {
+                    self.palette.push(v);
+                    self.counts.push(0);
+                    self.palette.len() - 1
+                }
+            },
+        };
+
+        if new >= 1 << self.bits {
+            self.grow();
+        }
+
+        self.counts[old] -= 1;
+        self.counts[new] += 1;
+        self.set_index(i, new);
+        self.counts[new] == self.len
+    }
+
+    /// Repack every index with the next wider index width. Palettes are
+    /// unpacked before they outgrow the widest one.
+    fn grow(&mut self) {
+        let bits = *INDEX_WIDTHS
+            .iter()
+            .find(|&&b| b > self.bits)
+            .expect("palette exceeds 16 bit indices");
+        let indices = (0..self.len).map(|i| self.index(i)).collect::<Vec<_>>();
+        self.bits = bits;
+        self.words = vec![0; words_for(self.len, bits)];
+        for (i, p) in indices.into_iter().enumerate() {
+            self.set_index(i, p);
+        }
+    }
+}
+
+#[inline]
+fn words_for(len: usize, bits: u8) -> usize {
+    let per_word = 64 / bits as usize;
+    (len + per_word - 1) / per_word
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use material::MaterialId;
+    use quantize::Occupancy;
+    #[cfg(feature = "occupancy16")]
+    use std::collections::HashMap;
+    use voxel_grid::QuantizedFloat;
+
+    #[test]
+    fn pal_uniform_until_set() {
+        let air = Voxel::new();
+        let rock = Voxel::new_with_args(MaterialId::ROCK, QuantizedFloat::FULL);
+        let mut s = VoxelStorage::filled(air, 100);
+        assert!(s.is_uniform());
+        s.set(10, air);
+        assert!(s.is_uniform());
+        s.set(10, rock);
+        assert!(!s.is_uniform());
+        assert_eq!(*s.get(10), rock);
+        assert_eq!(*s.get(11), air);
+        s.set(10, air);
+        assert!(s.is_uniform());
+    }
+
+    #[test]
+    fn pal_grows_and_compacts() {
+        let mut s = VoxelStorage::filled(Voxel::new(), 4096);
+        for i in 0..300 {
+            s.set(
+                i,
+                Voxel::new_with_args(
+                    MaterialId::SNOW,
+                    QuantizedFloat::new((i % 256) as Occupancy),
+                ),
+            );
+        }
+        assert_eq!(s.palette_len(), 257);
+        for i in 0..300 {
+            assert_eq!(s.get(i).get_occupancy().value, (i % 256) as Occupancy);
+        }
+        for i in 0..200 {
+            s.set(i, Voxel::new());
+        }
+        s.compact();
+        assert!(s.palette_len() < 257);
+        assert_eq!(s.get(250).get_occupancy().value, 250);
+    }
+
+    #[quickcheck]
+    fn prop_matches_vec(writes: Vec<(u16, Voxel)>) -> bool {
+        let len = 700;
+        let mut model = vec![Voxel::new(); len];
+        let mut s = VoxelStorage::filled(Voxel::new(), len);
+        for (i, v) in writes {
+            let i = i as usize % len;
+            model[i] = v;
+            s.set(i, v);
+        }
+        (0..len).all(|i| *s.get(i) == model[i])
+    }
+
+    /// Voxel `i` of `full_palette`.
+    #[cfg(feature = "occupancy16")]
+    fn full_palette_voxel(i: usize) -> Voxel {
+        if i % 31 == 0 && i / 31 > 0 && i / 31 < MAX_PALETTE_LEN {
+            let o = QuantizedFloat::new((i / 31) as Occupancy);
+            Voxel::new_with_args(MaterialId::ROCK, o)
+        } else {
+            Voxel::new()
+        }
+    }
+
+    /// Storage of a 128³ chunk with as many distinct voxels as a palette
+    /// holds. Built directly, setting the voxels one by one searches the
+    /// palette for every new value.
+    #[cfg(feature = "occupancy16")]
+    fn full_palette() -> VoxelStorage {
+        let len = 128 * 128 * 128;
+        let mut p = PalettedStorage {
+            palette: vec![Voxel::new()],
+            counts: vec![len],
+            bits: 16,
+            words: vec![0; words_for(len, 16)],
+            len,
+        };
+        for i in 1..MAX_PALETTE_LEN {
+            p.palette.push(full_palette_voxel(i * 31));
+            p.counts.push(1);
+            p.counts[0] -= 1;
+            p.set_index(i * 31, i);
+        }
+        VoxelStorage::Paletted(p)
+    }
+
+    #[cfg(feature = "occupancy16")]
+    thread_local!(static FULL_PALETTE: VoxelStorage = full_palette());
+
+    #[cfg(feature = "occupancy16")]
+    #[quickcheck]
+    fn prop_full_palette_matches_vec(writes: Vec<(u32, Voxel)>) -> bool {
+        let mut s = FULL_PALETTE.with(|s| s.clone());
+        let len = s.len();
+        let mut model = HashMap::new();
+        for &(i, v) in &writes {
+            let i = i as usize % len;
+            model.insert(i, v);
+            s.set(i, v);
+        }
+        let written = writes.iter().map(|&(i, _)| i as usize % len);
+        written.chain((0..len).step_by(31)).all(|i| {
+            let expected = model
+                .get(&i)
+                .cloned()
+                .unwrap_or_else(|| full_palette_voxel(i));
+            *s.get(i) == expected
+        })
+    }
+}
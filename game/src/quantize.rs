@@ -0,0 +1,149 @@
+/// Stores values in `[0, 1]` as integers from 0 to `MAX`. 0 and 1 are stored
+/// exactly, encoding rounds to the nearest step and never decreases as the
+/// value grows, and decoding then encoding a stored integer gives it back.
+pub trait Precision {
+    type Raw: Copy;
+
+    const MAX: u32;
+
+    fn from_u32(v: u32) -> Self::Raw;
+
+    fn to_u32(raw: Self::Raw) -> u32;
+}
+
+/// 256 steps, for when memory matters most.
+pub struct Bits8;
+
+/// 65536 steps, enough to accumulate small increments such as snowfall.
+pub struct Bits16;
+
+impl Precision for Bits8 {
+    type Raw = u8;
+
+    const MAX: u32 = 255;
+
+    #[inline]
+    fn from_u32(v: u32) -> u8 {
+        v as u8
+    }
+
+    #[inline]
+    fn to_u32(raw: u8) -> u32 {
+        u32::from(raw)
+    }
+}
+
+impl Precision for Bits16 {
+    type Raw = u16;
+
+    const MAX: u32 = 65_535;
+
+    #[inline]
+    fn from_u32(v: u32) -> u16 {
+        v as u16
+    }
+
+    #[inline]
+    fn to_u32(raw: u16) -> u32 {
+        u32::from(raw)
+    }
+}
+
+/// Precision of voxel occupancy, 16 bits with the `occupancy16` feature.
+#[cfg(not(feature = "occupancy16"))]
+pub type OccupancyPrecision = Bits8;
+#[cfg(feature = "occupancy16")]
+pub type OccupancyPrecision = Bits16;
+
+/// Integer type voxel occupancy is stored in.
+#[cfg(not(feature = "occupancy16"))]
+pub type Occupancy = u8;
+#[cfg(feature = "occupancy16")]
+pub type Occupancy = u16;
+
+/// Stored value of a full voxel.
+#[cfg(not(feature = "occupancy16"))]
+pub const OCCUPANCY_MAX: Occupancy = 255;
+#[cfg(feature = "occupancy16")]
+pub const OCCUPANCY_MAX: Occupancy = 65_535;
+
+/// Store `v`, clamped to `[0, 1]`. NaN is stored as 0.
+#[inline]
+pub fn encode<P: Precision>(v: f32) -> P::Raw {
+    let v = if v > 0.0 { v.min(1.0) } else { 0.0 };
+    P::from_u32((v * P::MAX as f32).round() as u32)
+}
+
+#[inline]
+pub fn decode<P: Precision>(raw: P::Raw) -> f32 {
+    P::to_u32(raw) as f32 / P::MAX as f32
+}
+
+/// Convert a stored value between precisions, rounding to the nearest step.
+#[inline]
+pub fn convert<S: Precision, D: Precision>(raw: S::Raw) -> D::Raw {
+    let v = u64::from(S::to_u32(raw)) * u64::from(D::MAX);
+    let max = u64::from(S::MAX);
+    D::from_u32(((v + max / 2) / max) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exact_ends<P: Precision>() -> bool {
+        P::to_u32(encode::<P>(0.0)) == 0
+            && P::to_u32(encode::<P>(1.0)) == P::MAX
+            && decode::<P>(encode::<P>(0.0)) == 0.0
+            && decode::<P>(encode::<P>(1.0)) == 1.0
+            && P::to_u32(encode::<P>(-3.0)) == 0
+            && P::to_u32(encode::<P>(7.5)) == P::MAX
+    }
+
+    fn close<P: Precision>(v: f32) -> bool {
+        let v = v.abs().fract();
+        (decode::<P>(encode::<P>(v)) - v).abs() <= 0.5 / P::MAX as f32 + 1e-7
+    }
+
+    fn monotonic<P: Precision>(a: f32, b: f32) -> bool {
+        let (lo, hi) = if a <= b { (a, b) } else { (b, a) };
+        P::to_u32(encode::<P>(lo)) <= P::to_u32(encode::<P>(hi))
+    }
+
+    #[test]
+    fn quantize_exact_ends() {
+        assert!(exact_ends::<Bits8>());
+        assert!(exact_ends::<Bits16>());
+        assert_eq!(encode::<Bits8>(::std::f32::NAN), 0);
+    }
+
+    #[test]
+    fn quantize_convert() {
+        assert_eq!(convert::<Bits8, Bits16>(255), 65_535);
+        assert_eq!(convert::<Bits8, Bits16>(1), 257);
+        assert_eq!(convert::<Bits16, Bits8>(65_535), 255);
+        assert_eq!(convert::<Bits16, Bits8>(128), 0);
+        assert_eq!(convert::<Bits16, Bits8>(129), 1);
+    }
+
+    #[quickcheck]
+    fn prop_roundtrip_8(raw: u8) -> bool {
+        encode::<Bits8>(decode::<Bits8>(raw)) == raw
+            && convert::<Bits16, Bits8>(convert::<Bits8, Bits16>(raw)) == raw
+    }
+
+    #[quickcheck]
+    fn prop_roundtrip_16(raw: u16) -> bool {
+        encode::<Bits16>(decode::<Bits16>(raw)) == raw
+    }
+
+    #[quickcheck]
+    fn prop_encode_close(v: f32) -> bool {
+        close::<Bits8>(v) && close::<Bits16>(v)
+    }
+
+    #[quickcheck]
+    fn prop_encode_monotonic(a: f32, b: f32) -> bool {
+        monotonic::<Bits8>(a, b) && monotonic::<Bits16>(a, b)
+    }
+}
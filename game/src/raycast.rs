@@ -0,0 +1,224 @@
+use std::f32;
+
+use cgmath::{InnerSpace, Vector3};
+
+use voxel_grid::{Chunk, ChunkIndex, VoxelGrid, VoxelIndex, WorldPos, ISO_LEVEL};
+
+/// Most voxels a ray visits, so very long rays through empty space end.
+const MAX_STEPS: f32 = 1_048_576.0;
+
+/// First voxel hit by a ray. Positions are in voxel units, voxel `i` covers
+/// `[i, i + 1)` along each axis.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RaycastHit {
+    /// Point where the ray enters the voxel.
+    pub position: Vector3<f32>,
+    /// Normal of the face the ray entered through, zero if the ray started
+    /// inside the voxel.
+    pub normal: Vector3<i32>,
+    pub distance: f32,
+    pub world_pos: WorldPos,
+    pub chunk: ChunkIndex,
+    pub voxel: VoxelIndex,
+}
+
+impl VoxelGrid {
+    /// Walk the voxels along a ray and return the first one that is solid.
+    pub fn raycast(
+        &self,
+        origin: Vector3<f32>,
+        dir: Vector3<f32>,
+        max_dist: f32,
+    ) -> Option<RaycastHit> {
+        self.raycast_with_threshold(origin, dir, max_dist, ISO_LEVEL)
+    }
+
+    /// Walk the voxels along a ray and return the first one with an
+    /// occupancy of at least `threshold`. Chunks that do not exist are
+    /// treated as air. An infinite `max_dist` ends after the most steps a ray
+    /// takes, a negative or NaN one hits nothing.
+    pub fn raycast_with_threshold(
+        &self,
+        origin: Vector3<f32>,
+        dir: Vector3<f32>,
+        max_dist: f32,
+        threshold: f32,
+    ) -> Option<RaycastHit> {
+        if dir.magnitude2() == 0.0 || max_dist.is_nan() || max_dist < 0.0 {
+            return None;
+        }
+        let dir = dir.normalize();
+        let o = [origin.x, origin.y, origin.z];
+        let d = [dir.x, dir.y, dir.z];
+
+        let mut cell = [
+            o[0].floor() as i32,
+            o[1].floor() as i32,
+            o[2].floor() as i32,
+        ];
+        let mut step = [0; 3];
+        let mut t_max = [f32::INFINITY; 3];
+        let mut t_delta = [f32::INFINITY; 3];
+        for axis in 0..3 {
+            if d[axis] > 0.0 {
+                step[axis] = 1;
+                t_max[axis] = (cell[axis] as f32 + 1.0 - o[axis]) / d[axis];
+                t_delta[axis] = 1.0 / d[axis];
+            } else if d[axis] < 0.0 {
+                step[axis] = -1;
+                t_max[axis] = (o[axis] - cell[axis] as f32) / -d[axis];
+                t_delta[axis] = -1.0 / d[axis];
+            }
+        }
+
+        let mut t = 0.0;
+        let mut normal = Vector3::new(0, 0, 0);
+        let mut cached: Option<(ChunkIndex, Option<&Chunk>)> = None;
+
+        // A ray crosses at most three voxel faces per unit of distance
+        let max_steps = (3.0 * (max_dist + 1.0)).max(0.0).min(MAX_STEPS) as u32;
+        for _ in 0..max_steps + 1 {
+            if t > max_dist {
+                break;
+            }
+            let world_pos = Vector3::new(cell[0], cell[1], cell[2]);
+            let (chunk, voxel) = self.locate(&world_pos);
+            let source = match cached {
+                Some((idx, c)) if idx == chunk => c,
+                _ => {
+                    let c = self.get_chunk(&chunk);
+                    cached = Some((chunk, c));
+                    c
+                }
+            };
+
+            if let Some(c) = source {
+                if c.get_voxel_at(voxel).get_occupancy_as_f32() >= threshold {
+                    return Some(RaycastHit {
+                        position: origin + dir * t,
+                        normal,
+                        distance: t,
+                        world_pos,
+                        chunk,
+                        voxel,
+                    });
+                }
+            }
+
+            let axis = if t_max[0] < t_max[1] {
+                if t_max[0] < t_max[2] {
+                    0
+                } else {
+                    2
+                }
+            } else if t_max[1] < t_max[2] {
+                1
+            } else {
+                2
+            };
+            t = t_max[axis];
+            t_max[axis] += t_delta[axis];
+            cell[axis] += step[axis];
+            normal = Vector3::new(0, 0, 0);
+            normal[axis] = -step[axis];
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use material::MaterialId;
+    use voxel_grid::{QuantizedFloat, Voxel};
+
+    fn ground() -> VoxelGrid {
+        let mut vg = VoxelGrid::with_dimension(8);
+        vg.fill_region(
+            &Vector3::new(-2, -1, -2),
+            &Vector3::new(1, 0, 1),
+            &|pos: &WorldPos| {
+                if pos.y < 0 {
+                    Voxel::new_with_args(MaterialId::ROCK, QuantizedFloat::FULL)
+                } else {
+                    Voxel::new()
+                }
+            },
+        );
+        vg
+    }
+
+    #[test]
+    fn ray_hits_ground() {
+        let vg = ground();
+        let hit = vg
+            .raycast(
+                Vector3::new(-3.5, 5.0, 2.5),
+                Vector3::new(0.0, -1.0, 0.0),
+                10.0,
+            )
+            .unwrap();
+        assert_eq!(hit.world_pos, Vector3::new(-4, -1, 2));
+        assert_eq!(hit.normal, Vector3::new(0, 1, 0));
+        assert!((hit.distance - 5.0).abs() < 1e-5);
+        assert_eq!(hit.chunk, Vector3::new(-1, -1, 0));
+        assert_eq!(hit.voxel, Vector3::new(4, 7, 2));
+    }
+
+    #[test]
+    fn ray_crosses_chunks() {
+        let vg = ground();
+        let hit = vg
+            .raycast(
+                Vector3::new(-12.0, 3.0, -12.0),
+                Vector3::new(1.0, -0.5, 1.0),
+                100.0,
+            )
+            .unwrap();
+        assert_eq!(hit.world_pos.y, -1);
+        assert_eq!(hit.normal, Vector3::new(0, 1, 0));
+        assert!((hit.position.y - 0.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn ray_misses() {
+        let vg = ground();
+        let down = Vector3::new(0.0, -1.0, 0.0);
+        assert!(vg.raycast(Vector3::new(0.5, 5.0, 0.5), down, 4.5).is_none());
+        assert!(vg
+            .raycast(Vector3::new(0.5, 5.0, 0.5), -down, 50.0)
+            .is_none());
+        assert!(vg
+            .raycast(Vector3::new(50.5, 5.0, 0.5), down, 50.0)
+            .is_none());
+        assert!(vg
+            .raycast(Vector3::new(0.5, 5.0, 0.5), -down, f32::INFINITY)
+            .is_none());
+        assert!(vg
+            .raycast(Vector3::new(0.5, 5.0, 0.5), down, f32::NAN)
+            .is_none());
+        assert!(vg
+            .raycast(Vector3::new(0.5, 5.0, 0.5), down, -1.0)
+            .is_none());
+        // Unbounded rays still hit
+        let hit = vg
+            .raycast(Vector3::new(0.5, 5.0, 0.5), down, f32::INFINITY)
+            .unwrap();
+        assert_eq!(hit.world_pos, Vector3::new(0, -1, 0));
+    }
+
+    #[test]
+    fn ray_starts_inside() {
+        let vg = ground();
+        let hit = vg
+            .raycast(
+                Vector3::new(0.5, -0.5, 0.5),
+                Vector3::new(1.0, 0.0, 0.0),
+                1.0,
+            )
+            .unwrap();
+        assert_eq!(hit.distance, 0.0);
+        assert_eq!(hit.normal, Vector3::new(0, 0, 0));
+    }
+}
@@ -0,0 +1,340 @@
+use cgmath::Vector3;
+
+use voxel_grid::{Chunk, ChunkIndex, Voxel, VoxelGrid, VoxelIndex, WorldPos, AIR};
+
+/// Every voxel of a chunk with its index, in x-y-z order.
+pub struct ChunkVoxels<'a> {
+    chunk: &'a Chunk,
+    next: usize,
+    len: usize,
+}
+
+impl<'a> Iterator for ChunkVoxels<'a> {
+    type Item = (VoxelIndex, &'a Voxel);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.len {
+            return None;
+        }
+        let d = self.chunk.dimension() as usize;
+        let i = self.next;
+        self.next += 1;
+        let idx = Vector3::new((i % d) as u16, (i / d % d) as u16, (i / (d * d)) as u16);
+        Some((idx, self.chunk.voxel_ref(idx)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.len - self.next;
+        (n, Some(n))
+    }
+}
+
+impl<'a> ExactSizeIterator for ChunkVoxels<'a> {}
+
+/// Every voxel of a chunk in a grid with its world position, in x-y-z order.
+pub struct WorldVoxels<'a> {
+    voxels: ChunkVoxels<'a>,
+    origin: WorldPos,
+}
+
+impl<'a> Iterator for WorldVoxels<'a> {
+    type Item = (WorldPos, &'a Voxel);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let origin = self.origin;
+        self.voxels.next().map(|(i, v)| {
+            let pos = origin + Vector3::new(i32::from(i.x), i32::from(i.y), i32::from(i.z));
+            (pos, v)
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.voxels.size_hint()
+    }
+}
+
+impl<'a> ExactSizeIterator for WorldVoxels<'a> {}
+
+/// Every voxel in a box of world positions, in x-y-z order. Chunks that do
+/// not exist read as air.
+pub struct RegionVoxels<'a> {
+    grid: &'a VoxelGrid,
+    min: WorldPos,
+    max: WorldPos,
+    next: Option<WorldPos>,
+    // Last chunk looked up, most steps stay inside it
+    chunk: Option<(ChunkIndex, Option<&'a Chunk>)>,
+}
+
+impl<'a> Iterator for RegionVoxels<'a> {
+    type Item = (WorldPos, &'a Voxel);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pos = self.next?;
+        self.next = step(pos, &self.min, &self.max);
+
+        let (idx, voxel) = self.grid.locate(&pos);
+        let chunk = match self.chunk {
+            Some((cached, chunk)) if cached == idx => chunk,
+            _ => {
+                let chunk = self.grid.get_chunk(&idx);
+                self.chunk = Some((idx, chunk));
+                chunk
+            }
+        };
+        match chunk {
+            Some(c) => Some((pos, c.voxel_ref(voxel))),
+            None => Some((pos, &AIR)),
+        }
+    }
+}
+
+/// Position after `pos` when walking from `min` to `max` in x-y-z order.
+#[inline]
+fn step(pos: WorldPos, min: &WorldPos, max: &WorldPos) -> Option<WorldPos> {
+    let mut p = pos;
+    p.x += 1;
+    if p.x > max.x {
+        p.x = min.x;
+        p.y += 1;
+        if p.y > max.y {
+            p.y = min.y;
+            p.z += 1;
+            if p.z > max.z {
+                return None;
+            }
+        }
+    }
+    Some(p)
+}
+
+impl Chunk {
+    /// Every voxel with its index, in x-y-z order.
+    pub fn voxels<'a>(&'a self) -> ChunkVoxels<'a> {
+        let d = self.dimension() as usize;
+        ChunkVoxels {
+            chunk: self,
+            next: 0,
+            len: d * d * d,
+        }
+    }
+
+    /// Let `f` modify every voxel in turn. Voxels are handed out by value
+    /// and written back if `f` changed them, as storage is packed.
+    pub fn update<F>(&mut self, mut f: F)
+    where
+        F: FnMut(VoxelIndex, &mut Voxel),
+    {
+        let dim = self.dimension();
+        for z in 0..dim {
+            for y in 0..dim {
+                for x in 0..dim {
+                    let idx = Vector3::new(x, y, z);
+                    let mut v = self.get_voxel_at(idx);
+                    f(idx, &mut v);
+                    self.set_voxel_at(idx, v.get_material(), v.get_occupancy());
+                }
+            }
+        }
+    }
+}
+
+impl VoxelGrid {
+    /// First and last world position covered by a chunk.
+    pub fn chunk_bounds(&self, idx: &ChunkIndex) -> (WorldPos, WorldPos) {
+        let last = self.dimension() - 1;
+        (
+            self.world_pos(idx, &Vector3::new(0, 0, 0)),
+            self.world_pos(idx, &Vector3::new(last, last, last)),
+        )
+    }
+
+    /// Smallest box of world positions holding every chunk, `None` if the
+    /// grid is empty.
+    pub fn loaded_bounds(&self) -> Option<(WorldPos, WorldPos)> {
+        let mut indices = self.chunk_indices();
+        let first = *indices.next()?;
+        let (lo, hi) = indices.fold((first, first), |(lo, hi), i| {
+            (
+                Vector3::new(lo.x.min(i.x), lo.y.min(i.y), lo.z.min(i.z)),
+                Vector3::new(hi.x.max(i.x), hi.y.max(i.y), hi.z.max(i.z)),
+            )
+        });
+        Some((self.chunk_bounds(&lo).0, self.chunk_bounds(&hi).1))
+    }
+
+    /// Every voxel of a chunk with its world position, `None` if the chunk
+    /// does not exist.
+    pub fn chunk_voxels<'a>(&'a self, idx: &ChunkIndex) -> Option<WorldVoxels<'a>> {
+        let origin = self.chunk_bounds(idx).0;
+        self.get_chunk(idx).map(|c| WorldVoxels {
+            voxels: c.voxels(),
+            origin,
+        })
+    }
+
+    /// Every voxel from `min` to `max` (inclusive) with its world position,
+    /// across chunk borders. Empty if `min` is greater than `max` on any
+    /// axis.
+    pub fn region<'a>(&'a self, min: &WorldPos, max: &WorldPos) -> RegionVoxels<'a> {
+        let empty = min.x > max.x || min.y > max.y || min.z > max.z;
+        RegionVoxels {
+            grid: self,
+            min: *min,
+            max: *max,
+            next: if empty { None } else { Some(*min) },
+            chunk: None,
+        }
+    }
+
+    /// Let `f` modify every voxel from `min` to `max` (inclusive), as one
+    /// step of the undo history. Chunks are only created where `f` changes
+    /// a voxel that reads as air.
+    pub fn update_region<F>(&mut self, min: &WorldPos, max: &WorldPos, mut f: F)
+    where
+        F: FnMut(WorldPos, &mut Voxel),
+    {
+        let mut next = if min.x > max.x || min.y > max.y || min.z > max.z {
+            None
+        } else {
+            Some(*min)
+        };
+
+        self.begin_transaction();
+        while let Some(pos) = next {
+            let before = self.voxel_at(&pos);
+            let mut v = before;
+            f(pos, &mut v);
+            if v != before {
+                self.set_voxel(&pos, v.get_material(), v.get_occupancy());
+            }
+            next = step(pos, min, max);
+        }
+        self.commit_transaction();
+    }
+
+    /// Let `f` modify every voxel of a chunk, as one step of the undo
+    /// history. Returns false if the chunk does not exist.
+    pub fn update_chunk<F>(&mut self, idx: &ChunkIndex, f: F) -> bool
+    where
+        F: FnMut(WorldPos, &mut Voxel),
+    {
+        if self.get_chunk(idx).is_none() {
+            return false;
+        }
+        let (min, max) = self.chunk_bounds(idx);
+        self.update_region(&min, &max, f);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use material::MaterialId;
+    use voxel_grid::QuantizedFloat;
+
+    fn rock() -> Voxel {
+        Voxel::new_with_args(MaterialId::ROCK, QuantizedFloat::FULL)
+    }
+
+    fn layered() -> VoxelGrid {
+        let mut vg = VoxelGrid::with_dimension(4);
+        vg.fill_region(
+            &Vector3::new(-1, -1, -1),
+            &Vector3::new(0, 0, 0),
+            &|pos: &WorldPos| {
+                if pos.y < pos.x {
+                    rock()
+                } else {
+                    Voxel::new()
+                }
+            },
+        );
+        vg
+    }
+
+    #[test]
+    fn chunk_voxels_positions() {
+        let vg = layered();
+        let idx = Vector3::new(-1, 0, -1);
+        let all = vg.chunk_voxels(&idx).unwrap().collect::<Vec<_>>();
+        assert_eq!(all.len(), 64);
+        assert_eq!(all[0].0, Vector3::new(-4, 0, -4));
+        assert_eq!(all[1].0, Vector3::new(-3, 0, -4));
+        assert_eq!(all[63].0, Vector3::new(-1, 3, -1));
+        for (pos, v) in all {
+            assert_eq!(vg.locate(&pos).0, idx);
+            assert_eq!(*v, vg.voxel_at(&pos));
+        }
+        assert!(vg.chunk_voxels(&Vector3::new(5, 0, 0)).is_none());
+
+        let chunk = vg.get_chunk(&idx).unwrap();
+        assert_eq!(chunk.voxels().len(), 64);
+        assert!(chunk.voxels().all(|(i, v)| *v == chunk.get_voxel_at(i)));
+    }
+
+    #[test]
+    fn region_spans_chunks() {
+        let vg = layered();
+        let (min, max) = (Vector3::new(-3, -2, -1), Vector3::new(6, 1, 0));
+        let mut n = 0;
+        for (pos, v) in vg.region(&min, &max) {
+            // Chunks with x above 0 do not exist and read as air
+            assert_eq!(*v, vg.voxel_at(&pos));
+            n += 1;
+        }
+        assert_eq!(n, 10 * 4 * 2);
+        assert_eq!(vg.region(&max, &min).count(), 0);
+        assert_eq!(vg.region(&min, &min).count(), 1);
+    }
+
+    #[test]
+    fn update_region_is_one_step() {
+        let mut vg = layered();
+        let before = vg.clone();
+        vg.update_region(&Vector3::new(-2, -2, -2), &Vector3::new(5, 1, 1), |_, v| {
+            if v.get_material() == MaterialId::ROCK {
+                v.set_material(MaterialId::SNOW);
+            }
+        });
+        assert_eq!(vg.chunk_indices().count(), 8);
+        assert_eq!(
+            vg.voxel_at(&Vector3::new(1, 0, 1)).get_material(),
+            MaterialId::SNOW
+        );
+        assert_eq!(
+            vg.voxel_at(&Vector3::new(-3, -4, 0)).get_material(),
+            MaterialId::ROCK
+        );
+
+        assert!(vg.undo());
+        for (idx, c) in before.chunks() {
+            assert!(c.voxels().eq(vg.get_chunk(idx).unwrap().voxels()));
+        }
+        assert!(!vg.undo());
+
+        // Writing into missing chunks creates them
+        vg.update_region(&Vector3::new(7, 0, 0), &Vector3::new(8, 0, 0), |_, v| {
+            *v = rock()
+        });
+        assert_eq!(vg.chunk_indices().count(), 10);
+        assert!(!vg.update_chunk(&Vector3::new(9, 9, 9), |_, _| {}));
+    }
+
+    #[test]
+    fn chunk_update_and_chunks_mut() {
+        let mut vg = layered();
+        vg.clear_changes();
+        for (_, c) in vg.chunks_mut() {
+            c.update(|i, v| {
+                if i.x == 0 {
+                    *v = rock();
+                }
+            });
+        }
+        assert_eq!(vg.dirty_chunks().count(), 8);
+        assert_eq!(vg.voxel_at(&Vector3::new(-4, 3, 0)), rock());
+        assert_eq!(vg.voxel_at(&Vector3::new(-3, 3, 0)), Voxel::new());
+    }
+}
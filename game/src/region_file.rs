@@ -0,0 +1,436 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use cgmath::Vector3;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use grid_io::{self, GridHeader, GridIoError, FORMAT_VERSION, MATERIAL_TABLE_VERSION};
+use voxel_grid::{Chunk, ChunkIndex};
+
+const REGION_MAGIC: [u8; 4] = *b"VXLR";
+
+/// log2 of the number of chunks per side of a region.
+const REGION_SHIFT: i32 = 3;
+
+/// Chunks per side of a region.
+pub const REGION_SIZE: i32 = 1 << REGION_SHIFT;
+
+const SLOTS: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+
+/// Bytes before the slot table: region magic followed by a grid header.
+const HEADER_LEN: u64 = 4 + 14;
+
+/// Bytes per slot table entry: offset and length.
+const SLOT_LEN: u64 = 12;
+
+/// Bytes of a chunk record with a run per voxel: index and run count, then
+/// a run length, material and occupancy per run.
+fn max_record_len(dimension: u16) -> u64 {
+    let voxels = u64::from(dimension).pow(3);
+    16 + voxels * 7
+}
+
+/// Region files kept open by a `RegionStore`.
+const MAX_OPEN_REGIONS: usize = 16;
+
+/// Position of a region, in units of whole regions.
+pub type RegionIndex = Vector3<i32>;
+
+/// The region holding a chunk and the chunk's slot in it.
+#[inline]
+pub fn region_slot(idx: &ChunkIndex) -> (RegionIndex, usize) {
+    let mask = REGION_SIZE - 1;
+    let region = Vector3::new(
+        idx.x >> REGION_SHIFT,
+        idx.y >> REGION_SHIFT,
+        idx.z >> REGION_SHIFT,
+    );
+    let (x, y, z) = (idx.x & mask, idx.y & mask, idx.z & mask);
+    (region, (x + REGION_SIZE * (y + REGION_SIZE * z)) as usize)
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Slot {
+    offset: u64,
+    len: u32,
+}
+
+/// File holding the chunks of one region. Each chunk is stored as a
+/// compressed grid chunk record, found through a slot table at the start of
+/// the file. Records are only ever appended and the slot is pointed at a
+/// new record once it is on disk, so a crash while saving keeps the old
+/// record. The space of old records is not reused.
+#[derive(Debug)]
+pub struct RegionFile {
+    file: File,
+    header: GridHeader,
+    slots: Vec<Slot>,
+}
+
+impl RegionFile {
+    /// Open a region file, creating it if it does not exist. Files written
+    /// with an older format are rewritten with the current one.
+    pub fn open<P: AsRef<Path>>(path: P, dimension: u16) -> Result<RegionFile, GridIoError> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return RegionFile::create(path, dimension);
+        }
+
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+        if magic != REGION_MAGIC {
+            return Err(GridIoError::NotAGrid);
+        }
+        let header = GridHeader::read(&mut file)?;
+        if header.chunk_dimension != dimension {
+            return Err(GridIoError::Corrupt(
+                "region has a different chunk dimension",
+            ));
+        }
+        if header.chunk_count as usize != SLOTS {
+            return Err(GridIoError::Corrupt("region has the wrong number of slots"));
+        }
+
+        let mut table = vec![0u8; SLOTS * SLOT_LEN as usize];
+        file.read_exact(&mut table)?;
+        let mut r = &table[..];
+        let mut slots = Vec::with_capacity(SLOTS);
+        for _ in 0..SLOTS {
+            slots.push(Slot {
+                offset: r.read_u64::<LittleEndian>()?,
+                len: r.read_u32::<LittleEndian>()?,
+            });
+        }
+
+        let region = RegionFile {
+            file,
+            header,
+            slots,
+        };
+        if header.version != FORMAT_VERSION
+            || header.material_table_version != MATERIAL_TABLE_VERSION
+        {
+            return region.upgrade(path);
+        }
+        Ok(region)
+    }
+
+    fn create(path: &Path, dimension: u16) -> Result<RegionFile, GridIoError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let header = GridHeader::current(dimension, SLOTS as u32);
+        file.write_all(&REGION_MAGIC)?;
+        header.write(&mut file)?;
+        file.write_all(&vec![0u8; SLOTS * SLOT_LEN as usize])?;
+        Ok(RegionFile {
+            file,
+            header,
+            slots: vec![Slot::default(); SLOTS],
+        })
+    }
+
+    /// Rewrite the chunks to a new file that replaces the original only
+    /// once it is complete, so a failed upgrade keeps the old file.
+    fn upgrade(mut self, path: &Path) -> Result<RegionFile, GridIoError> {
+        let mut chunks = Vec::new();
+        for slot in 0..SLOTS {
+            if let Some(c) = self.read(slot)? {
+                chunks.push((slot, c));
+            }
+        }
+        let dimension = self.header.chunk_dimension;
+        drop(self);
+
+        let upgraded = path.with_extension("vxr.upgrade");
+        let written = RegionFile::create(&upgraded, dimension).and_then(|mut region| {
+            for (slot, (idx, chunk)) in chunks {
+                region.write(slot, &idx, &chunk)?;
+            }
+            region.file.sync_all()?;
+            Ok(())
+        });
+        if let Err(e) = written {
+            let _ = fs::remove_file(&upgraded);
+            return Err(e);
+        }
+        fs::rename(&upgraded, path)?;
+        RegionFile::open(path, dimension)
+    }
+
+    /// The chunk in `slot` with the index it was stored under.
+    pub fn read(&mut self, slot: usize) -> Result<Option<(ChunkIndex, Chunk)>, GridIoError> {
+        let Slot { offset, len } = self.slots[slot];
+        if len == 0 {
+            return Ok(None);
+        }
+        // Compressing may add a few bytes per block to incompressible data
+        let max_record = max_record_len(self.header.chunk_dimension);
+        if u64::from(len) > max_record + max_record / 1024 + 64 {
+            return Err(GridIoError::Corrupt("chunk record is too long"));
+        }
+        let mut bytes = vec![0u8; len as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut bytes)?;
+        let mut body = ZlibDecoder::new(&bytes[..]);
+        grid_io::read_chunk_record(&mut body, &self.header).map(Some)
+    }
+
+    pub fn write(
+        &mut self,
+        slot: usize,
+        idx: &ChunkIndex,
+        chunk: &Chunk,
+    ) -> Result<(), GridIoError> {
+        if chunk.dimension() != self.header.chunk_dimension {
+            return Err(GridIoError::Corrupt("chunk has a different dimension"));
+        }
+        let mut body = ZlibEncoder::new(Vec::new(), Compression::default());
+        grid_io::write_chunk(&mut body, idx, chunk)?;
+        let bytes = body.finish()?;
+
+        let offset = self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&bytes)?;
+        self.file.sync_data()?;
+
+        let entry = Slot {
+            offset,
+            len: bytes.len() as u32,
+        };
+        self.file
+            .seek(SeekFrom::Start(HEADER_LEN + slot as u64 * SLOT_LEN))?;
+        self.file.write_u64::<LittleEndian>(entry.offset)?;
+        self.file.write_u32::<LittleEndian>(entry.len)?;
+        self.file.sync_data()?;
+        self.slots[slot] = entry;
+        Ok(())
+    }
+
+    /// Number of chunks stored in the region.
+    pub fn len(&self) -> usize {
+        self.slots.iter().filter(|s| s.len > 0).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Directory of region files, one per region that ever had a chunk saved.
+#[derive(Debug)]
+pub struct RegionStore {
+    dir: PathBuf,
+    dimension: u16,
+    files: HashMap<RegionIndex, RegionFile>,
+}
+
+impl RegionStore {
+    /// Store in `dir` for chunks with `dimension` voxels per side. The
+    /// directory is created if needed.
+    pub fn open<P: Into<PathBuf>>(dir: P, dimension: u16) -> io::Result<RegionStore> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(RegionStore {
+            dir,
+            dimension,
+            files: HashMap::new(),
+        })
+    }
+
+    #[inline]
+    pub fn dimension(&self) -> u16 {
+        self.dimension
+    }
+
+    /// A stored chunk, `None` if it was never saved.
+    pub fn load(&mut self, idx: &ChunkIndex) -> Result<Option<Chunk>, GridIoError> {
+        let (region, slot) = region_slot(idx);
+        if !self.files.contains_key(&region) && !self.path(&region).exists() {
+            return Ok(None);
+        }
+        match self.file(&region)?.read(slot)? {
+            Some((stored, _)) if stored != *idx => {
+                Err(GridIoError::Corrupt("chunk stored in the wrong slot"))
+            }
+            Some((_, chunk)) => Ok(Some(chunk)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn save(&mut self, idx: &ChunkIndex, chunk: &Chunk) -> Result<(), GridIoError> {
+        let (region, slot) = region_slot(idx);
+        self.file(&region)?.write(slot, idx, chunk)
+    }
+
+    fn file(&mut self, region: &RegionIndex) -> Result<&mut RegionFile, GridIoError> {
+        if !self.files.contains_key(region) {
+            if self.files.len() >= MAX_OPEN_REGIONS {
+                self.files.clear();
+            }
+            let file = RegionFile::open(self.path(region), self.dimension)?;
+            self.files.insert(*region, file);
+        }
+        Ok(self.files.get_mut(region).unwrap())
+    }
+
+    fn path(&self, region: &RegionIndex) -> PathBuf {
+        self.dir
+            .join(format!("r.{}.{}.{}.vxr", region.x, region.y, region.z))
+    }
+}
+
+/// A directory of this process for the files of a test, removed if it
+/// exists.
+#[cfg(test)]
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = ::std::env::temp_dir().join(format!("vallen-{}-{}", name, ::std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use material::MaterialId;
+    use voxel_grid::{QuantizedFloat, Voxel};
+
+    fn chunk(seed: u16) -> Chunk {
+        Chunk::from_fn(8, |i| {
+            if (i.x + i.y * seed + i.z) % 3 == 0 {
+                Voxel::new_with_args(MaterialId::SNOW, QuantizedFloat::from_f32(0.7))
+            } else {
+                Voxel::new()
+            }
+        })
+    }
+
+    fn same(a: &Chunk, b: &Chunk) -> bool {
+        a.voxels().eq(b.voxels())
+    }
+
+    #[test]
+    fn region_slots() {
+        assert_eq!(
+            region_slot(&Vector3::new(0, 0, 0)),
+            (Vector3::new(0, 0, 0), 0)
+        );
+        assert_eq!(
+            region_slot(&Vector3::new(9, 1, 0)),
+            (Vector3::new(1, 0, 0), 9)
+        );
+        assert_eq!(
+            region_slot(&Vector3::new(-1, -8, -9)),
+            (Vector3::new(-1, -1, -2), 7 + 7 * 64)
+        );
+    }
+
+    #[test]
+    fn region_store_roundtrip() {
+        let dir = temp_dir("region-store");
+        let a = Vector3::new(-3, 2, 17);
+        let b = Vector3::new(-4, 2, 17);
+        {
+            let mut store = RegionStore::open(dir.clone(), 8).unwrap();
+            assert!(store.load(&a).unwrap().is_none());
+            store.save(&a, &chunk(1)).unwrap();
+            store.save(&b, &Chunk::new(8)).unwrap();
+            store.save(&b, &chunk(2)).unwrap();
+            store.save(&a, &Chunk::new(8)).unwrap();
+            assert!(store.save(&a, &Chunk::new(4)).is_err());
+        }
+
+        let mut store = RegionStore::open(dir.clone(), 8).unwrap();
+        assert!(same(&store.load(&a).unwrap().unwrap(), &Chunk::new(8)));
+        assert!(same(&store.load(&b).unwrap().unwrap(), &chunk(2)));
+        assert!(store.load(&Vector3::new(-3, 3, 17)).unwrap().is_none());
+        assert!(RegionStore::open(dir.clone(), 16)
+            .unwrap()
+            .load(&a)
+            .is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Overwrite bytes of a region file.
+    fn patch(path: &Path, offset: u64, bytes: &[u8]) {
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn region_upgrade() {
+        let dir = temp_dir("region-upgrade");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("r.0.0.0.vxr");
+        let idx = Vector3::new(1, 0, 0);
+        RegionFile::create(&path, 8)
+            .unwrap()
+            .write(1, &idx, &chunk(1))
+            .unwrap();
+        // Material table version 1 stored the index into the old enum,
+        // where 2 was snow as well
+        patch(&path, 12, &[1, 0]);
+
+        let mut region = RegionFile::open(&path, 8).unwrap();
+        assert_eq!(region.header.material_table_version, MATERIAL_TABLE_VERSION);
+        let (stored, c) = region.read(1).unwrap().unwrap();
+        assert_eq!(stored, idx);
+        assert!(same(&c, &chunk(1)));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn region_keeps_old_records() {
+        let dir = temp_dir("region-old-records");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("r.0.0.0.vxr");
+        let idx = Vector3::new(0, 0, 0);
+        let mut region = RegionFile::create(&path, 8).unwrap();
+        region.write(0, &idx, &chunk(1)).unwrap();
+        let old = region.slots[0];
+        region.write(0, &idx, &Chunk::new(8)).unwrap();
+        drop(region);
+
+        // A save interrupted before its slot was updated finds the old
+        // record untouched
+        let mut entry = Vec::new();
+        entry.write_u64::<LittleEndian>(old.offset).unwrap();
+        entry.write_u32::<LittleEndian>(old.len).unwrap();
+        patch(&path, HEADER_LEN, &entry);
+        let (_, c) = RegionFile::open(&path, 8)
+            .unwrap()
+            .read(0)
+            .unwrap()
+            .unwrap();
+        assert!(same(&c, &chunk(1)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn region_rejects_long_records() {
+        let dir = temp_dir("region-long");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("r.0.0.0.vxr");
+        RegionFile::create(&path, 8)
+            .unwrap()
+            .write(0, &Vector3::new(0, 0, 0), &chunk(1))
+            .unwrap();
+        patch(&path, HEADER_LEN + 8, &[0xff, 0xff, 0xff, 0xff]);
+
+        match RegionFile::open(&path, 8).unwrap().read(0) {
+            Err(GridIoError::Corrupt(_)) => {}
+            r => panic!("unexpected {:?}", r.map(|c| c.map(|c| c.0))),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
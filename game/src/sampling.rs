@@ -0,0 +1,133 @@
+use cgmath::{InnerSpace, Vector3};
+
+use voxel_grid::{VoxelGrid, WorldPos};
+
+impl VoxelGrid {
+    /// Occupancy at a point in voxel units, trilinearly interpolated between
+    /// voxel centres. Voxel `i` covers `[i, i + 1)` and is sampled at its
+    /// centre. Chunks that do not exist are treated as air.
+    pub fn sample(&self, pos: Vector3<f32>) -> f32 {
+        let q = pos - Vector3::new(0.5, 0.5, 0.5);
+        let base = Vector3::new(q.x.floor(), q.y.floor(), q.z.floor());
+        let t = q - base;
+        let c = self.corners(&Vector3::new(base.x as i32, base.y as i32, base.z as i32));
+
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let x00 = lerp(c[0], c[1], t.x);
+        let x10 = lerp(c[2], c[3], t.x);
+        let x01 = lerp(c[4], c[5], t.x);
+        let x11 = lerp(c[6], c[7], t.x);
+        lerp(lerp(x00, x10, t.y), lerp(x01, x11, t.y), t.z)
+    }
+
+    /// Gradient of `sample`, pointing towards increasing occupancy, i.e. into
+    /// the terrain. Estimated with central differences one voxel apart.
+    pub fn gradient(&self, pos: Vector3<f32>) -> Vector3<f32> {
+        let dx = Vector3::new(0.5, 0.0, 0.0);
+        let dy = Vector3::new(0.0, 0.5, 0.0);
+        let dz = Vector3::new(0.0, 0.0, 0.5);
+        Vector3::new(
+            self.sample(pos + dx) - self.sample(pos - dx),
+            self.sample(pos + dy) - self.sample(pos - dy),
+            self.sample(pos + dz) - self.sample(pos - dz),
+        )
+    }
+
+    /// Unit normal of the terrain surface near `pos`, pointing out of the
+    /// terrain. `None` where occupancy does not change, e.g. deep inside rock.
+    pub fn surface_normal(&self, pos: Vector3<f32>) -> Option<Vector3<f32>> {
+        let g = self.gradient(pos);
+        if g.magnitude2() < 1e-12 {
+            return None;
+        }
+        Some(-g.normalize())
+    }
+
+    /// Occupancy of the eight voxels from `base` to `base + (1, 1, 1)`, with
+    /// x changing fastest.
+    fn corners(&self, base: &WorldPos) -> [f32; 8] {
+        let mut c = [0.0; 8];
+        let (chunk, voxel) = self.locate(base);
+        let last = self.dimension() - 1;
+        if voxel.x < last && voxel.y < last && voxel.z < last {
+            // All corners are in the same chunk
+            if let Some(ch) = self.get_chunk(&chunk) {
+                for (i, v) in c.iter_mut().enumerate() {
+                    let i = i as u16;
+                    let idx = voxel + Vector3::new(i & 1, (i >> 1) & 1, i >> 2);
+                    *v = ch.get_voxel_at(idx).get_occupancy_as_f32();
+                }
+            }
+            return c;
+        }
+
+        for (i, v) in c.iter_mut().enumerate() {
+            let i = i as i32;
+            let p = base + Vector3::new(i & 1, (i >> 1) & 1, i >> 2);
+            *v = self.voxel_at(&p).get_occupancy_as_f32();
+        }
+        c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use material::MaterialId;
+    use voxel_grid::{QuantizedFloat, Voxel, ISO_LEVEL};
+
+    fn ground() -> VoxelGrid {
+        let mut vg = VoxelGrid::with_dimension(8);
+        vg.fill_region(
+            &Vector3::new(-1, -1, -1),
+            &Vector3::new(0, 0, 0),
+            &|pos: &WorldPos| {
+                if pos.y < 0 {
+                    Voxel::new_with_args(MaterialId::ROCK, QuantizedFloat::FULL)
+                } else {
+                    Voxel::new()
+                }
+            },
+        );
+        vg
+    }
+
+    #[test]
+    fn sample_ground() {
+        let vg = ground();
+        assert_eq!(vg.sample(Vector3::new(0.3, -4.0, 2.0)), 1.0);
+        assert_eq!(vg.sample(Vector3::new(0.3, 4.0, 2.0)), 0.0);
+        assert!((vg.sample(Vector3::new(-3.7, 0.0, 5.1)) - ISO_LEVEL).abs() < 1e-6);
+        assert!((vg.sample(Vector3::new(1.0, 0.25, -1.0)) - 0.25).abs() < 1e-6);
+
+        let n = vg.surface_normal(Vector3::new(-0.5, 0.0, 0.5)).unwrap();
+        assert!((n - Vector3::new(0.0, 1.0, 0.0)).magnitude() < 1e-5);
+        assert!(vg.surface_normal(Vector3::new(2.0, -5.0, 2.0)).is_none());
+    }
+
+    #[test]
+    fn sample_across_chunks() {
+        // Occupancy ramps up along x through several chunks
+        let mut vg = VoxelGrid::with_dimension(4);
+        vg.fill_region(
+            &Vector3::new(-2, 0, 0),
+            &Vector3::new(1, 0, 0),
+            &|pos: &WorldPos| {
+                let occ = (pos.x + 8) as f32 / 16.0;
+                Voxel::new_with_args(MaterialId::SNOW, QuantizedFloat::from_f32(occ))
+            },
+        );
+        let step = 1.0 / QuantizedFloat::FULL.value as f32;
+        let mut x = -7.5;
+        while x < 7.5 {
+            let expected = (x - 0.5 + 8.0) / 16.0;
+            let s = vg.sample(Vector3::new(x, 1.5, 1.5));
+            assert!((s - expected).abs() <= step, "{} {}", x, s);
+            if x >= -7.0 && x < 7.0 {
+                let g = vg.gradient(Vector3::new(x, 1.5, 1.5));
+                assert!((g.x - 1.0 / 16.0).abs() <= 2.0 * step);
+            }
+            x += 0.25;
+        }
+    }
+}
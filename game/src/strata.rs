@@ -0,0 +1,255 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use ron;
+
+use material::MaterialId;
+use voxel_grid::{QuantizedFloat, Voxel, VoxelGenerator, WorldPos};
+
+#[derive(Debug)]
+pub enum StrataError {
+    Io(io::Error),
+    Parse(ron::de::Error),
+}
+
+impl fmt::Display for StrataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StrataError::Io(ref e) => write!(f, "i/o error: {}", e),
+            StrataError::Parse(ref e) => write!(f, "invalid strata rules: {}", e),
+        }
+    }
+}
+
+impl Error for StrataError {
+    fn description(&self) -> &str {
+        match *self {
+            StrataError::Io(_) => "i/o error",
+            StrataError::Parse(_) => "invalid strata rules",
+        }
+    }
+}
+
+impl From<io::Error> for StrataError {
+    fn from(e: io::Error) -> Self {
+        StrataError::Io(e)
+    }
+}
+
+impl From<ron::de::Error> for StrataError {
+    fn from(e: ron::de::Error) -> Self {
+        StrataError::Parse(e)
+    }
+}
+
+/// A layer of loose material covering the rock.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Layer {
+    pub material: MaterialId,
+    /// Thickness on flat ground, in voxels.
+    pub depth: f32,
+    /// Thickness lost per unit of slope, so steep faces expose what lies
+    /// underneath.
+    #[serde(default)]
+    pub thinning: f32,
+}
+
+/// Which material fills terrain at which depth. Rules missing from a RON
+/// file keep their default.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct StrataRules {
+    /// Layers from the surface down.
+    pub layers: Vec<Layer>,
+    /// Material under the layers.
+    pub rock: MaterialId,
+    /// Material at the bottom of the terrain.
+    pub bedrock: MaterialId,
+    /// Thickness of the bedrock, in voxels.
+    pub bedrock_depth: f32,
+    /// Material replacing the top layer where the surface is above
+    /// `snow_line`.
+    pub snow: MaterialId,
+    pub snow_line: Option<f32>,
+}
+
+impl Default for StrataRules {
+    fn default() -> Self {
+        StrataRules {
+            layers: vec![Layer {
+                material: MaterialId::GRASS,
+                depth: 2.0,
+                thinning: 2.0,
+            }],
+            rock: MaterialId::ROCK,
+            bedrock: MaterialId::BEDROCK,
+            bedrock_depth: 2.0,
+            snow: MaterialId::SNOW,
+            snow_line: None,
+        }
+    }
+}
+
+impl StrataRules {
+    pub fn from_ron(s: &str) -> Result<Self, StrataError> {
+        Ok(ron::de::from_str(s)?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, StrataError> {
+        let mut s = String::new();
+        File::open(path)?.read_to_string(&mut s)?;
+        StrataRules::from_ron(&s)
+    }
+
+    /// Thickness of all layers on flat ground, the depth below which there
+    /// is only rock.
+    pub fn soil_depth(&self) -> f32 {
+        self.layers.iter().map(|l| l.depth.max(0.0)).sum()
+    }
+
+    /// Material of the voxel starting at height `y`, under a surface at
+    /// `surface` sloping by `slope` and above a terrain bottom at `floor`.
+    pub fn material(&self, y: f32, floor: f32, surface: f32, slope: f32) -> MaterialId {
+        if y < floor + self.bedrock_depth {
+            return self.bedrock;
+        }
+        let depth = surface - y;
+        let mut top = 0.0;
+        for (i, layer) in self.layers.iter().enumerate() {
+            top += (layer.depth - layer.thinning * slope).max(0.0);
+            if depth <= top {
+                return match self.snow_line {
+                    Some(line) if i == 0 && surface > line => self.snow,
+                    _ => layer.material,
+                };
+            }
+        }
+        self.rock
+    }
+}
+
+/// Fills the columns under the surface `height(x, z)` with the layers of a
+/// `StrataRules`, down to `floor`.
+pub struct Strata<'a, H> {
+    rules: &'a StrataRules,
+    floor: f32,
+    height: H,
+}
+
+impl<'a, H> Strata<'a, H>
+where
+    H: Fn(f32, f32) -> f32 + Sync,
+{
+    pub fn new(rules: &'a StrataRules, floor: f32, height: H) -> Self {
+        Strata {
+            rules,
+            floor,
+            height,
+        }
+    }
+
+    /// Steepness of the surface at a column centre, as height per column.
+    /// Neighbours without a surface are ignored.
+    fn slope(&self, x: f32, z: f32, h: f32) -> f32 {
+        let at = |x, z| {
+            let n = (self.height)(x, z);
+            if n.is_nan() {
+                h
+            } else {
+                n
+            }
+        };
+        let dx = (at(x + 1.0, z) - at(x - 1.0, z)) / 2.0;
+        let dz = (at(x, z + 1.0) - at(x, z - 1.0)) / 2.0;
+        (dx * dx + dz * dz).sqrt()
+    }
+}
+
+impl<'a, H> VoxelGenerator for Strata<'a, H>
+where
+    H: Fn(f32, f32) -> f32 + Sync,
+{
+    fn generate(&self, pos: &WorldPos) -> Voxel {
+        let (x, y, z) = (pos.x as f32 + 0.5, pos.y as f32, pos.z as f32 + 0.5);
+        let h = (self.height)(x, z);
+        let occupancy = (h - y).max(0.0).min(1.0);
+        // Also true for NaN heights, which leave the column empty
+        if occupancy <= 0.0 {
+            return Voxel::new();
+        }
+        // Only the layers depend on the slope
+        let slope = if h - y > self.rules.soil_depth() {
+            0.0
+        } else {
+            self.slope(x, z, h)
+        };
+        let material = self.rules.material(y, self.floor, h, slope);
+        Voxel::new_with_args(material, QuantizedFloat::from_f32(occupancy))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::Vector3;
+    use voxel_grid::VoxelGrid;
+
+    #[test]
+    fn strata_rules_ron() {
+        let rules = StrataRules::from_ron(
+            "(layers: [(material: 2, depth: 1.0), (material: 1, depth: 3.0, thinning: 4.0)])",
+        )
+        .unwrap();
+        assert_eq!(rules.layers.len(), 2);
+        assert_eq!(rules.layers[0].thinning, 0.0);
+        assert_eq!(rules.soil_depth(), 4.0);
+        assert_eq!(rules.bedrock, StrataRules::default().bedrock);
+        assert!(StrataRules::from_ron("(layers: [(depth: 1.0)])").is_err());
+        StrataRules::load(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/strata.ron")).unwrap();
+    }
+
+    #[test]
+    fn strata_layers() {
+        let rules = StrataRules {
+            snow_line: Some(20.0),
+            ..StrataRules::default()
+        };
+        // Flat ground at 10 above a floor at 0
+        assert_eq!(rules.material(9.0, 0.0, 10.0, 0.0), MaterialId::GRASS);
+        assert_eq!(rules.material(8.0, 0.0, 10.0, 0.0), MaterialId::GRASS);
+        assert_eq!(rules.material(7.0, 0.0, 10.0, 0.0), MaterialId::ROCK);
+        assert_eq!(rules.material(2.0, 0.0, 10.0, 0.0), MaterialId::ROCK);
+        assert_eq!(rules.material(1.0, 0.0, 10.0, 0.0), MaterialId::BEDROCK);
+        // Soil thins on slopes and is gone from a 45 degree face
+        assert_eq!(rules.material(8.0, 0.0, 10.0, 0.5), MaterialId::ROCK);
+        assert_eq!(rules.material(9.0, 0.0, 10.0, 0.5), MaterialId::GRASS);
+        assert_eq!(rules.material(9.5, 0.0, 10.0, 1.0), MaterialId::ROCK);
+        // Snow above the snow line, over the rock
+        assert_eq!(rules.material(20.0, 0.0, 21.0, 0.0), MaterialId::SNOW);
+        assert_eq!(rules.material(18.0, 0.0, 21.0, 0.0), MaterialId::ROCK);
+    }
+
+    #[test]
+    fn strata_fill() {
+        // Flat ground at 6 for x < 8, a 45 degree ramp beyond
+        let height = |x: f32, _z: f32| if x < 8.0 { 6.0 } else { x - 2.0 };
+        let rules = StrataRules::default();
+        let mut vg = VoxelGrid::with_dimension(16);
+        vg.fill_region(
+            &Vector3::new(0, 0, 0),
+            &Vector3::new(0, 0, 0),
+            &Strata::new(&rules, 0.0, height),
+        );
+        let at = |x, y| vg.voxel_at(&Vector3::new(x, y, 4)).get_material();
+        assert_eq!(at(2, 5), MaterialId::GRASS);
+        assert_eq!(at(2, 4), MaterialId::GRASS);
+        assert_eq!(at(2, 3), MaterialId::ROCK);
+        assert_eq!(at(2, 1), MaterialId::BEDROCK);
+        assert_eq!(at(2, 6), MaterialId::AIR);
+        assert_eq!(at(12, 9), MaterialId::ROCK);
+        assert_eq!(at(12, 0), MaterialId::BEDROCK);
+    }
+}
@@ -0,0 +1,617 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+
+use amethyst::ecs::{Fetch, FetchMut, System};
+use cgmath::Vector3;
+
+use grid_io::GridIoError;
+use region_file::RegionStore;
+use terrain::Terrain;
+use voxel_grid::{Chunk, ChunkIndex, VoxelError, VoxelGrid, VoxelIndex};
+
+/// Chunks within this many chunks of a point of interest are kept loaded.
+pub const DEFAULT_LOAD_RADIUS: i32 = 4;
+
+/// Bytes of voxel data kept in memory before chunks are evicted.
+pub const DEFAULT_MEMORY_BUDGET: usize = 256 * 1024 * 1024;
+
+/// Positions, in voxel units, around which chunks are kept loaded, such as
+/// the camera and active skiers. Available to systems as a resource.
+#[derive(Debug, Clone, Default)]
+pub struct PointsOfInterest(pub Vec<Vector3<f32>>);
+
+#[derive(Debug)]
+pub enum StreamError {
+    Load(ChunkIndex, GridIoError),
+    Save(ChunkIndex, GridIoError),
+    /// The streaming thread stopped, nothing more is loaded or saved.
+    Stopped,
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StreamError::Load(idx, ref e) => write!(f, "failed to load chunk {:?}: {}", idx, e),
+            StreamError::Save(idx, ref e) => write!(f, "failed to save chunk {:?}: {}", idx, e),
+            StreamError::Stopped => write!(f, "chunk streaming thread stopped"),
+        }
+    }
+}
+
+impl Error for StreamError {
+    fn description(&self) -> &str {
+        match *self {
+            StreamError::Load(..) => "failed to load chunk",
+            StreamError::Save(..) => "failed to save chunk",
+            StreamError::Stopped => "chunk streaming thread stopped",
+        }
+    }
+}
+
+enum Request {
+    Load(ChunkIndex),
+    Save(ChunkIndex, Chunk),
+    /// Answered once every earlier request is done.
+    Flush(Sender<()>),
+}
+
+enum Reply {
+    Loaded(ChunkIndex, Result<Option<Chunk>, GridIoError>),
+    SaveFailed(ChunkIndex, GridIoError),
+}
+
+/// Pages the chunks of a `VoxelGrid` to region files. Chunks near points of
+/// interest are loaded on a background thread, and when the grid uses more
+/// than its memory budget the least recently wanted chunks are saved and
+/// evicted. Chunks near a point of interest are never evicted.
+pub struct ChunkStreamer {
+    requests: Option<Sender<Request>>,
+    replies: Receiver<Reply>,
+    worker: Option<JoinHandle<()>>,
+    /// Chunks requested but not yet loaded.
+    pending: HashSet<ChunkIndex>,
+    /// Wanted chunks that are not stored, so not requested again.
+    missing: HashSet<ChunkIndex>,
+    /// Frame each chunk was last wanted.
+    last_used: HashMap<ChunkIndex, u64>,
+    frame: u64,
+    radius: i32,
+    budget: usize,
+    errors: Vec<StreamError>,
+}
+
+impl ChunkStreamer {
+    /// Stream chunks with `dimension` voxels per side to region files in
+    /// `dir`, which is created if needed.
+    pub fn new<P: Into<PathBuf>>(dir: P, dimension: u16) -> io::Result<ChunkStreamer> {
+        let store = RegionStore::open(dir, dimension)?;
+        let (requests, worker_requests) = mpsc::channel();
+        let (worker_replies, replies) = mpsc::channel();
+        let worker = thread::Builder::new()
+            .name("chunk streaming".to_owned())
+            .spawn(move || serve(store, &worker_requests, &worker_replies))?;
+        Ok(ChunkStreamer {
+            requests: Some(requests),
+            replies,
+            worker: Some(worker),
+            pending: HashSet::new(),
+            missing: HashSet::new(),
+            last_used: HashMap::new(),
+            frame: 0,
+            radius: DEFAULT_LOAD_RADIUS,
+            budget: DEFAULT_MEMORY_BUDGET,
+            errors: Vec::new(),
+        })
+    }
+
+    pub fn with_radius(mut self, radius: i32) -> Self {
+        self.radius = radius;
+        self
+    }
+
+    pub fn with_budget(mut self, bytes: usize) -> Self {
+        self.budget = bytes;
+        self
+    }
+
+    /// Insert the chunks loaded since the last update, request the chunks
+    /// near `points` that are not loaded, nearest first, and evict chunks
+    /// until the grid is within budget. Chunks created by edits are loaded
+    /// too, with the edits kept on top.
+    pub fn update(&mut self, grid: &mut VoxelGrid, points: &[Vector3<f32>]) {
+        self.frame += 1;
+        self.receive(grid);
+        self.load_created(grid);
+
+        let wanted = self.wanted(grid, points);
+        for idx in &wanted {
+            self.last_used.insert(*idx, self.frame);
+            let loaded = grid.get_chunk(idx).is_some();
+            if !loaded && !self.pending.contains(idx) && !self.missing.contains(idx) {
+                self.pending.insert(*idx);
+                self.send(Request::Load(*idx));
+            }
+        }
+
+        let wanted = wanted.into_iter().collect::<HashSet<_>>();
+        self.missing.retain(|idx| wanted.contains(idx));
+        self.evict(grid, &wanted);
+    }
+
+    /// Save every chunk of the grid and wait until they are written. Fails
+    /// if the streaming thread stopped, chunks that failed to save are
+    /// reported by `take_errors`.
+    pub fn flush(&mut self, grid: &mut VoxelGrid) -> Result<(), StreamError> {
+        // Chunks created by edits are merged with the stored ones first, so
+        // they are not saved over them
+        self.load_created(grid);
+        self.wait()?;
+        self.receive(grid);
+
+        let chunks = grid
+            .chunks()
+            .map(|(idx, c)| (*idx, c.clone()))
+            .collect::<Vec<_>>();
+        for (idx, chunk) in chunks {
+            self.missing.remove(&idx);
+            self.send(Request::Save(idx, chunk));
+        }
+        self.wait()?;
+        self.receive(grid);
+        Ok(())
+    }
+
+    /// Wait until every request sent so far is done.
+    fn wait(&mut self) -> Result<(), StreamError> {
+        let (done, wait) = mpsc::channel();
+        if !self.send(Request::Flush(done)) || wait.recv().is_err() {
+            return Err(StreamError::Stopped);
+        }
+        Ok(())
+    }
+
+    /// Request the chunks created by edits that are not being loaded yet.
+    /// Until they are loaded they are not evicted.
+    fn load_created(&mut self, grid: &VoxelGrid) {
+        let created = grid
+            .created_chunks()
+            .filter(|idx| !self.pending.contains(idx))
+            .cloned()
+            .collect::<Vec<_>>();
+        for idx in created {
+            self.pending.insert(idx);
+            self.send(Request::Load(idx));
+        }
+    }
+
+    /// True while chunks are being loaded.
+    #[inline]
+    pub fn is_loading(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Chunks that failed to load or save since the last call.
+    pub fn take_errors(&mut self) -> Vec<StreamError> {
+        self.errors.drain(..).collect()
+    }
+
+    fn receive(&mut self, grid: &mut VoxelGrid) {
+        while let Ok(reply) = self.replies.try_recv() {
+            match reply {
+                Reply::Loaded(idx, result) => {
+                    self.pending.remove(&idx);
+                    match result {
+                        // The chunk may have been edited into existence while
+                        // it loaded, keep those edits on top of the terrain.
+                        // A chunk inserted meanwhile replaces the stored one.
+                        Ok(Some(mut chunk)) => {
+                            let inserted = match (grid.take_written(&idx), grid.get_chunk(&idx)) {
+                                (Some(written), Some(edited)) => {
+                                    merge_edits(&mut chunk, edited, &written)
+                                        .and_then(|_| grid.try_insert_chunk(&idx, chunk))
+                                }
+                                (_, Some(_)) => Ok(()),
+                                (_, None) => grid.try_insert_chunk(&idx, chunk),
+                            };
+                            if inserted.is_err() {
+                                let e = GridIoError::Corrupt("chunk has a different dimension");
+                                self.errors.push(StreamError::Load(idx, e));
+                            }
+                        }
+                        Ok(None) => {
+                            grid.take_written(&idx);
+                            self.missing.insert(idx);
+                        }
+                        Err(e) => {
+                            grid.take_written(&idx);
+                            self.missing.insert(idx);
+                            self.errors.push(StreamError::Load(idx, e));
+                        }
+                    }
+                }
+                Reply::SaveFailed(idx, e) => self.errors.push(StreamError::Save(idx, e)),
+            }
+        }
+    }
+
+    /// Chunks within `radius` of any point, nearest first.
+    fn wanted(&self, grid: &VoxelGrid, points: &[Vector3<f32>]) -> Vec<ChunkIndex> {
+        let r = self.radius;
+        let mut distances: HashMap<ChunkIndex, i32> = HashMap::new();
+        for p in points {
+            let pos = Vector3::new(p.x.floor() as i32, p.y.floor() as i32, p.z.floor() as i32);
+            let centre = grid.locate(&pos).0;
+            for z in -r..r + 1 {
+                for y in -r..r + 1 {
+                    for x in -r..r + 1 {
+                        let d = x * x + y * y + z * z;
+                        if d > r * r {
+                            continue;
+                        }
+                        let idx = centre + Vector3::new(x, y, z);
+                        let e = distances.entry(idx).or_insert(d);
+                        if d < *e {
+                            *e = d;
+                        }
+                    }
+                }
+            }
+        }
+        let mut wanted = distances.into_iter().collect::<Vec<_>>();
+        wanted.sort_by_key(|&(idx, d)| (d, idx.x, idx.y, idx.z));
+        wanted.into_iter().map(|(idx, _)| idx).collect()
+    }
+
+    fn evict(&mut self, grid: &mut VoxelGrid, wanted: &HashSet<ChunkIndex>) {
+        let mut usage = grid.chunks().map(|(_, c)| c.memory_usage()).sum::<usize>();
+        if usage <= self.budget {
+            return;
+        }
+
+        let mut candidates = grid
+            .chunk_indices()
+            .filter(|idx| !wanted.contains(idx) && !self.pending.contains(idx))
+            .map(|idx| (self.last_used.get(idx).cloned().unwrap_or(0), *idx))
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|&(frame, idx)| (frame, idx.x, idx.y, idx.z));
+        for (_, idx) in candidates {
+            if usage <= self.budget {
+                break;
+            }
+            if let Some(chunk) = grid.take_chunk(&idx) {
+                usage -= chunk.memory_usage();
+                self.last_used.remove(&idx);
+                self.send(Request::Save(idx, chunk));
+            }
+        }
+    }
+
+    /// Returns false if the streaming thread stopped, which is reported
+    /// once.
+    fn send(&mut self, request: Request) -> bool {
+        let load = match request {
+            Request::Load(idx) => Some(idx),
+            _ => None,
+        };
+        let sent = match self.requests {
+            Some(ref r) => r.send(request).is_ok(),
+            None => false,
+        };
+        if !sent {
+            if let Some(idx) = load {
+                self.pending.remove(&idx);
+            }
+            if self.requests.take().is_some() {
+                self.errors.push(StreamError::Stopped);
+            }
+        }
+        sent
+    }
+}
+
+impl Drop for ChunkStreamer {
+    /// Waits for pending saves. Chunks still in the grid are not saved, see
+    /// `flush` and `TerrainStreamer::save`.
+    fn drop(&mut self) {
+        self.requests.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Copy the `written` voxels of `edited` onto `loaded`. `edited` was
+/// created by edits while `loaded` was not in the grid.
+fn merge_edits(
+    loaded: &mut Chunk,
+    edited: &Chunk,
+    written: &HashSet<VoxelIndex>,
+) -> Result<(), VoxelError> {
+    for &idx in written {
+        let v = edited.try_get_voxel_at(idx)?;
+        loaded.try_set_voxel_at(idx, v.get_material(), v.get_occupancy())?;
+    }
+    Ok(())
+}
+
+/// Answer requests on the streaming thread until the streamer is dropped.
+fn serve(mut store: RegionStore, requests: &Receiver<Request>, replies: &Sender<Reply>) {
+    for request in requests.iter() {
+        let reply = match request {
+            Request::Load(idx) => Some(Reply::Loaded(idx, store.load(&idx))),
+            Request::Save(idx, chunk) => store
+                .save(&idx, &chunk)
+                .err()
+                .map(|e| Reply::SaveFailed(idx, e)),
+            Request::Flush(done) => {
+                let _ = done.send(());
+                None
+            }
+        };
+        if let Some(reply) = reply {
+            let _ = replies.send(reply);
+        }
+    }
+}
+
+/// The `ChunkStreamer` of the terrain, if it is streamed. Available to
+/// systems as a resource.
+#[derive(Default)]
+pub struct TerrainStreamer(Option<Mutex<ChunkStreamer>>);
+
+impl TerrainStreamer {
+    pub fn new(streamer: ChunkStreamer) -> Self {
+        TerrainStreamer(Some(Mutex::new(streamer)))
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut ChunkStreamer> {
+        self.0.as_mut().map(|s| match s.get_mut() {
+            Ok(s) => s,
+            Err(poisoned) => poisoned.into_inner(),
+        })
+    }
+
+    /// Save the chunks of `grid` before exiting, the streamer does not save
+    /// them when it is dropped.
+    pub fn save(&mut self, grid: &mut VoxelGrid) {
+        if let Some(streamer) = self.get_mut() {
+            if let Err(e) = streamer.flush(grid) {
+                error!("Failed to save terrain: {}", e);
+            }
+            for e in streamer.take_errors() {
+                error!("Failed to save terrain: {}", e);
+            }
+        }
+    }
+}
+
+/// Loads and evicts terrain chunks around the `PointsOfInterest` with the
+/// `TerrainStreamer`.
+#[derive(Default)]
+pub struct ChunkStreamingSystem;
+
+impl<'s> System<'s> for ChunkStreamingSystem {
+    type SystemData = (
+        FetchMut<'s, Terrain>,
+        FetchMut<'s, TerrainStreamer>,
+        Fetch<'s, PointsOfInterest>,
+    );
+
+    fn run(&mut self, (mut terrain, mut streamer, points): Self::SystemData) {
+        if let Some(streamer) = streamer.get_mut() {
+            streamer.update(terrain.grid_mut(), &points.0);
+            for e in streamer.take_errors() {
+                error!("Failed to stream terrain: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use material::MaterialId;
+    use region_file::temp_dir;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+    use voxel_grid::{QuantizedFloat, Voxel, WorldPos};
+
+    fn terrain() -> VoxelGrid {
+        let mut vg = VoxelGrid::with_dimension(8);
+        vg.fill_region(
+            &Vector3::new(-4, -1, 0),
+            &Vector3::new(3, 0, 0),
+            &|pos: &WorldPos| {
+                if pos.y < pos.x / 4 {
+                    Voxel::new_with_args(MaterialId::ROCK, QuantizedFloat::FULL)
+                } else {
+                    Voxel::new()
+                }
+            },
+        );
+        vg
+    }
+
+    fn settle(streamer: &mut ChunkStreamer, grid: &mut VoxelGrid, points: &[Vector3<f32>]) {
+        for _ in 0..5000 {
+            streamer.update(grid, points);
+            if !streamer.is_loading() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("chunks never loaded");
+    }
+
+    #[test]
+    fn stream_evict_and_reload() {
+        let dir = temp_dir("streaming");
+        let expected = terrain();
+        let mut grid = terrain();
+        let near_chunks = [
+            Vector3::new(-4, -1, 0),
+            Vector3::new(-4, 0, 0),
+            Vector3::new(-3, 0, 0),
+        ];
+        let budget = near_chunks
+            .iter()
+            .map(|idx| grid.get_chunk(idx).unwrap().memory_usage())
+            .sum();
+
+        // Only the chunks near x = -28 fit in the budget
+        let near = [Vector3::new(-28.0, 0.0, 4.0)];
+        let mut streamer = ChunkStreamer::new(dir.clone(), 8)
+            .unwrap()
+            .with_radius(1)
+            .with_budget(budget);
+        streamer.update(&mut grid, &near);
+        assert_eq!(grid.chunk_indices().count(), 3);
+        assert!(near_chunks.iter().all(|idx| grid.get_chunk(idx).is_some()));
+
+        // Moving away loads the evicted chunks back
+        let far = [Vector3::new(28.0, 0.0, 4.0)];
+        settle(&mut streamer, &mut grid, &far);
+        assert_eq!(grid.chunk_indices().count(), 3);
+        for idx in &[Vector3::new(3, -1, 0), Vector3::new(2, 0, 0)] {
+            let c = grid.get_chunk(idx).unwrap();
+            assert!(c.voxels().eq(expected.get_chunk(idx).unwrap().voxels()));
+        }
+        assert!(streamer.take_errors().is_empty());
+        drop(streamer);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stream_edit_evicted_chunk() {
+        let dir = temp_dir("streaming-evicted");
+        let expected = terrain();
+        let mut grid = terrain();
+        let budget = grid
+            .get_chunk(&Vector3::new(-4, 0, 0))
+            .unwrap()
+            .memory_usage()
+            * 3;
+        let near = [Vector3::new(-28.0, 0.0, 4.0)];
+        let mut streamer = ChunkStreamer::new(dir.clone(), 8)
+            .unwrap()
+            .with_radius(1)
+            .with_budget(budget);
+        streamer.update(&mut grid, &near);
+        let evicted = Vector3::new(3, -1, 0);
+        assert!(grid.get_chunk(&evicted).is_none());
+
+        // Digging into the saved chunk, e.g. by undoing, keeps the rest of it
+        let dug = Vector3::new(28, -8, 4);
+        assert_eq!(expected.voxel_at(&dug).get_material(), MaterialId::ROCK);
+        grid.set_voxel(&dug, MaterialId::AIR, QuantizedFloat::EMPTY);
+        settle(&mut streamer, &mut grid, &near);
+        streamer.update(&mut grid, &near);
+        assert!(grid.get_chunk(&evicted).is_none());
+
+        settle(&mut streamer, &mut grid, &[Vector3::new(28.0, -4.0, 4.0)]);
+        assert_eq!(grid.voxel_at(&dug), Voxel::new());
+        let solid = Vector3::new(29, -8, 4);
+        assert_eq!(grid.voxel_at(&solid).get_material(), MaterialId::ROCK);
+        assert!(streamer.take_errors().is_empty());
+        drop(streamer);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stream_flush() {
+        let dir = temp_dir("streaming-flush");
+        let mut grid = terrain();
+        let edited = Vector3::new(-20, 2, 3);
+        grid.set_voxel(&edited, MaterialId::SNOW, QuantizedFloat::FULL);
+        ChunkStreamer::new(dir.clone(), 8)
+            .unwrap()
+            .flush(&mut grid)
+            .unwrap();
+
+        let mut fresh = VoxelGrid::with_dimension(8);
+        let mut streamer = ChunkStreamer::new(dir.clone(), 8).unwrap().with_radius(1);
+        settle(&mut streamer, &mut fresh, &[Vector3::new(-20.0, 2.0, 3.0)]);
+        assert_eq!(fresh.voxel_at(&edited).get_material(), MaterialId::SNOW);
+        // Chunks never saved stay missing
+        assert!(fresh.get_chunk(&Vector3::new(-3, 1, 0)).is_none());
+
+        // Without a streaming thread flushing fails as a whole
+        streamer.requests.take();
+        match streamer.flush(&mut fresh) {
+            Err(StreamError::Stopped) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        drop(streamer);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stream_save_loaded_chunks() {
+        let dir = temp_dir("streaming-save");
+        ChunkStreamer::new(dir.clone(), 8)
+            .unwrap()
+            .flush(&mut terrain())
+            .unwrap();
+
+        // Edit a chunk after it was loaded, then exit
+        let point = [Vector3::new(-20.0, 2.0, 3.0)];
+        let edited = Vector3::new(-20, -7, 3);
+        let mut grid = VoxelGrid::with_dimension(8);
+        let mut streamer = ChunkStreamer::new(dir.clone(), 8).unwrap().with_radius(1);
+        settle(&mut streamer, &mut grid, &point);
+        grid.set_voxel(&edited, MaterialId::AIR, QuantizedFloat::EMPTY);
+        let mut streamer = TerrainStreamer::new(streamer);
+        streamer.save(&mut grid);
+        drop(streamer);
+
+        let mut fresh = VoxelGrid::with_dimension(8);
+        let mut streamer = ChunkStreamer::new(dir.clone(), 8).unwrap().with_radius(1);
+        settle(&mut streamer, &mut fresh, &point);
+        assert_eq!(fresh.voxel_at(&edited), Voxel::new());
+        assert_eq!(
+            fresh.voxel_at(&Vector3::new(-21, -7, 3)).get_material(),
+            MaterialId::ROCK
+        );
+        drop(streamer);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stream_edit_while_loading() {
+        let dir = temp_dir("streaming-edit");
+        let expected = terrain();
+        ChunkStreamer::new(dir.clone(), 8)
+            .unwrap()
+            .flush(&mut terrain())
+            .unwrap();
+
+        // Edit a chunk whose load was requested but not yet received
+        let mut fresh = VoxelGrid::with_dimension(8);
+        let mut streamer = ChunkStreamer::new(dir.clone(), 8).unwrap().with_radius(0);
+        let point = [Vector3::new(4.0, 4.0, 4.0)];
+        streamer.update(&mut fresh, &point);
+        assert!(streamer.is_loading());
+        let edited = Vector3::new(4, 7, 4);
+        fresh.set_voxel(&edited, MaterialId::SNOW, QuantizedFloat::FULL);
+        let dug = Vector3::new(6, 0, 4);
+        assert_eq!(expected.voxel_at(&dug).get_material(), MaterialId::ROCK);
+        fresh.set_voxel(&dug, MaterialId::AIR, QuantizedFloat::EMPTY);
+
+        settle(&mut streamer, &mut fresh, &point);
+        assert_eq!(fresh.voxel_at(&edited).get_material(), MaterialId::SNOW);
+        assert_eq!(fresh.voxel_at(&dug), Voxel::new());
+        assert!(fresh.created_chunks().next().is_none());
+        let solid = Vector3::new(5, 0, 4);
+        assert_eq!(fresh.voxel_at(&solid), expected.voxel_at(&solid));
+        assert_eq!(fresh.voxel_at(&solid).get_material(), MaterialId::ROCK);
+        drop(streamer);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,8 +1,35 @@
 use voxel_grid::*;
 
+/// Voxel terrain of the world, available to systems as a resource.
 #[derive(Debug)]
-struct Terrain {
+pub struct Terrain {
     grid: VoxelGrid,
 }
 
-impl Terrain {}
+impl Terrain {
+    pub fn new(grid: VoxelGrid) -> Self {
+        Terrain { grid }
+    }
+
+    #[inline]
+    pub fn grid(&self) -> &VoxelGrid {
+        &self.grid
+    }
+
+    #[inline]
+    pub fn grid_mut(&mut self) -> &mut VoxelGrid {
+        &mut self.grid
+    }
+
+    /// Replace the chunks from `min` to `max` (inclusive) with the output of
+    /// `gen`.
+    pub fn generate<G: VoxelGenerator>(&mut self, min: &ChunkIndex, max: &ChunkIndex, gen: &G) {
+        self.grid.fill_region(min, max, gen);
+    }
+}
+
+impl Default for Terrain {
+    fn default() -> Self {
+        Terrain::new(VoxelGrid::new())
+    }
+}
@@ -0,0 +1,111 @@
+use std::path::PathBuf;
+
+use changes::VoxelChangeEvent;
+use material::MaterialRegistry;
+use meshing::{ChunkMeshingSystem, TerrainMaterial};
+use streaming::{ChunkStreamer, ChunkStreamingSystem, PointsOfInterest, TerrainStreamer};
+use terrain::Terrain;
+use thermal::{ThermalErosionSystem, ThermalParams};
+use voxel_events::VoxelEventSystem;
+use voxel_grid::DEFAULT_CHUNK_DIMENSION;
+
+use amethyst::core::bundle::{ECSBundle, Result};
+use amethyst::ecs::{DispatcherBuilder, World};
+use amethyst::shrev::EventChannel;
+
+/// Voxel size used when none is given, in world units.
+pub const DEFAULT_VOXEL_SIZE: f32 = 1.0;
+
+pub struct TerrainBundle {
+    materials: MaterialRegistry,
+    streaming: Option<PathBuf>,
+    thermal: ThermalParams,
+    voxel_size: f32,
+}
+
+impl TerrainBundle {
+    pub fn new(materials: MaterialRegistry) -> Self {
+        TerrainBundle {
+            materials,
+            streaming: None,
+            thermal: ThermalParams::default(),
+            voxel_size: DEFAULT_VOXEL_SIZE,
+        }
+    }
+
+    /// Size of a voxel in world units, used to place chunk meshes.
+    pub fn with_voxel_size(mut self, voxel_size: f32) -> Self {
+        self.voxel_size = voxel_size;
+        self
+    }
+
+    /// Page terrain chunks to region files in `dir`, keeping the chunks
+    /// around the `PointsOfInterest` loaded. The loaded chunks are only
+    /// written by `TerrainStreamer::save`, e.g. when the game stops.
+    pub fn with_streaming<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.streaming = Some(dir.into());
+        self
+    }
+
+    /// How fast edited slopes slump, see `ThermalErosionSystem`.
+    pub fn with_thermal_erosion(mut self, params: ThermalParams) -> Self {
+        self.thermal = params;
+        self
+    }
+}
+
+impl Default for TerrainBundle {
+    fn default() -> Self {
+        TerrainBundle::new(MaterialRegistry::builtin())
+    }
+}
+
+impl<'a, 'b> ECSBundle<'a, 'b> for TerrainBundle {
+    fn build(
+        self,
+        world: &mut World,
+        mut builder: DispatcherBuilder<'a, 'b>,
+    ) -> Result<DispatcherBuilder<'a, 'b>> {
+        world.add_resource(self.materials);
+        world.add_resource(Terrain::default());
+        // Readers only see events written after they register, so they are
+        // registered before the first frame writes any
+        let mut events = EventChannel::<VoxelChangeEvent>::new();
+        let thermal_reader = events.register_reader();
+        let meshing_reader = events.register_reader();
+        world.add_resource(events);
+        world.add_resource(PointsOfInterest::default());
+        world.add_resource(TerrainMaterial::default());
+
+        let mut event_deps = Vec::new();
+        if let Some(dir) = self.streaming {
+            let streamer = ChunkStreamer::new(dir.clone(), DEFAULT_CHUNK_DIMENSION)
+                .map_err(|e| format!("Failed to open chunk store {}: {}", dir.display(), e))?;
+            world.add_resource(TerrainStreamer::new(streamer));
+            builder = builder.add(
+                ChunkStreamingSystem::default(),
+                "chunk_streaming_system",
+                &[],
+            );
+            event_deps.push("chunk_streaming_system");
+        } else {
+            world.add_resource(TerrainStreamer::default());
+        }
+        Ok(builder
+            .add(
+                VoxelEventSystem::default(),
+                "voxel_event_system",
+                &event_deps,
+            )
+            .add(
+                ThermalErosionSystem::new(self.thermal, thermal_reader),
+                "thermal_erosion_system",
+                &["voxel_event_system"],
+            )
+            .add(
+                ChunkMeshingSystem::new(self.voxel_size, meshing_reader),
+                "chunk_meshing_system",
+                &["voxel_event_system"],
+            ))
+    }
+}
@@ -0,0 +1,351 @@
+use amethyst::ecs::{Fetch, FetchMut, System};
+use amethyst::shrev::{EventChannel, ReaderId};
+use cgmath::Vector3;
+
+use changes::{ChangeSource, VoxelChangeEvent};
+use erosion::HeightField;
+use material::{MaterialId, MaterialRegistry};
+use terrain::Terrain;
+use voxel_grid::{VoxelGrid, WorldPos};
+
+/// Height changes smaller than this count as settled.
+const EPSILON: f32 = 1e-3;
+
+/// Neighbouring columns with their distance.
+const NEIGHBOURS: [(i32, i32, f32); 8] = [
+    (1, 0, 1.0),
+    (-1, 0, 1.0),
+    (0, 1, 1.0),
+    (0, -1, 1.0),
+    (1, 1, ::std::f32::consts::SQRT_2),
+    (-1, 1, ::std::f32::consts::SQRT_2),
+    (1, -1, ::std::f32::consts::SQRT_2),
+    (-1, -1, ::std::f32::consts::SQRT_2),
+];
+
+/// Settings of thermal erosion, see `VoxelGrid::relax_slopes`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ThermalParams {
+    /// Most relaxation steps per call.
+    pub iterations: u32,
+    /// Fraction of the excess height moved downslope per step.
+    pub rate: f32,
+}
+
+impl Default for ThermalParams {
+    fn default() -> Self {
+        ThermalParams {
+            iterations: 8,
+            rate: 0.5,
+        }
+    }
+}
+
+/// Column heights being relaxed, with the material on top of each column.
+struct Slopes {
+    field: HeightField,
+    /// Material at the top of every column, what slides off it.
+    materials: Vec<MaterialId>,
+    /// Steepest slope of every column, as height per column of distance.
+    talus: Vec<f32>,
+    /// Columns whose surface is not inside the field, they neither give
+    /// nor take material.
+    fixed: Vec<bool>,
+}
+
+impl Slopes {
+    /// Slopes of the columns of `field`, read from `grid` with the talus
+    /// angles of `materials`.
+    fn new(grid: &VoxelGrid, field: HeightField, materials: &MaterialRegistry) -> Self {
+        let (floor, ceiling) = (field.min.y as f32, (field.max.y + 1) as f32);
+        let mut slopes = Slopes {
+            materials: Vec::with_capacity(field.heights.len()),
+            talus: Vec::with_capacity(field.heights.len()),
+            fixed: Vec::with_capacity(field.heights.len()),
+            field,
+        };
+        for z in 0..slopes.field.depth {
+            for x in 0..slopes.field.width {
+                let h = slopes.field.get(x, z);
+                let min = slopes.field.min;
+                let top = Vector3::new(min.x + x as i32, h.ceil() as i32 - 1, min.z + z as i32);
+                let m = grid.voxel_at(&top).get_material();
+                let angle = materials.get(m).map_or(90.0, |d| d.talus_angle);
+                slopes.materials.push(m);
+                slopes.talus.push(if angle < 90.0 {
+                    angle.to_radians().tan()
+                } else {
+                    ::std::f32::INFINITY
+                });
+                slopes.fixed.push(h <= floor || h >= ceiling);
+            }
+        }
+        slopes
+    }
+
+    /// Move material from columns steeper than their talus angle to their
+    /// lower neighbours, up to `params.iterations` times. Returns false once
+    /// every slope has settled.
+    fn relax(&mut self, params: &ThermalParams) -> bool {
+        let (w, d) = (self.field.width as i32, self.field.depth as i32);
+        let mut moved = false;
+        for _ in 0..params.iterations {
+            let mut delta = vec![0.0f32; self.field.heights.len()];
+            let mut settled = true;
+            for z in 0..d {
+                for x in 0..w {
+                    let i = (x + w * z) as usize;
+                    if self.fixed[i] {
+                        continue;
+                    }
+                    let h = self.field.heights[i];
+
+                    let mut excess = [(0usize, 0.0f32); 8];
+                    let (mut count, mut total, mut steepest) = (0, 0.0, 0.0f32);
+                    for &(dx, dz, dist) in &NEIGHBOURS {
+                        let (nx, nz) = (x + dx, z + dz);
+                        if nx < 0 || nz < 0 || nx >= w || nz >= d {
+                            continue;
+                        }
+                        let j = (nx + w * nz) as usize;
+                        let e = h - self.field.heights[j] - self.talus[i] * dist;
+                        if self.fixed[j] || e <= EPSILON {
+                            continue;
+                        }
+                        excess[count] = (j, e);
+                        count += 1;
+                        total += e;
+                        steepest = steepest.max(e);
+                    }
+                    if count == 0 {
+                        continue;
+                    }
+
+                    // Moving half the steepest excess at most levels the
+                    // slope without overshooting
+                    let amount = params.rate * steepest / 2.0;
+                    delta[i] -= amount;
+                    for &(j, e) in &excess[..count] {
+                        delta[j] += amount * e / total;
+                        self.materials[j] = self.materials[i];
+                        self.talus[j] = self.talus[i];
+                    }
+                    settled = false;
+                }
+            }
+            if settled {
+                break;
+            }
+            for (h, d) in self.field.heights.iter_mut().zip(delta) {
+                *h += d;
+            }
+            moved = true;
+        }
+        moved
+    }
+}
+
+impl VoxelGrid {
+    /// Let the surface of the columns from `min` to `max` slump wherever it
+    /// is steeper than the talus angle of its material, as one step of the
+    /// undo history. Returns false if nothing moved.
+    pub fn relax_slopes(
+        &mut self,
+        min: &WorldPos,
+        max: &WorldPos,
+        materials: &MaterialRegistry,
+        params: &ThermalParams,
+    ) -> bool {
+        let field = self.height_field(min, max);
+        let mut slopes = Slopes::new(self, field, materials);
+        if !slopes.relax(params) {
+            return false;
+        }
+        let width = slopes.field.width;
+        self.apply_height_field_with(&slopes.field, materials, |x, z| {
+            slopes.materials[x + width * z]
+        });
+        true
+    }
+}
+
+/// Columns around an edit that may slump with it.
+const MARGIN: i32 = 3;
+/// Voxels above and below an edit that may slump with it.
+const DEPTH: i32 = 8;
+
+/// Lets terrain slump after it is edited, a few relaxation steps per frame
+/// until the slopes around the edit have settled.
+pub struct ThermalErosionSystem {
+    params: ThermalParams,
+    reader: ReaderId<VoxelChangeEvent>,
+}
+
+impl ThermalErosionSystem {
+    /// `reader` must be registered before the first voxel events are
+    /// written.
+    pub fn new(params: ThermalParams, reader: ReaderId<VoxelChangeEvent>) -> Self {
+        ThermalErosionSystem { params, reader }
+    }
+
+    /// Boxes around the edits published since the last call that may need
+    /// to slump.
+    fn edits(
+        &mut self,
+        events: &mut EventChannel<VoxelChangeEvent>,
+        grid: &VoxelGrid,
+    ) -> Vec<(WorldPos, WorldPos)> {
+        let mut edits = Vec::new();
+        for event in events.read(&mut self.reader) {
+            // Loaded chunks and the voxels put back by undo and redo have
+            // already settled
+            if let VoxelChangeEvent::Modified {
+                chunk,
+                region,
+                source: ChangeSource::Edit,
+            } = *event
+            {
+                let margin = Vector3::new(MARGIN, DEPTH, MARGIN);
+                edits.push((
+                    grid.world_pos(&chunk, &region.min) - margin,
+                    grid.world_pos(&chunk, &region.max) + margin,
+                ));
+            }
+        }
+        edits
+    }
+}
+
+impl<'s> System<'s> for ThermalErosionSystem {
+    type SystemData = (
+        FetchMut<'s, Terrain>,
+        FetchMut<'s, EventChannel<VoxelChangeEvent>>,
+        Fetch<'s, MaterialRegistry>,
+    );
+
+    fn run(&mut self, (mut terrain, mut events, materials): Self::SystemData) {
+        let grid = terrain.grid_mut();
+        // The slumped voxels are published next frame and relaxed again
+        for (min, max) in self.edits(&mut events, grid) {
+            grid.relax_slopes(&min, &max, &materials, &self.params);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use voxel_grid::{Chunk, QuantizedFloat, Voxel};
+
+    /// Flat ground of `m` with a pit dug into it, its walls are vertical.
+    fn pit(m: MaterialId) -> VoxelGrid {
+        let mut vg = VoxelGrid::with_dimension(16);
+        vg.fill_region(
+            &Vector3::new(0, 0, 0),
+            &Vector3::new(1, 0, 1),
+            &|pos: &WorldPos| {
+                let dug = pos.x >= 10 && pos.x < 20 && pos.z >= 10 && pos.z < 20;
+                if pos.y < 2 || (pos.y < 8 && !dug) {
+                    Voxel::new_with_args(m, QuantizedFloat::FULL)
+                } else {
+                    Voxel::new()
+                }
+            },
+        );
+        vg
+    }
+
+    fn bounds() -> (WorldPos, WorldPos) {
+        (Vector3::new(0, 0, 0), Vector3::new(31, 15, 31))
+    }
+
+    #[test]
+    fn cliff_slumps() {
+        let mut vg = pit(MaterialId::GRASS);
+        let materials = MaterialRegistry::builtin();
+        let (min, max) = bounds();
+        let before = vg.height_field(&min, &max);
+        let params = ThermalParams {
+            iterations: 500,
+            ..ThermalParams::default()
+        };
+        assert!(vg.relax_slopes(&min, &max, &materials, &params));
+
+        let after = vg.height_field(&min, &max);
+        let volume = |f: &HeightField| f.heights.iter().sum::<f32>();
+        assert!((volume(&after) - volume(&before)).abs() < 2.0);
+        let talus = 35.0f32.to_radians().tan();
+        for z in 0..32 {
+            for x in 0..31 {
+                let dx = (after.get(x, z) - after.get(x + 1, z)).abs();
+                let dz = (after.get(z, x) - after.get(z, x + 1)).abs();
+                assert!(dx < talus + 0.1 && dz < talus + 0.1);
+            }
+        }
+        // The pit's walls slid into it
+        assert!(after.get(10, 15) > 3.0);
+        assert_eq!(
+            vg.voxel_at(&Vector3::new(10, 2, 10)).get_material(),
+            MaterialId::GRASS
+        );
+
+        // Slumping is undone as a step of its own
+        assert!(vg.undo());
+        assert!(!vg.journal().can_undo());
+        assert_eq!(vg.height_field(&min, &max).heights, before.heights);
+        assert!(vg.redo());
+        assert_eq!(vg.height_field(&min, &max).heights, after.heights);
+    }
+
+    #[test]
+    fn steep_materials_stand() {
+        let mut vg = pit(MaterialId::ICE);
+        let (min, max) = bounds();
+        let params = ThermalParams::default();
+        assert!(!vg.relax_slopes(&min, &max, &MaterialRegistry::builtin(), &params));
+        assert!(!vg.undo());
+    }
+
+    #[test]
+    fn thermal_reads_events_before_first_run() {
+        let mut grid = VoxelGrid::with_dimension(8);
+        let mut events = EventChannel::new();
+        let mut system =
+            ThermalErosionSystem::new(ThermalParams::default(), events.register_reader());
+
+        let pos = Vector3::new(1, 2, 3);
+        grid.set_voxel(&pos, MaterialId::ROCK, QuantizedFloat::FULL);
+        grid.clear_changes();
+        grid.set_voxel(&pos, MaterialId::AIR, QuantizedFloat::EMPTY);
+        events.iter_write(grid.take_changes());
+        assert_eq!(
+            system.edits(&mut events, &grid),
+            vec![(
+                Vector3::new(1 - MARGIN, 2 - DEPTH, 3 - MARGIN),
+                Vector3::new(1 + MARGIN, 2 + DEPTH, 3 + MARGIN)
+            )]
+        );
+
+        // Undoing the edit does not make the terrain slump again
+        assert!(grid.undo());
+        events.iter_write(grid.take_changes());
+        assert!(system.edits(&mut events, &grid).is_empty());
+
+        // Neither do loaded chunks, but chunks created by edits do
+        grid.insert_chunk(&Vector3::new(1, 0, 0), Chunk::new(8));
+        grid.set_voxel(
+            &Vector3::new(-1, 2, 3),
+            MaterialId::ROCK,
+            QuantizedFloat::FULL,
+        );
+        events.iter_write(grid.take_changes());
+        assert_eq!(
+            system.edits(&mut events, &grid),
+            vec![(
+                Vector3::new(-8 - MARGIN, -DEPTH, -MARGIN),
+                Vector3::new(-1 + MARGIN, 7 + DEPTH, 7 + MARGIN)
+            )]
+        );
+    }
+}
@@ -0,0 +1,524 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use cgmath::Vector3;
+
+use material::{MaterialId, MaterialRegistry};
+use voxel_grid::{QuantizedFloat, VoxelGrid, WorldPos, ISO_LEVEL};
+
+const MAGIC: [u8; 4] = *b"VOX ";
+const VERSION: i32 = 150;
+
+/// Largest model MagicaVoxel can open, per axis.
+pub const MAX_MODEL_SIZE: u32 = 256;
+
+#[derive(Debug)]
+pub enum VoxError {
+    Io(io::Error),
+    /// The data does not start with the .vox magic.
+    NotAVox,
+    Malformed(&'static str),
+    /// The exported region is larger than `MAX_MODEL_SIZE` along an axis.
+    TooLarge,
+}
+
+impl fmt::Display for VoxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VoxError::Io(ref e) => write!(f, "i/o error: {}", e),
+            VoxError::NotAVox => write!(f, "not a .vox file"),
+            VoxError::Malformed(why) => write!(f, "malformed .vox file: {}", why),
+            VoxError::TooLarge => write!(f, "region is too large for a .vox model"),
+        }
+    }
+}
+
+impl Error for VoxError {
+    fn description(&self) -> &str {
+        match *self {
+            VoxError::Io(_) => "i/o error",
+            VoxError::NotAVox => "not a .vox file",
+            VoxError::Malformed(why) => why,
+            VoxError::TooLarge => "region is too large for a .vox model",
+        }
+    }
+}
+
+impl From<io::Error> for VoxError {
+    fn from(e: io::Error) -> Self {
+        VoxError::Io(e)
+    }
+}
+
+/// One model of a .vox file, in MagicaVoxel's z-up coordinates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VoxModel {
+    pub size: Vector3<u32>,
+    /// Position and colour index of every solid voxel. Colour indices start
+    /// at 1.
+    pub voxels: Vec<(Vector3<u8>, u8)>,
+}
+
+/// Contents of a .vox file. Scene graph, material and layer chunks are
+/// skipped when reading.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VoxFile {
+    pub models: Vec<VoxModel>,
+    /// RGBA colour of every colour index. Index 0 is unused and colours
+    /// read as zero if the file has no palette.
+    pub palette: Vec<[u8; 4]>,
+}
+
+impl VoxFile {
+    pub fn read<R: Read>(mut r: R) -> Result<VoxFile, VoxError> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(VoxError::NotAVox);
+        }
+        r.read_i32::<LittleEndian>()?;
+
+        let (id, content, children) = read_chunk_header(&mut r)?;
+        if &id != b"MAIN" {
+            return Err(VoxError::Malformed("missing MAIN chunk"));
+        }
+        skip(&mut r, content)?;
+
+        let mut file = VoxFile {
+            models: Vec::new(),
+            palette: vec![[0; 4]; 256],
+        };
+        let mut size = None;
+        let mut remaining = children;
+        while remaining > 0 {
+            let (id, content, children) = read_chunk_header(&mut r)?;
+            let total = 12 + u64::from(content) + u64::from(children);
+            if total > u64::from(remaining) {
+                return Err(VoxError::Malformed("chunk overruns MAIN"));
+            }
+            remaining -= total as u32;
+
+            match &id {
+                b"SIZE" if content >= 12 => {
+                    size = Some(Vector3::new(
+                        r.read_u32::<LittleEndian>()?,
+                        r.read_u32::<LittleEndian>()?,
+                        r.read_u32::<LittleEndian>()?,
+                    ));
+                    skip(&mut r, content - 12)?;
+                }
+                b"XYZI" if content >= 4 => {
+                    let size = match size.take() {
+                        Some(s) => s,
+                        None => return Err(VoxError::Malformed("XYZI without SIZE")),
+                    };
+                    let count = r.read_u32::<LittleEndian>()?;
+                    if u64::from(count) * 4 > u64::from(content - 4) {
+                        return Err(VoxError::Malformed("XYZI overruns its chunk"));
+                    }
+                    let mut voxels = Vec::with_capacity(count as usize);
+                    for _ in 0..count {
+                        let mut v = [0u8; 4];
+                        r.read_exact(&mut v)?;
+                        if u32::from(v[0]) >= size.x
+                            || u32::from(v[1]) >= size.y
+                            || u32::from(v[2]) >= size.z
+                        {
+                            return Err(VoxError::Malformed("voxel outside its model"));
+                        }
+                        voxels.push((Vector3::new(v[0], v[1], v[2]), v[3]));
+                    }
+                    skip(&mut r, content - 4 - count * 4)?;
+                    file.models.push(VoxModel { size, voxels });
+                }
+                b"RGBA" if content >= 1024 => {
+                    for i in 0..255 {
+                        r.read_exact(&mut file.palette[i + 1])?;
+                    }
+                    skip(&mut r, content - 1020)?;
+                }
+                _ => skip(&mut r, content)?,
+            }
+            skip(&mut r, children)?;
+        }
+        Ok(file)
+    }
+
+    pub fn write<W: Write>(&self, mut w: W) -> Result<(), VoxError> {
+        let mut body = Vec::new();
+        for model in &self.models {
+            let mut size = Vec::new();
+            size.write_u32::<LittleEndian>(model.size.x)?;
+            size.write_u32::<LittleEndian>(model.size.y)?;
+            size.write_u32::<LittleEndian>(model.size.z)?;
+            write_chunk(&mut body, b"SIZE", &size)?;
+
+            let mut xyzi = Vec::with_capacity(4 + model.voxels.len() * 4);
+            xyzi.write_u32::<LittleEndian>(model.voxels.len() as u32)?;
+            for &(p, c) in &model.voxels {
+                xyzi.write_all(&[p.x, p.y, p.z, c])?;
+            }
+            write_chunk(&mut body, b"XYZI", &xyzi)?;
+        }
+        let mut rgba = Vec::with_capacity(1024);
+        for i in 1..257 {
+            rgba.write_all(&self.palette.get(i).cloned().unwrap_or([0; 4]))?;
+        }
+        write_chunk(&mut body, b"RGBA", &rgba)?;
+
+        w.write_all(&MAGIC)?;
+        w.write_i32::<LittleEndian>(VERSION)?;
+        w.write_all(b"MAIN")?;
+        w.write_u32::<LittleEndian>(0)?;
+        w.write_u32::<LittleEndian>(body.len() as u32)?;
+        w.write_all(&body)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<VoxFile, VoxError> {
+        VoxFile::read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), VoxError> {
+        self.write(BufWriter::new(File::create(path)?))
+    }
+}
+
+fn read_chunk_header<R: Read>(r: &mut R) -> io::Result<([u8; 4], u32, u32)> {
+    let mut id = [0u8; 4];
+    r.read_exact(&mut id)?;
+    let content = r.read_u32::<LittleEndian>()?;
+    let children = r.read_u32::<LittleEndian>()?;
+    Ok((id, content, children))
+}
+
+fn write_chunk<W: Write>(w: &mut W, id: &[u8; 4], content: &[u8]) -> io::Result<()> {
+    w.write_all(id)?;
+    w.write_u32::<LittleEndian>(content.len() as u32)?;
+    w.write_u32::<LittleEndian>(0)?;
+    w.write_all(content)
+}
+
+fn skip<R: Read>(r: &mut R, n: u32) -> io::Result<()> {
+    let skipped = io::copy(&mut r.by_ref().take(u64::from(n)), &mut io::sink())?;
+    if skipped < u64::from(n) {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "truncated chunk",
+        ));
+    }
+    Ok(())
+}
+
+/// Maps .vox colour indices to materials and back. Colour indices without a
+/// material are skipped on import.
+#[derive(Debug, Clone)]
+pub struct MaterialMapping {
+    materials: HashMap<u8, MaterialId>,
+}
+
+impl MaterialMapping {
+    pub fn new() -> Self {
+        MaterialMapping {
+            materials: HashMap::new(),
+        }
+    }
+
+    /// Map every colour index to the material whose colour in `colors` is
+    /// closest to the index's colour in `palette`.
+    pub fn nearest(palette: &[[u8; 4]], colors: &[(MaterialId, [u8; 3])]) -> Self {
+        let mut mapping = MaterialMapping::new();
+        for (i, c) in palette.iter().enumerate().skip(1).take(255) {
+            let best = colors.iter().min_by_key(|&&(_, m)| {
+                (0..3)
+                    .map(|k| {
+                        let d = i32::from(c[k]) - i32::from(m[k]);
+                        d * d
+                    })
+                    .sum::<i32>()
+            });
+            if let Some(&(m, _)) = best {
+                mapping.set(i as u8, m);
+            }
+        }
+        mapping
+    }
+
+    /// Map every colour index to the solid material of `registry` with the
+    /// closest colour.
+    pub fn from_registry(palette: &[[u8; 4]], registry: &MaterialRegistry) -> Self {
+        let colors = registry
+            .iter()
+            .filter(|def| def.id != MaterialId::AIR)
+            .map(|def| {
+                let c = |v: f32| (v.max(0.0).min(1.0) * 255.0).round() as u8;
+                (
+                    def.id,
+                    [c(def.colour[0]), c(def.colour[1]), c(def.colour[2])],
+                )
+            })
+            .collect::<Vec<_>>();
+        MaterialMapping::nearest(palette, &colors)
+    }
+
+    pub fn set(&mut self, index: u8, m: MaterialId) {
+        self.materials.insert(index, m);
+    }
+
+    #[inline]
+    pub fn material(&self, index: u8) -> Option<MaterialId> {
+        self.materials.get(&index).cloned()
+    }
+
+    /// Lowest colour index mapped to `m`.
+    pub fn index(&self, m: MaterialId) -> Option<u8> {
+        self.materials
+            .iter()
+            .filter(|&(_, mat)| *mat == m)
+            .map(|(i, _)| *i)
+            .min()
+    }
+}
+
+impl Default for MaterialMapping {
+    /// Colour indices 1 to 5 hold grass, snow, water, ice and rock.
+    fn default() -> Self {
+        let mut mapping = MaterialMapping::new();
+        mapping.set(1, MaterialId::GRASS);
+        mapping.set(2, MaterialId::SNOW);
+        mapping.set(3, MaterialId::WATER);
+        mapping.set(4, MaterialId::ICE);
+        mapping.set(5, MaterialId::ROCK);
+        mapping
+    }
+}
+
+impl VoxelGrid {
+    /// Write the solid voxels of `model` into the grid with the model's
+    /// origin at `origin`, as one undoable transaction. MagicaVoxel's z axis
+    /// becomes the grid's y axis and its y axis the grid's negative z axis.
+    /// Indestructible voxels of the grid are kept.
+    pub fn import_vox(
+        &mut self,
+        model: &VoxModel,
+        mapping: &MaterialMapping,
+        materials: &MaterialRegistry,
+        origin: &WorldPos,
+    ) {
+        let depth = model.size.y as i32;
+        self.begin_transaction();
+        for &(p, c) in &model.voxels {
+            if let Some(m) = mapping.material(c) {
+                let pos = origin
+                    + Vector3::new(i32::from(p.x), i32::from(p.z), depth - 1 - i32::from(p.y));
+                if !materials.is_indestructible(self.voxel_at(&pos).get_material()) {
+                    self.set_voxel(&pos, m, QuantizedFloat::FULL);
+                }
+            }
+        }
+        self.commit_transaction();
+    }
+
+    /// Turn the voxels between `min` and `max` (inclusive) into a model.
+    /// Voxels that are not solid or whose material has no colour index are
+    /// left empty.
+    pub fn export_vox(
+        &self,
+        min: &WorldPos,
+        max: &WorldPos,
+        mapping: &MaterialMapping,
+    ) -> Result<VoxModel, VoxError> {
+        let extent = max - min + Vector3::new(1, 1, 1);
+        let limit = MAX_MODEL_SIZE as i32;
+        if extent.x > limit || extent.y > limit || extent.z > limit {
+            return Err(VoxError::TooLarge);
+        }
+        if extent.x <= 0 || extent.y <= 0 || extent.z <= 0 {
+            return Ok(VoxModel {
+                size: Vector3::new(0, 0, 0),
+                voxels: Vec::new(),
+            });
+        }
+
+        let mut voxels = Vec::new();
+        for (pos, v) in self.region(min, max) {
+            if v.get_occupancy_as_f32() < ISO_LEVEL {
+                continue;
+            }
+            if let Some(c) = mapping.index(v.get_material()) {
+                let p = pos - min;
+                let p = Vector3::new(p.x as u8, (extent.z - 1 - p.z) as u8, p.y as u8);
+                voxels.push((p, c));
+            }
+        }
+        Ok(VoxModel {
+            size: Vector3::new(extent.x as u32, extent.z as u32, extent.y as u32),
+            voxels,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lodge() -> VoxelGrid {
+        let mut vg = VoxelGrid::with_dimension(8);
+        for x in -2..3 {
+            for z in 0..4 {
+                vg.set_voxel(
+                    &Vector3::new(x, 0, z),
+                    MaterialId::ROCK,
+                    QuantizedFloat::FULL,
+                );
+            }
+        }
+        vg.set_voxel(
+            &Vector3::new(0, 1, 3),
+            MaterialId::SNOW,
+            QuantizedFloat::FULL,
+        );
+        vg.set_voxel(
+            &Vector3::new(1, 1, 3),
+            MaterialId::SNOW,
+            QuantizedFloat::new(10),
+        );
+        vg
+    }
+
+    #[test]
+    fn vox_roundtrip() {
+        let vg = lodge();
+        let mapping = MaterialMapping::default();
+        let model = vg
+            .export_vox(&Vector3::new(-2, 0, 0), &Vector3::new(2, 1, 3), &mapping)
+            .unwrap();
+        assert_eq!(model.size, Vector3::new(5, 4, 2));
+        assert_eq!(model.voxels.len(), 21);
+
+        let mut file = VoxFile {
+            models: vec![model],
+            palette: vec![[0, 0, 0, 255]; 256],
+        };
+        file.palette[0] = [0; 4];
+        file.palette[5] = [128, 128, 128, 255];
+        let mut bytes = Vec::new();
+        file.write(&mut bytes).unwrap();
+        let read = VoxFile::read(&bytes[..]).unwrap();
+        assert_eq!(read, file);
+
+        let materials = MaterialRegistry::builtin();
+        let mut copy = VoxelGrid::with_dimension(8);
+        copy.import_vox(
+            &read.models[0],
+            &mapping,
+            &materials,
+            &Vector3::new(-2, 0, 0),
+        );
+        for x in -3..4 {
+            for y in -1..3 {
+                for z in -1..5 {
+                    let p = Vector3::new(x, y, z);
+                    let solid = vg.voxel_at(&p).get_occupancy_as_f32() >= ISO_LEVEL;
+                    let v = copy.voxel_at(&p);
+                    assert_eq!(v.get_occupancy() == QuantizedFloat::FULL, solid);
+                    if solid {
+                        assert_eq!(v.get_material(), vg.voxel_at(&p).get_material());
+                    }
+                }
+            }
+        }
+        assert!(copy.undo());
+        assert!(copy.voxel_at(&Vector3::new(0, 0, 0)).get_material() == MaterialId::AIR);
+
+        // Bedrock is not replaced
+        let bedrock = Vector3::new(0, 0, 0);
+        copy.set_voxel(&bedrock, MaterialId::BEDROCK, QuantizedFloat::FULL);
+        copy.import_vox(
+            &read.models[0],
+            &mapping,
+            &materials,
+            &Vector3::new(-2, 0, 0),
+        );
+        assert_eq!(copy.voxel_at(&bedrock).get_material(), MaterialId::BEDROCK);
+        assert_eq!(
+            copy.voxel_at(&Vector3::new(1, 0, 0)).get_material(),
+            MaterialId::ROCK
+        );
+    }
+
+    #[test]
+    fn vox_skips_unknown_chunks() {
+        let mut file = VoxFile {
+            models: vec![VoxModel {
+                size: Vector3::new(1, 1, 1),
+                voxels: vec![(Vector3::new(0, 0, 0), 7)],
+            }],
+            palette: vec![[1, 2, 3, 4]; 256],
+        };
+        file.palette[0] = [0; 4];
+        let mut bytes = Vec::new();
+        file.write(&mut bytes).unwrap();
+
+        let mut extra = Vec::new();
+        write_chunk(&mut extra, b"nTRN", &[0; 28]).unwrap();
+        let at = 20;
+        for (i, b) in extra.iter().enumerate() {
+            bytes.insert(at + i, *b);
+        }
+        let children = (bytes.len() - at) as u32;
+        (&mut bytes[16..20])
+            .write_u32::<LittleEndian>(children)
+            .unwrap();
+
+        assert_eq!(VoxFile::read(&bytes[..]).unwrap(), file);
+        match VoxFile::read(&b"VOX"[..]) {
+            Err(VoxError::Io(_)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        match VoxFile::read(&bytes[4..]) {
+            Err(VoxError::NotAVox) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+
+        // Voxels must lie inside the model
+        file.models[0].voxels[0].0 = Vector3::new(0, 1, 0);
+        let mut bytes = Vec::new();
+        file.write(&mut bytes).unwrap();
+        match VoxFile::read(&bytes[..]) {
+            Err(VoxError::Malformed(_)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn vox_nearest_mapping() {
+        let mut palette = vec![[0, 0, 0, 255]; 256];
+        palette[1] = [250, 250, 255, 255];
+        palette[2] = [40, 200, 30, 255];
+        let mapping = MaterialMapping::nearest(
+            &palette,
+            &[
+                (MaterialId::SNOW, [255, 255, 255]),
+                (MaterialId::GRASS, [0, 255, 0]),
+                (MaterialId::ROCK, [0, 0, 0]),
+            ],
+        );
+        assert_eq!(mapping.material(1), Some(MaterialId::SNOW));
+        assert_eq!(mapping.material(2), Some(MaterialId::GRASS));
+        assert_eq!(mapping.material(3), Some(MaterialId::ROCK));
+        assert_eq!(mapping.material(0), None);
+        assert_eq!(mapping.index(MaterialId::ROCK), Some(3));
+
+        let registry = MaterialRegistry::builtin();
+        let mapping = MaterialMapping::from_registry(&palette, &registry);
+        assert_eq!(mapping.material(1), Some(MaterialId::SNOW));
+        assert_eq!(mapping.material(2), Some(MaterialId::GRASS));
+    }
+}
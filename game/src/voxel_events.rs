@@ -0,0 +1,28 @@
+use amethyst::ecs::{FetchMut, System};
+use amethyst::shrev::EventChannel;
+
+use changes::VoxelChangeEvent;
+use terrain::Terrain;
+
+/// Publishes the changes made to the terrain since the previous frame.
+pub struct VoxelEventSystem;
+
+impl Default for VoxelEventSystem {
+    fn default() -> Self {
+        VoxelEventSystem {}
+    }
+}
+
+impl<'s> System<'s> for VoxelEventSystem {
+    type SystemData = (
+        FetchMut<'s, Terrain>,
+        FetchMut<'s, EventChannel<VoxelChangeEvent>>,
+    );
+
+    fn run(&mut self, (mut terrain, mut events): Self::SystemData) {
+        let changes = terrain.grid_mut().take_changes();
+        if !changes.is_empty() {
+            events.iter_write(changes);
+        }
+    }
+}
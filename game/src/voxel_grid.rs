@@ -1,50 +1,147 @@
-use std::collections::HashMap;
+use std::collections::{hash_map, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
 
 use cgmath::Vector3;
+use rayon::prelude::*;
+
+use changes::{ChangeSource, ChangeTracker, DirtyChunks, DirtyRegion, VoxelChangeEvent};
+use journal::{EditJournal, Transaction};
+use material::MaterialId;
+use mipmap::MipChain;
+use palette::VoxelStorage;
+use quantize::{self, Occupancy, OccupancyPrecision, OCCUPANCY_MAX};
 
 #[cfg(test)]
 use quickcheck::Arbitrary;
 #[cfg(test)]
 use quickcheck::Gen;
 
-// TODO: Be smarter about this at some point in the future - lookup table somewhere?
+/// Position of a chunk in the grid, in units of whole chunks.
+pub type ChunkIndex = Vector3<i32>;
+
+/// Position of a voxel inside its chunk.
+pub type VoxelIndex = Vector3<u16>;
+
+/// Position of a voxel in the world, in units of whole voxels.
+pub type WorldPos = Vector3<i32>;
+
+pub const DEFAULT_CHUNK_DIMENSION: u16 = 32;
+
+/// Most voxels per side a chunk can have.
+pub const MAX_CHUNK_DIMENSION: u16 = 256;
+
+/// Directions to the six face neighbours, in the order used by
+/// `VoxelGrid::neighbors`.
+pub const FACE_DIRECTIONS: [[i32; 3]; 6] = [
+    [1, 0, 0],
+    [-1, 0, 0],
+    [0, 1, 0],
+    [0, -1, 0],
+    [0, 0, 1],
+    [0, 0, -1],
+];
+
+/// Occupancy at which a voxel counts as solid, i.e. where the surface is.
+pub const ISO_LEVEL: f32 = 0.5;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Material {
-    Air,
-    Grass,
-    Snow,
-    Water,
-    Ice,
-    Rock,
+pub enum VoxelError {
+    /// Chunks have from 1 to `MAX_CHUNK_DIMENSION` voxels per side.
+    InvalidDimension(u16),
+    OutOfBounds {
+        index: VoxelIndex,
+        dimension: u16,
+    },
+    /// A chunk does not have the dimension of the grid it is added to.
+    DimensionMismatch {
+        expected: u16,
+        found: u16,
+    },
 }
 
-pub type ChunkIndex = Vector3<u16>;
+impl fmt::Display for VoxelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VoxelError::InvalidDimension(d) => write!(f, "invalid chunk dimension {}", d),
+            VoxelError::OutOfBounds { index, dimension } => write!(
+                f,
+                "voxel ({}, {}, {}) is outside a chunk of dimension {}",
+                index.x, index.y, index.z, dimension
+            ),
+            VoxelError::DimensionMismatch { expected, found } => {
+                write!(f, "chunk has dimension {} instead of {}", found, expected)
+            }
+        }
+    }
+}
+
+impl Error for VoxelError {
+    fn description(&self) -> &str {
+        match *self {
+            VoxelError::InvalidDimension(_) => "invalid chunk dimension",
+            VoxelError::OutOfBounds { .. } => "voxel outside chunk",
+            VoxelError::DimensionMismatch { .. } => "chunk has the wrong dimension",
+        }
+    }
+}
 
-#[derive(Debug, Copy, Clone)]
+/// Check that chunks can have `dim` voxels per side.
+#[inline]
+pub fn check_dimension(dim: u16) -> Result<(), VoxelError> {
+    if dim == 0 || dim > MAX_CHUNK_DIMENSION {
+        Err(VoxelError::InvalidDimension(dim))
+    } else {
+        Ok(())
+    }
+}
+
+#[inline]
+fn assert_dimension(dim: u16) {
+    assert!(
+        check_dimension(dim).is_ok(),
+        "invalid chunk dimension {}",
+        dim
+    );
+}
+
+/// Occupancy in `[0, 1]`, stored with `OccupancyPrecision`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct QuantizedFloat {
-    pub value: u8,
+    pub value: Occupancy,
 }
 
 impl QuantizedFloat {
+    pub const EMPTY: QuantizedFloat = QuantizedFloat { value: 0 };
+    pub const FULL: QuantizedFloat = QuantizedFloat {
+        value: OCCUPANCY_MAX,
+    };
+
     #[inline]
-    pub fn new(val: u8) -> Self {
+    pub fn new(val: Occupancy) -> Self {
         QuantizedFloat { value: val }
     }
 
+    /// Nearest representable occupancy to `v`, which is clamped to `[0, 1]`.
+    #[inline]
+    pub fn from_f32(v: f32) -> Self {
+        QuantizedFloat::new(quantize::encode::<OccupancyPrecision>(v))
+    }
+
     #[inline]
     pub fn encode(&mut self, v: f32) {
-        self.value = (v * 256.0 + 1.0) as u8;
+        self.value = quantize::encode::<OccupancyPrecision>(v);
     }
 
     #[inline]
     pub fn decode(&self) -> f32 {
-        (f32::from(self.value) + 1.0) / 256.0
+        quantize::decode::<OccupancyPrecision>(self.value)
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Voxel {
-    material: Material,
+    material: MaterialId,
     occupancy: QuantizedFloat,
 }
 
@@ -52,12 +149,12 @@ impl Voxel {
     #[inline]
     pub fn new() -> Self {
         Voxel {
-            material: Material::Air,
-            occupancy: QuantizedFloat::new(0),
+            material: MaterialId::AIR,
+            occupancy: QuantizedFloat::EMPTY,
         }
     }
 
-    pub fn new_with_args(mat: Material, occ: QuantizedFloat) -> Self {
+    pub fn new_with_args(mat: MaterialId, occ: QuantizedFloat) -> Self {
         Voxel {
             material: mat,
             occupancy: occ,
@@ -65,13 +162,13 @@ impl Voxel {
     }
 
     #[inline]
-    pub fn set(&mut self, m: Material, o: QuantizedFloat) {
+    pub fn set(&mut self, m: MaterialId, o: QuantizedFloat) {
         self.material = m;
         self.occupancy = o;
     }
 
     #[inline]
-    pub fn set_material(&mut self, m: Material) {
+    pub fn set_material(&mut self, m: MaterialId) {
         self.material = m;
     }
 
@@ -86,7 +183,7 @@ impl Voxel {
     }
 
     #[inline]
-    pub fn get_material(&self) -> Material {
+    pub fn get_material(&self) -> MaterialId {
         self.material
     }
 
@@ -101,73 +198,414 @@ impl Voxel {
     }
 }
 
-// TODO: Mipmap
+/// Empty space, what chunks that do not exist read as.
+pub static AIR: Voxel = Voxel {
+    material: MaterialId::AIR,
+    occupancy: QuantizedFloat::EMPTY,
+};
+
+/// Produces the voxel found at a world position, used to fill chunks.
+pub trait VoxelGenerator: Sync {
+    fn generate(&self, pos: &WorldPos) -> Voxel;
+
+    /// The chunk with `dim` voxels per side whose first voxel is at
+    /// `origin`. Generators that share work between voxels, such as the
+    /// voxels of a column, override it.
+    fn generate_chunk(&self, origin: &WorldPos, dim: u16) -> Chunk {
+        Chunk::from_fn(dim, |voxel| {
+            self.generate(&Vector3::new(
+                origin.x + i32::from(voxel.x),
+                origin.y + i32::from(voxel.y),
+                origin.z + i32::from(voxel.z),
+            ))
+        })
+    }
+}
+
+impl<F> VoxelGenerator for F
+where
+    F: Fn(&WorldPos) -> Voxel + Sync,
+{
+    #[inline]
+    fn generate(&self, pos: &WorldPos) -> Voxel {
+        self(pos)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Chunk {
-    pub voxels: Vec<Voxel>,
+    voxels: VoxelStorage,
+    mips: MipChain,
     dimension: u16,
 }
 
-type VoxelIndex = Vector3<u16>;
+/// Offset of `i` in the x-y-z ordered voxels of a cube with `dim` per side.
+#[inline]
+pub fn linear_index(dim: u16, i: VoxelIndex) -> usize {
+    let d = dim as usize;
+    i.x as usize + d * (i.y as usize + d * i.z as usize)
+}
+
+/// Offset of `i` along the Z-order curve, which interleaves the bits of the
+/// coordinates so that voxels close in space are close in memory.
+#[inline]
+pub fn morton_index(i: VoxelIndex) -> usize {
+    spread_bits(i.x) | spread_bits(i.y) << 1 | spread_bits(i.z) << 2
+}
+
+/// Put two zero bits between each of the low ten bits of `v`.
+#[inline]
+fn spread_bits(v: u16) -> usize {
+    let mut x = u32::from(v) & 0x3ff;
+    x = (x | x << 16) & 0x0300_00ff;
+    x = (x | x << 8) & 0x0300_f00f;
+    x = (x | x << 4) & 0x030c_30c3;
+    x = (x | x << 2) & 0x0924_9249;
+    x as usize
+}
+
+/// Offset of `i` in the voxel storage of a chunk. With the `morton` feature
+/// chunks whose dimension is a power of two are stored in Z-order, other
+/// chunks always use the x-y-z layout.
+#[inline]
+pub fn storage_index(dim: u16, i: VoxelIndex) -> usize {
+    if cfg!(feature = "morton") && dim.is_power_of_two() {
+        morton_index(i)
+    } else {
+        linear_index(dim, i)
+    }
+}
 
 impl Chunk {
     /// Empty Chunk
     #[inline]
     pub fn new(dim: u16) -> Chunk {
-        let v = (0..(dim * dim * dim))
-            .map(|_| Voxel::new())
-            .collect::<Vec<Voxel>>();
+        Chunk::filled(dim, Voxel::new())
+    }
+
+    /// Chunk where every voxel is `v`. Panics if `dim` is not a valid
+    /// dimension, see `try_filled`.
+    #[inline]
+    pub fn filled(dim: u16, v: Voxel) -> Chunk {
+        assert_dimension(dim);
+        let d = dim as usize;
+        Chunk {
+            voxels: VoxelStorage::filled(v, d * d * d),
+            mips: MipChain::filled(dim, v),
+            dimension: dim,
+        }
+    }
+
+    /// Empty chunk, or an error if `dim` is not a valid dimension.
+    pub fn try_new(dim: u16) -> Result<Chunk, VoxelError> {
+        Chunk::try_filled(dim, Voxel::new())
+    }
+
+    pub fn try_filled(dim: u16, v: Voxel) -> Result<Chunk, VoxelError> {
+        check_dimension(dim)?;
+        Ok(Chunk::filled(dim, v))
+    }
+
+    /// Chunk with the voxel at every index given by `f`
+    pub fn from_fn<F>(dim: u16, f: F) -> Chunk
+    where
+        F: Fn(VoxelIndex) -> Voxel,
+    {
+        assert_dimension(dim);
+        let d = dim as usize;
+        let mut voxels = VoxelStorage::filled(Voxel::new(), d * d * d);
+        for z in 0..dim {
+            for y in 0..dim {
+                for x in 0..dim {
+                    let idx = Vector3::new(x, y, z);
+                    voxels.set(storage_index(dim, idx), f(idx));
+                }
+            }
+        }
+        let mips = MipChain::build(dim, |i| *voxels.get(storage_index(dim, i)));
         Chunk {
-            voxels: v,
+            voxels,
+            mips,
             dimension: dim,
         }
     }
 
+    #[inline]
+    pub fn dimension(&self) -> u16 {
+        self.dimension
+    }
+
     #[inline]
     fn one_dim_coord(&self, i: VoxelIndex) -> usize {
-        (i.x + self.dimension * (i.y + self.dimension * i.z)) as usize
+        storage_index(self.dimension, i)
+    }
+
+    /// True if `idx` is inside the chunk.
+    #[inline]
+    pub fn contains(&self, idx: VoxelIndex) -> bool {
+        idx.x < self.dimension && idx.y < self.dimension && idx.z < self.dimension
+    }
+
+    #[inline]
+    fn assert_bounds(&self, idx: VoxelIndex) {
+        assert!(
+            self.contains(idx),
+            "voxel {:?} outside a chunk of dimension {}",
+            idx,
+            self.dimension
+        );
     }
 
+    #[inline]
+    fn check_bounds(&self, idx: VoxelIndex) -> Result<(), VoxelError> {
+        if self.contains(idx) {
+            Ok(())
+        } else {
+            Err(VoxelError::OutOfBounds {
+                index: idx,
+                dimension: self.dimension,
+            })
+        }
+    }
+
+    /// Voxel at `idx`, which must be inside the chunk. Use
+    /// `try_get_voxel_at` for indices that may not be.
     #[inline]
     pub fn get_voxel_at(&self, idx: VoxelIndex) -> Voxel {
-        self.voxels[self.one_dim_coord(idx)]
+        self.assert_bounds(idx);
+        *self.voxels.get(self.one_dim_coord(idx))
     }
 
     #[inline]
-    pub fn set_voxel_at(&mut self, idx: VoxelIndex, m: Material, o: QuantizedFloat) {
-        let v = {
-            let mut temp = self.get_voxel_at(idx);
-            temp.set(m, o);
-            temp
-        };
+    pub fn try_get_voxel_at(&self, idx: VoxelIndex) -> Result<Voxel, VoxelError> {
+        self.check_bounds(idx)?;
+        Ok(*self.voxels.get(self.one_dim_coord(idx)))
+    }
+
+    #[inline]
+    pub fn voxel_ref(&self, idx: VoxelIndex) -> &Voxel {
+        self.assert_bounds(idx);
+        self.voxels.get(self.one_dim_coord(idx))
+    }
+
+    /// Set the voxel at `idx`, or reject the edit if `idx` is outside the
+    /// chunk.
+    pub fn try_set_voxel_at(
+        &mut self,
+        idx: VoxelIndex,
+        m: MaterialId,
+        o: QuantizedFloat,
+    ) -> Result<(), VoxelError> {
+        self.check_bounds(idx)?;
+        self.set_voxel_at(idx, m, o);
+        Ok(())
+    }
+
+    /// Set the voxel at `idx`, which must be inside the chunk. Use
+    /// `try_set_voxel_at` for indices that may not be.
+    #[inline]
+    pub fn set_voxel_at(&mut self, idx: VoxelIndex, m: MaterialId, o: QuantizedFloat) {
+        self.assert_bounds(idx);
         let i = self.one_dim_coord(idx);
-        self.voxels[i] = v;
+        let v = Voxel::new_with_args(m, o);
+        if *self.voxels.get(i) == v {
+            return;
+        }
+        self.voxels.set(i, v);
+
+        let (voxels, dim) = (&self.voxels, self.dimension);
+        self.mips
+            .update(dim, idx, |i| *voxels.get(storage_index(dim, i)));
+    }
+
+    /// Number of mip levels, including the full resolution level 0.
+    #[inline]
+    pub fn num_levels(&self) -> usize {
+        self.mips.len() + 1
+    }
+
+    /// Voxels per side at mip `level`.
+    #[inline]
+    pub fn level_dimension(&self, level: usize) -> u16 {
+        if level == 0 {
+            self.dimension
+        } else {
+            self.mips.dimension(level)
+        }
+    }
+
+    /// Voxel at `idx` in mip `level`, where `idx` is in that level's
+    /// `level_dimension`.
+    #[inline]
+    pub fn get_voxel_at_level(&self, idx: VoxelIndex, level: usize) -> Voxel {
+        if level == 0 {
+            self.get_voxel_at(idx)
+        } else {
+            self.mips.get(level, idx)
+        }
+    }
+
+    /// True if every voxel in the chunk is the same.
+    #[inline]
+    pub fn is_uniform(&self) -> bool {
+        self.voxels.is_uniform()
+    }
+
+    /// Approximate number of bytes used by the chunk.
+    #[inline]
+    pub fn memory_usage(&self) -> usize {
+        self.voxels.memory_usage()
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct VoxelGrid {
     chunks: HashMap<ChunkIndex, Chunk>,
+    dimension: u16,
+    changes: ChangeTracker,
+    journal: EditJournal,
+    /// Chunks created by writing to them, with every voxel written since.
+    /// The rest of such a chunk may still be stored, see `ChunkStreamer`.
+    created: HashMap<ChunkIndex, HashSet<VoxelIndex>>,
 }
 
 impl VoxelGrid {
     /// Construct empty voxel grid.
     pub fn new() -> Self {
+        VoxelGrid::with_dimension(DEFAULT_CHUNK_DIMENSION)
+    }
+
+    /// Construct empty voxel grid made of chunks with `dim` voxels per side.
+    /// Panics if `dim` is not a valid dimension, see `try_with_dimension`.
+    pub fn with_dimension(dim: u16) -> Self {
+        assert_dimension(dim);
         VoxelGrid {
             chunks: HashMap::new(),
+            dimension: dim,
+            changes: ChangeTracker::new(),
+            journal: EditJournal::default(),
+            created: HashMap::new(),
+        }
+    }
+
+    /// Empty grid, or an error if `dim` is not a valid chunk dimension.
+    pub fn try_with_dimension(dim: u16) -> Result<Self, VoxelError> {
+        check_dimension(dim)?;
+        Ok(VoxelGrid::with_dimension(dim))
+    }
+
+    #[inline]
+    pub fn dimension(&self) -> u16 {
+        self.dimension
+    }
+
+    /// Replace a chunk with the output of `gen`.
+    pub fn fill<G: VoxelGenerator>(&mut self, chunk: &ChunkIndex, gen: &G) {
+        let ch = self.generate_chunk(chunk, gen);
+        self.insert_chunk(chunk, ch);
+    }
+
+    /// Replace every chunk between `min` and `max` (inclusive) with the
+    /// output of `gen`, generating chunks in parallel.
+    pub fn fill_region<G: VoxelGenerator>(&mut self, min: &ChunkIndex, max: &ChunkIndex, gen: &G) {
+        let mut indices = Vec::new();
+        for z in min.z..max.z + 1 {
+            for y in min.y..max.y + 1 {
+                for x in min.x..max.x + 1 {
+                    indices.push(Vector3::new(x, y, z));
+                }
+            }
+        }
+
+        let generated = indices
+            .par_iter()
+            .map(|idx| (*idx, self.generate_chunk(idx, gen)))
+            .collect::<Vec<_>>();
+        for (idx, ch) in generated {
+            self.insert_chunk(&idx, ch);
         }
     }
 
-    pub fn fill(&mut self, _chunk: ChunkIndex) {
-        unimplemented!();
+    fn generate_chunk<G: VoxelGenerator>(&self, idx: &ChunkIndex, gen: &G) -> Chunk {
+        let origin = self.world_pos(idx, &Vector3::new(0, 0, 0));
+        gen.generate_chunk(&origin, self.dimension)
     }
 
+    /// Insert a chunk, it should have the same dimension as the grid.
     pub fn insert_chunk(&mut self, idx: &ChunkIndex, chunk: Chunk) {
+        let region = DirtyRegion::whole(chunk.dimension());
+        self.changes.mark_from(idx, region, ChangeSource::Load);
+        self.created.remove(idx);
         self.chunks.insert(*idx, chunk);
     }
 
+    /// Insert a chunk, or reject it if its dimension is not the grid's.
+    pub fn try_insert_chunk(&mut self, idx: &ChunkIndex, chunk: Chunk) -> Result<(), VoxelError> {
+        if chunk.dimension() != self.dimension {
+            return Err(VoxelError::DimensionMismatch {
+                expected: self.dimension,
+                found: chunk.dimension(),
+            });
+        }
+        self.insert_chunk(idx, chunk);
+        Ok(())
+    }
+
     pub fn delete_chunk(&mut self, idx: &ChunkIndex) {
-        self.chunks.remove(idx);
+        self.take_chunk(idx);
+    }
+
+    /// Remove a chunk and hand it back.
+    pub fn take_chunk(&mut self, idx: &ChunkIndex) -> Option<Chunk> {
+        let chunk = self.chunks.remove(idx);
+        if chunk.is_some() {
+            self.changes.mark_removed(idx);
+        }
+        self.created.remove(idx);
+        chunk
+    }
+
+    /// Chunks created by writing to them rather than inserted.
+    pub fn created_chunks<'a>(&'a self) -> hash_map::Keys<'a, ChunkIndex, HashSet<VoxelIndex>> {
+        self.created.keys()
+    }
+
+    /// Voxels written to a chunk since a write created it, air included.
+    /// Stops tracking them, `None` if the chunk was not created by a write.
+    pub fn take_written(&mut self, idx: &ChunkIndex) -> Option<HashSet<VoxelIndex>> {
+        self.created.remove(idx)
+    }
+
+    /// Every chunk with its index, in no particular order.
+    pub fn chunks<'a>(&'a self) -> hash_map::Iter<'a, ChunkIndex, Chunk> {
+        self.chunks.iter()
+    }
+
+    /// Every chunk, in no particular order. All of them are marked as
+    /// entirely dirty.
+    pub fn chunks_mut<'a>(&'a mut self) -> hash_map::IterMut<'a, ChunkIndex, Chunk> {
+        for (idx, c) in &self.chunks {
+            self.changes.mark(idx, DirtyRegion::whole(c.dimension()));
+        }
+        self.chunks.iter_mut()
+    }
+
+    /// Index of every chunk, in no particular order.
+    pub fn chunk_indices<'a>(&'a self) -> hash_map::Keys<'a, ChunkIndex, Chunk> {
+        self.chunks.keys()
+    }
+
+    pub fn get_chunk(&self, idx: &ChunkIndex) -> Option<&Chunk> {
+        self.chunks.get(idx)
+    }
+
+    /// Mutable access to a chunk, which is marked as entirely dirty.
+    pub fn get_chunk_mut(&mut self, idx: &ChunkIndex) -> Option<&mut Chunk> {
+        let chunk = self.chunks.get_mut(idx);
+        if let Some(ref c) = chunk {
+            self.changes.mark(idx, DirtyRegion::whole(c.dimension()));
+        }
+        chunk
     }
 
     pub fn neighbors(&self, idx: &ChunkIndex) -> [Option<&Chunk>; 6] {
@@ -184,6 +622,175 @@ impl VoxelGrid {
     pub fn is_empty(&self) -> bool {
         self.chunks.is_empty()
     }
+
+    /// Split a world position into the chunk owning it and the voxel index
+    /// inside that chunk.
+    #[inline]
+    pub fn locate(&self, pos: &WorldPos) -> (ChunkIndex, VoxelIndex) {
+        let dim = i32::from(self.dimension);
+        let (cx, vx) = floor_div_mod(pos.x, dim);
+        let (cy, vy) = floor_div_mod(pos.y, dim);
+        let (cz, vz) = floor_div_mod(pos.z, dim);
+        (
+            Vector3::new(cx, cy, cz),
+            Vector3::new(vx as u16, vy as u16, vz as u16),
+        )
+    }
+
+    /// World position of voxel `voxel` in chunk `chunk`.
+    #[inline]
+    pub fn world_pos(&self, chunk: &ChunkIndex, voxel: &VoxelIndex) -> WorldPos {
+        let dim = i32::from(self.dimension);
+        Vector3::new(
+            chunk.x * dim + i32::from(voxel.x),
+            chunk.y * dim + i32::from(voxel.y),
+            chunk.z * dim + i32::from(voxel.z),
+        )
+    }
+
+    /// Voxel at a world position. Chunks that do not exist read as air.
+    pub fn voxel_at(&self, pos: &WorldPos) -> Voxel {
+        let (chunk, voxel) = self.locate(pos);
+        match self.chunks.get(&chunk) {
+            Some(c) => c.get_voxel_at(voxel),
+            None => Voxel::new(),
+        }
+    }
+
+    /// Set the voxel at a world position, creating its chunk if needed.
+    pub fn set_voxel(&mut self, pos: &WorldPos, m: MaterialId, o: QuantizedFloat) {
+        let (chunk, voxel) = self.locate(pos);
+        let v = Voxel::new_with_args(m, o);
+        if let Some(before) = self.write_voxel(&chunk, voxel, v) {
+            self.journal.record(&chunk, voxel, before, v);
+        }
+    }
+
+    /// Write a voxel without journaling it. Returns the previous value if
+    /// the voxel changed.
+    fn write_voxel(&mut self, chunk: &ChunkIndex, voxel: VoxelIndex, v: Voxel) -> Option<Voxel> {
+        let dim = self.dimension;
+        let changes = &mut self.changes;
+        let created = &mut self.created;
+        let ch = self.chunks.entry(*chunk).or_insert_with(|| {
+            changes.mark(chunk, DirtyRegion::whole(dim));
+            created.insert(*chunk, HashSet::new());
+            Chunk::new(dim)
+        });
+        // Also when nothing changes, the voxel may differ from a stored one
+        if let Some(written) = created.get_mut(chunk) {
+            written.insert(voxel);
+        }
+        let before = ch.get_voxel_at(voxel);
+        if before == v {
+            return None;
+        }
+        ch.set_voxel_at(voxel, v.get_material(), v.get_occupancy());
+        changes.mark(chunk, DirtyRegion::voxel(voxel));
+        Some(before)
+    }
+
+    /// Group the edits made until the matching `commit_transaction` into a
+    /// single step of the undo history. Structural changes such as
+    /// inserting, deleting or filling chunks are not journaled.
+    pub fn begin_transaction(&mut self) {
+        self.journal.begin();
+    }
+
+    pub fn commit_transaction(&mut self) {
+        self.journal.commit();
+    }
+
+    /// Revert the most recent transaction. Returns false if there is
+    /// nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        let edits = match self.journal.pop_undo() {
+            Some(t) => flatten(t, |&(before, _)| before),
+            None => return false,
+        };
+        self.replay(edits);
+        true
+    }
+
+    /// Re-apply the most recently undone transaction. Returns false if
+    /// there is nothing to redo.
+    pub fn redo(&mut self) -> bool {
+        let edits = match self.journal.pop_redo() {
+            Some(t) => flatten(t, |&(_, after)| after),
+            None => return false,
+        };
+        self.replay(edits);
+        true
+    }
+
+    fn replay(&mut self, edits: Vec<(ChunkIndex, VoxelIndex, Voxel)>) {
+        self.changes.set_source(ChangeSource::Replay);
+        for (chunk, voxel, v) in edits {
+            self.write_voxel(&chunk, voxel, v);
+        }
+        self.changes.set_source(ChangeSource::Edit);
+    }
+
+    #[inline]
+    pub fn journal(&self) -> &EditJournal {
+        &self.journal
+    }
+
+    #[inline]
+    pub fn journal_mut(&mut self) -> &mut EditJournal {
+        &mut self.journal
+    }
+
+    /// True if a chunk was modified or removed since the last
+    /// `take_changes`.
+    #[inline]
+    pub fn is_dirty(&self, idx: &ChunkIndex) -> bool {
+        self.changes.is_dirty(idx)
+    }
+
+    /// Part of a chunk modified since the last `take_changes`.
+    #[inline]
+    pub fn dirty_region(&self, idx: &ChunkIndex) -> Option<&DirtyRegion> {
+        self.changes.dirty_region(idx)
+    }
+
+    #[inline]
+    pub fn dirty_chunks<'a>(&'a self) -> DirtyChunks<'a> {
+        self.changes.dirty_chunks()
+    }
+
+    /// Everything that changed since the last call, as events.
+    pub fn take_changes(&mut self) -> Vec<VoxelChangeEvent> {
+        self.changes.take()
+    }
+
+    pub fn clear_changes(&mut self) {
+        self.changes.clear();
+    }
+}
+
+/// Every edit of a transaction, with the value picked by `pick`.
+fn flatten<F>(t: &Transaction, pick: F) -> Vec<(ChunkIndex, VoxelIndex, Voxel)>
+where
+    F: Fn(&(Voxel, Voxel)) -> Voxel,
+{
+    let mut edits = Vec::new();
+    for (chunk, delta) in t.deltas() {
+        edits.extend(delta.iter().map(|(voxel, e)| (*chunk, *voxel, pick(e))));
+    }
+    edits
+}
+
+/// Division rounding towards negative infinity, with the matching
+/// always-positive remainder.
+#[inline]
+fn floor_div_mod(a: i32, b: i32) -> (i32, i32) {
+    let (d, r) = (a / b, a % b);
+    if r < 0 {
+        (d - 1, r + b)
+    } else {
+        (d, r)
+    }
 }
 
 // ---
@@ -191,15 +798,15 @@ impl VoxelGrid {
 // ---
 
 #[cfg(test)]
-impl Arbitrary for Material {
-    fn arbitrary<G: Gen>(g: &mut G) -> Material {
+impl Arbitrary for MaterialId {
+    fn arbitrary<G: Gen>(g: &mut G) -> MaterialId {
         let v = &[
-            Material::Air,
-            Material::Grass,
-            Material::Snow,
-            Material::Water,
-            Material::Ice,
-            Material::Rock,
+            MaterialId::AIR,
+            MaterialId::GRASS,
+            MaterialId::SNOW,
+            MaterialId::WATER,
+            MaterialId::ICE,
+            MaterialId::ROCK,
         ];
         *g.choose(v).unwrap()
     }
@@ -222,19 +829,33 @@ impl Arbitrary for Voxel {
 #[cfg(test)]
 impl Arbitrary for Chunk {
     fn arbitrary<G: Gen>(g: &mut G) -> Chunk {
-        let size: u16 = Arbitrary::arbitrary(g);
-        let mut ch = Chunk::new(size);
-        for v in &mut ch.voxels {
-            v.set(Arbitrary::arbitrary(g), Arbitrary::arbitrary(g));
+        let size = g.gen_range(1, 16);
+        arbitrary_chunk(g, size)
+    }
+}
+
+#[cfg(test)]
+fn arbitrary_chunk<G: Gen>(g: &mut G, size: u16) -> Chunk {
+    let mut ch = Chunk::new(size);
+    for z in 0..size {
+        for y in 0..size {
+            for x in 0..size {
+                ch.set_voxel_at(
+                    Vector3::new(x, y, z),
+                    Arbitrary::arbitrary(g),
+                    Arbitrary::arbitrary(g),
+                );
+            }
         }
-        ch
     }
+    ch
 }
 
 #[cfg(test)]
 impl Arbitrary for VoxelGrid {
     fn arbitrary<G: Gen>(g: &mut G) -> VoxelGrid {
-        let mut grid = VoxelGrid::new();
+        let dim = g.gen_range(1, 16);
+        let mut grid = VoxelGrid::with_dimension(dim);
         let size = {
             let s = g.size();
             g.gen_range(0, s)
@@ -245,7 +866,8 @@ impl Arbitrary for VoxelGrid {
                 Arbitrary::arbitrary(g),
                 Arbitrary::arbitrary(g),
             );
-            grid.insert_chunk(&p, Arbitrary::arbitrary(g))
+            let ch = arbitrary_chunk(g, dim);
+            grid.insert_chunk(&p, ch)
         }
         grid
     }
@@ -266,13 +888,104 @@ mod tests {
         let mut chunk = Chunk::new(32);
         chunk.set_voxel_at(
             Vector3::new(1, 5, 3),
-            Material::Grass,
-            QuantizedFloat::new(255),
+            MaterialId::GRASS,
+            QuantizedFloat::FULL,
         );
         assert_eq!(
             chunk.get_voxel_at(Vector3::new(1, 5, 3)).get_material(),
-            Material::Grass
+            MaterialId::GRASS
+        );
+    }
+
+    #[test]
+    fn morton_order() {
+        assert_eq!(morton_index(Vector3::new(0, 0, 0)), 0);
+        assert_eq!(morton_index(Vector3::new(1, 0, 0)), 1);
+        assert_eq!(morton_index(Vector3::new(0, 1, 0)), 2);
+        assert_eq!(morton_index(Vector3::new(0, 0, 1)), 4);
+        assert_eq!(morton_index(Vector3::new(3, 1, 2)), 0b101_011);
+        assert_eq!(morton_index(Vector3::new(255, 255, 255)), (1 << 24) - 1);
+
+        // Every layout fills the storage without gaps or overlaps
+        for &dim in &[1, 5, 8, 16] {
+            let d = dim as usize;
+            let mut seen = vec![false; d * d * d];
+            for i in Chunk::new(dim).voxels().map(|(i, _)| storage_index(dim, i)) {
+                assert!(!seen[i]);
+                seen[i] = true;
+            }
+            assert!(seen.iter().all(|&s| s));
+        }
+    }
+
+    #[test]
+    fn ch_checked_access() {
+        let mut chunk = Chunk::try_new(12).unwrap();
+        let inside = Vector3::new(11, 0, 11);
+        let outside = Vector3::new(0, 12, 0);
+        assert!(chunk
+            .try_set_voxel_at(inside, MaterialId::ICE, QuantizedFloat::FULL)
+            .is_ok());
+        assert_eq!(
+            chunk.try_set_voxel_at(outside, MaterialId::ICE, QuantizedFloat::FULL),
+            Err(VoxelError::OutOfBounds {
+                index: outside,
+                dimension: 12,
+            })
+        );
+        assert_eq!(
+            chunk.try_get_voxel_at(inside).map(|v| v.get_material()),
+            Ok(MaterialId::ICE)
+        );
+        assert!(chunk.try_get_voxel_at(outside).is_err());
+        // The rejected edit did not land anywhere else
+        assert_eq!(
+            chunk.voxels().filter(|&(_, v)| *v != Voxel::new()).count(),
+            1
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn ch_unchecked_out_of_bounds() {
+        Chunk::new(12).get_voxel_at(Vector3::new(0, 12, 0));
+    }
+
+    #[test]
+    #[should_panic]
+    fn ch_unchecked_dimension() {
+        VoxelGrid::with_dimension(MAX_CHUNK_DIMENSION + 1);
+    }
+
+    #[test]
+    fn ch_dimensions() {
+        assert_eq!(
+            Chunk::try_new(0).err(),
+            Some(VoxelError::InvalidDimension(0))
         );
+        assert!(Chunk::try_new(MAX_CHUNK_DIMENSION + 1).is_err());
+        assert!(VoxelGrid::try_with_dimension(300).is_err());
+
+        for &dim in &[41, 128] {
+            let mut vg = VoxelGrid::try_with_dimension(dim).unwrap();
+            let last = i32::from(dim) - 1;
+            let pos = Vector3::new(last, -1, last);
+            vg.set_voxel(&pos, MaterialId::SNOW, QuantizedFloat::FULL);
+            let (chunk, voxel) = vg.locate(&pos);
+            assert_eq!(voxel, Vector3::new(dim - 1, dim - 1, dim - 1));
+            assert_eq!(vg.voxel_at(&pos).get_material(), MaterialId::SNOW);
+            let ch = vg.get_chunk(&chunk).unwrap();
+            assert_eq!(ch.voxels().len(), usize::from(dim).pow(3));
+
+            assert!(vg.try_insert_chunk(&chunk, Chunk::new(dim)).is_ok());
+            assert_eq!(
+                vg.try_insert_chunk(&chunk, Chunk::new(8)),
+                Err(VoxelError::DimensionMismatch {
+                    expected: dim,
+                    found: 8,
+                })
+            );
+        }
     }
 
     #[test]
@@ -287,8 +1000,211 @@ mod tests {
         assert!(vg.is_empty());
     }
 
+    #[test]
+    fn vg_set_voxel_negative() {
+        let mut vg = VoxelGrid::with_dimension(8);
+        vg.set_voxel(
+            &Vector3::new(-1, -9, 3),
+            MaterialId::SNOW,
+            QuantizedFloat::new(128),
+        );
+        assert!(vg.get_chunk(&Vector3::new(-1, -2, 0)).is_some());
+        assert_eq!(
+            vg.voxel_at(&Vector3::new(-1, -9, 3)).get_material(),
+            MaterialId::SNOW
+        );
+        assert_eq!(
+            vg.voxel_at(&Vector3::new(-1, -9, 4)).get_material(),
+            MaterialId::AIR
+        );
+        assert!(vg.neighbors(&Vector3::new(0, -2, 0))[1].is_some());
+    }
+
+    #[test]
+    fn vg_fill_region() {
+        let mut vg = VoxelGrid::with_dimension(4);
+        vg.fill_region(
+            &Vector3::new(-1, -1, 0),
+            &Vector3::new(0, 0, 1),
+            &|pos: &WorldPos| {
+                if pos.y < 0 {
+                    Voxel::new_with_args(MaterialId::ROCK, QuantizedFloat::FULL)
+                } else {
+                    Voxel::new()
+                }
+            },
+        );
+        assert!(vg.get_chunk(&Vector3::new(-1, -1, 1)).is_some());
+        assert!(vg.get_chunk(&Vector3::new(1, 0, 0)).is_none());
+        assert_eq!(
+            vg.voxel_at(&Vector3::new(-3, -1, 6)).get_material(),
+            MaterialId::ROCK
+        );
+        assert_eq!(
+            vg.voxel_at(&Vector3::new(2, 0, 1)).get_material(),
+            MaterialId::AIR
+        );
+    }
+
+    #[test]
+    fn ch_uniform_memory() {
+        let mut chunk = Chunk::filled(
+            32,
+            Voxel::new_with_args(MaterialId::ROCK, QuantizedFloat::FULL),
+        );
+        assert!(chunk.is_uniform());
+        let uniform = chunk.memory_usage();
+        chunk.set_voxel_at(
+            Vector3::new(0, 31, 0),
+            MaterialId::AIR,
+            QuantizedFloat::new(0),
+        );
+        assert!(!chunk.is_uniform());
+        assert!(chunk.memory_usage() > uniform);
+        assert!(chunk.memory_usage() < 32 * 32 * 32);
+    }
+
+    #[test]
+    fn vg_changes() {
+        let mut vg = VoxelGrid::with_dimension(8);
+        vg.insert_chunk(&Vector3::new(0, 0, 0), Chunk::new(8));
+        assert!(vg.is_dirty(&Vector3::new(0, 0, 0)));
+        vg.clear_changes();
+
+        vg.set_voxel(
+            &Vector3::new(1, 2, 3),
+            MaterialId::AIR,
+            QuantizedFloat::new(0),
+        );
+        assert!(vg.take_changes().is_empty());
+
+        vg.set_voxel(
+            &Vector3::new(1, 2, 3),
+            MaterialId::ROCK,
+            QuantizedFloat::new(9),
+        );
+        vg.set_voxel(
+            &Vector3::new(4, 0, 5),
+            MaterialId::ROCK,
+            QuantizedFloat::new(9),
+        );
+        assert_eq!(
+            vg.dirty_region(&Vector3::new(0, 0, 0)),
+            Some(&DirtyRegion {
+                min: Vector3::new(1, 0, 3),
+                max: Vector3::new(4, 2, 5),
+            })
+        );
+        assert_eq!(vg.take_changes().len(), 1);
+        assert!(!vg.is_dirty(&Vector3::new(0, 0, 0)));
+
+        vg.delete_chunk(&Vector3::new(0, 0, 0));
+        assert_eq!(
+            vg.take_changes(),
+            vec![VoxelChangeEvent::Removed(Vector3::new(0, 0, 0))]
+        );
+    }
+
+    #[test]
+    fn vg_undo_redo() {
+        let mut vg = VoxelGrid::with_dimension(8);
+        let a = Vector3::new(1, 1, 1);
+        let b = Vector3::new(-1, 1, 1);
+        vg.set_voxel(&a, MaterialId::ROCK, QuantizedFloat::FULL);
+
+        vg.begin_transaction();
+        vg.set_voxel(&a, MaterialId::SNOW, QuantizedFloat::new(10));
+        vg.set_voxel(&b, MaterialId::SNOW, QuantizedFloat::new(20));
+        vg.set_voxel(&a, MaterialId::SNOW, QuantizedFloat::new(30));
+        vg.commit_transaction();
+        vg.clear_changes();
+
+        // Changes made by undo and redo are told apart from edits
+        let replayed = |events: Vec<VoxelChangeEvent>| {
+            !events.is_empty()
+                && events.iter().all(|e| match *e {
+                    VoxelChangeEvent::Modified { source, .. } => source == ChangeSource::Replay,
+                    VoxelChangeEvent::Removed(_) => false,
+                })
+        };
+        assert!(vg.undo());
+        assert!(replayed(vg.take_changes()));
+        assert_eq!(vg.voxel_at(&a).get_material(), MaterialId::ROCK);
+        assert_eq!(vg.voxel_at(&b).get_material(), MaterialId::AIR);
+        assert!(vg.redo());
+        assert_eq!(vg.voxel_at(&a).get_occupancy().value, 30);
+        assert_eq!(vg.voxel_at(&b).get_occupancy().value, 20);
+        assert!(vg.undo());
+        assert!(vg.undo());
+        assert!(!vg.undo());
+        assert_eq!(vg.voxel_at(&a).get_material(), MaterialId::AIR);
+
+        vg.set_voxel(&b, MaterialId::ICE, QuantizedFloat::new(1));
+        assert!(!vg.journal().can_redo());
+        assert!(!replayed(vg.take_changes()));
+    }
+
+    #[test]
+    fn vg_journal_budget() {
+        let mut vg = VoxelGrid::with_dimension(8);
+        vg.journal_mut().set_budget(1024);
+        for x in 0..200 {
+            vg.set_voxel(
+                &Vector3::new(x, 0, 0),
+                MaterialId::ROCK,
+                QuantizedFloat::FULL,
+            );
+        }
+        assert!(vg.journal().memory_usage() <= 1024);
+        assert!(vg.undo());
+        assert_eq!(
+            vg.voxel_at(&Vector3::new(199, 0, 0)).get_material(),
+            MaterialId::AIR
+        );
+        while vg.undo() {}
+        assert_eq!(
+            vg.voxel_at(&Vector3::new(0, 0, 0)).get_material(),
+            MaterialId::ROCK
+        );
+    }
+
+    #[test]
+    fn quantized_float_exact_ends() {
+        assert_eq!(QuantizedFloat::from_f32(0.0), QuantizedFloat::EMPTY);
+        assert_eq!(QuantizedFloat::from_f32(1.0), QuantizedFloat::FULL);
+        assert_eq!(QuantizedFloat::EMPTY.decode(), 0.0);
+        assert_eq!(QuantizedFloat::FULL.decode(), 1.0);
+    }
+
     #[quickcheck]
-    fn prop_something() -> bool {
-        true
+    fn prop_quantized_float_roundtrip(q: QuantizedFloat) -> bool {
+        QuantizedFloat::from_f32(q.decode()) == q
+    }
+
+    #[quickcheck]
+    fn prop_quantized_float_monotonic(a: QuantizedFloat, b: QuantizedFloat) -> bool {
+        (a.value <= b.value) == (a.decode() <= b.decode())
+    }
+
+    #[quickcheck]
+    fn prop_quantized_float_accumulates(steps: u8) -> bool {
+        // Small increments add up instead of being rounded away
+        let mut q = QuantizedFloat::EMPTY;
+        let step = 1.0 / OCCUPANCY_MAX as f32;
+        for _ in 0..steps {
+            q = QuantizedFloat::from_f32(q.decode() + step);
+        }
+        u32::from(q.value) == u32::from(steps).min(u32::from(OCCUPANCY_MAX))
+    }
+
+    #[quickcheck]
+    fn prop_locate_roundtrip(x: i32, y: i32, z: i32, dim: u16) -> bool {
+        let vg = VoxelGrid::with_dimension(dim % 64 + 1);
+        let pos = Vector3::new(x, y, z);
+        let (chunk, voxel) = vg.locate(&pos);
+        voxel.x < vg.dimension()
+            && voxel.y < vg.dimension()
+            && voxel.z < vg.dimension()
+            && vg.world_pos(&chunk, &voxel) == pos
     }
 }